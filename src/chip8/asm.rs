@@ -0,0 +1,1402 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use super::cpu::{Mode, MODE_NAMES};
+use super::disasm::PROGRAM_START;
+use super::instruction::Instruction;
+
+// Highest address an NNN operand can hold
+const MAX_SHORT_ADDRESS: i32 = 0xFFF;
+// Stops macros that keep expanding themselves
+const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
+const CALC_BINARY_OPERATORS: [&str; 19] = [
+    "+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", ">", "<=", ">=",
+    "==", "!=",
+];
+const CALC_UNARY_FUNCTIONS: [&str; 10] = [
+    "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+// A compiled program along with the debugging markers in its source
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub rom: Vec<u8>,
+    // Addresses of `:breakpoint name` markers
+    pub breakpoints: BTreeMap<u16, String>,
+    // Memory named by `:monitor address length`
+    pub monitors: Vec<Monitor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monitor {
+    pub name: String,
+    pub address: u16,
+    pub length: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+// Tokens are separated by whitespace, `#` starts a comment that runs to the
+// end of the line. Lines and columns count from 1.
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (line_ind, line) in source.lines().enumerate() {
+        let mut current: Option<Token> = None;
+        for (column_ind, c) in line.chars().enumerate() {
+            if c == '#' || c.is_whitespace() {
+                tokens.extend(current.take());
+                if c == '#' {
+                    break;
+                }
+                continue;
+            }
+            current
+                .get_or_insert_with(|| Token {
+                    text: String::new(),
+                    line: line_ind + 1,
+                    column: column_ind + 1,
+                })
+                .text
+                .push(c);
+        }
+        tokens.extend(current);
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i32::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+            digit.to_digit(16).map(|digit| digit as u8)
+        }
+        _ => None,
+    }
+}
+
+// The skip with the opposite test
+fn invert_skip(skip: Instruction) -> Instruction {
+    match skip {
+        Instruction::SkipEqImm { x, nn } => Instruction::SkipNeqImm { x, nn },
+        Instruction::SkipNeqImm { x, nn } => Instruction::SkipEqImm { x, nn },
+        Instruction::SkipEq { x, y } => Instruction::SkipNeq { x, y },
+        Instruction::SkipNeq { x, y } => Instruction::SkipEq { x, y },
+        Instruction::SkipKey { x } => Instruction::SkipNotKey { x },
+        Instruction::SkipNotKey { x } => Instruction::SkipKey { x },
+        other => other,
+    }
+}
+
+// How a label reference that isn't known yet gets patched in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Patch {
+    // The low 12 bits of the opcode at the offset
+    Short,
+    // A whole 16 bit word at the offset
+    Long,
+}
+
+#[derive(Debug, Clone)]
+struct Reference {
+    offset: usize,
+    patch: Patch,
+    token: Token,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Open control flow blocks, each holding the offsets of the jumps that are
+// patched once the block closes
+#[derive(Debug, Clone)]
+enum Block {
+    // `loop`, exited by the jumps `while` emits
+    Loop {
+        start: u16,
+        exits: Vec<usize>,
+        token: Token,
+    },
+    // `if ... begin`, with the jump taken when the condition is false
+    Begin {
+        jump: usize,
+        token: Token,
+    },
+    // `else`, with the jump over the else branch
+    Else {
+        jump: usize,
+        token: Token,
+    },
+}
+
+impl Block {
+    fn token(&self) -> &Token {
+        match self {
+            Block::Loop { token, .. } | Block::Begin { token, .. } | Block::Else { token, .. } => {
+                token
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Assembler {
+    // Remaining tokens, back to front so the next one can be popped off
+    tokens: Vec<Token>,
+    // Where the last token ended, for errors about missing operands
+    end: Token,
+    // First token of the statement being assembled
+    current: Token,
+    mode: Mode,
+    rom: Vec<u8>,
+    // Offset into the ROM the next byte is written to, moved by `:org`
+    position: usize,
+    labels: HashMap<String, u16>,
+    // Octo constants are floating point so `:calc` keeps its precision
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    blocks: Vec<Block>,
+    references: Vec<Reference>,
+    breakpoints: BTreeMap<u16, String>,
+    // Monitors can name labels further down, so they resolve at the end
+    monitors: Vec<(Token, u16)>,
+    // Whether the ROM starts with the `jump main` compile puts there
+    jump_to_main: bool,
+}
+
+// Assembles Octo style source into a ROM that is loaded at PROGRAM_START,
+// accepting every instruction and starting at whatever comes first. Besides
+// the instructions the disassembler produces this understands `: label`,
+// `:const name value`, `:alias name vx`, `:byte value`, `:pointer address`,
+// `:sprite` rows such as `..XX..XX`, and bare numbers, which are emitted as
+// data bytes. A bare label name is a call.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new(source, Mode::XoChip);
+    assembler.assemble_all()?;
+    assembler.finish().map(|program| program.rom)
+}
+
+// Compiles an Octo program for the given mode, rejecting instructions the
+// mode doesn't have. On top of the assembler syntax this handles
+// `loop`/`while`/`again`, `if ... begin`/`else`/`end`, comparisons with <, >,
+// <= and >=, `:macro`, `:calc`, `:org`, `:breakpoint` and `:monitor`.
+// Programs start at `: main`, which gets a jump at PROGRAM_START unless
+// nothing is assembled before it.
+pub fn compile(source: &str, mode: Mode) -> Result<Program, AsmError> {
+    let mut assembler = Assembler::new(source, mode);
+    let start = Token {
+        text: "main".to_string(),
+        line: 1,
+        column: 1,
+    };
+    assembler.references.push(Reference {
+        offset: 0,
+        patch: Patch::Short,
+        token: start.clone(),
+    });
+    assembler.emit(Instruction::Jump { nnn: 0 })?;
+    assembler.jump_to_main = true;
+    assembler.assemble_all()?;
+    if !assembler.labels.contains_key("main") {
+        return Err(start.error("this program has no : main label".to_string()));
+    }
+    assembler.finish()
+}
+
+// Compiles sources that have a `: main` label and assembles the rest from
+// whatever comes first, like `assemble` but for the given mode
+pub fn compile_or_assemble(source: &str, mode: Mode) -> Result<Program, AsmError> {
+    let has_main = tokenize(source)
+        .windows(2)
+        .any(|pair| pair[0].text == ":" && pair[1].text == "main");
+    if has_main {
+        return compile(source, mode);
+    }
+    let mut assembler = Assembler::new(source, mode);
+    assembler.assemble_all()?;
+    assembler.finish()
+}
+
+impl Assembler {
+    fn new(source: &str, mode: Mode) -> Self {
+        let mut tokens = tokenize(source);
+        let end = tokens.last().cloned().unwrap_or(Token {
+            text: String::new(),
+            line: 1,
+            column: 1,
+        });
+        tokens.reverse();
+        Assembler {
+            tokens,
+            current: end.clone(),
+            end,
+            mode,
+            rom: Vec::new(),
+            position: 0,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            blocks: Vec::new(),
+            references: Vec::new(),
+            breakpoints: BTreeMap::new(),
+            monitors: Vec::new(),
+            jump_to_main: false,
+        }
+    }
+
+    fn assemble_all(&mut self) -> Result<(), AsmError> {
+        while let Some(token) = self.tokens.pop() {
+            self.current = token.clone();
+            self.statement(token)?;
+        }
+        match self.blocks.last() {
+            Some(block) => {
+                let token = block.token();
+                Err(token.error(format!("{} is never closed", token.text)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn finish(mut self) -> Result<Program, AsmError> {
+        self.resolve_references()?;
+        if PROGRAM_START as usize + self.rom.len() > self.mode.memory_size() {
+            return Err(self.end.error(format!(
+                "the program is {} bytes, which doesn't fit in {} memory",
+                self.rom.len(),
+                MODE_NAMES[self.mode as usize]
+            )));
+        }
+        let mut monitors = Vec::new();
+        for (token, length) in self.monitors.iter() {
+            let address = self
+                .lookup_value(&token.text)
+                .ok_or_else(|| token.error(format!("undefined label {}", token.text)))?;
+            monitors.push(Monitor {
+                name: token.text.clone(),
+                address: address as u16,
+                length: *length,
+            });
+        }
+        Ok(Program {
+            rom: self.rom,
+            breakpoints: self.breakpoints,
+            monitors,
+        })
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        self.tokens.pop().ok_or_else(|| {
+            self.end
+                .error(format!("unexpected end of input after {}", self.end.text))
+        })
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(token.error(format!("expected {}, found {}", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn here(&self) -> Result<u16, AsmError> {
+        let address = PROGRAM_START as usize + self.position;
+        u16::try_from(address).map_err(|_| {
+            self.current
+                .error("the program runs past the end of memory".to_string())
+        })
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.position >= self.rom.len() {
+            self.rom.resize(self.position + 1, 0);
+        }
+        self.rom[self.position] = byte;
+        self.position += 1;
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Result<(), AsmError> {
+        let required = instruction.required_mode();
+        if required > self.mode {
+            return Err(self.current.error(format!(
+                "{} needs {} mode, the target is {}",
+                self.current.text, MODE_NAMES[required as usize], MODE_NAMES[self.mode as usize]
+            )));
+        }
+        self.emit_word(instruction.encode());
+        Ok(())
+    }
+
+    fn emit_word(&mut self, word: u16) {
+        self.push((word >> 8) as u8);
+        self.push(word as u8);
+    }
+
+    // A jump that gets its target once the enclosing block closes
+    fn emit_jump(&mut self) -> Result<usize, AsmError> {
+        let offset = self.position;
+        self.emit(Instruction::Jump { nnn: 0 })?;
+        Ok(offset)
+    }
+
+    fn write_short(&mut self, offset: usize, address: u16) {
+        self.rom[offset] = (self.rom[offset] & 0xF0) | (address >> 8) as u8;
+        self.rom[offset + 1] = address as u8;
+    }
+
+    // Points the jump at `offset` here
+    fn patch_jump(&mut self, offset: usize) -> Result<(), AsmError> {
+        let address = self.here()?;
+        if address as i32 > MAX_SHORT_ADDRESS {
+            return Err(self
+                .current
+                .error(format!("can't jump to {:04X}, it is out of range", address)));
+        }
+        self.write_short(offset, address);
+        Ok(())
+    }
+
+    fn lookup_register(&self, text: &str) -> Option<u8> {
+        self.aliases
+            .get(text)
+            .copied()
+            .or_else(|| parse_register(text))
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        self.lookup_register(text).is_some()
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.lookup_register(&token.text)
+            .ok_or_else(|| token.error(format!("expected a register, found {}", token.text)))
+    }
+
+    // Numbers and constants, labels only count once they are defined
+    fn lookup_value(&self, text: &str) -> Option<i32> {
+        parse_number(text)
+            .or_else(|| {
+                self.constants
+                    .get(text)
+                    .map(|constant| constant.floor() as i32)
+            })
+            .or_else(|| self.labels.get(text).map(|address| *address as i32))
+    }
+
+    fn value_in_range(&mut self, min: i32, max: i32) -> Result<i32, AsmError> {
+        let token = self.next()?;
+        let value = self
+            .lookup_value(&token.text)
+            .ok_or_else(|| token.error(format!("expected a number, found {}", token.text)))?;
+        if value < min || value > max {
+            return Err(token.error(format!(
+                "{} is out of range, expected {} to {}",
+                token.text, min, max
+            )));
+        }
+        Ok(value)
+    }
+
+    // Negative bytes are stored as two's complement
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        self.value_in_range(-128, 255).map(|value| value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        self.value_in_range(0, 15).map(|value| value as u8)
+    }
+
+    // An address operand for an instruction or word about to be emitted at
+    // `offset` bytes from here. Unknown names are taken to be labels that are
+    // defined further down.
+    fn address(&mut self, patch: Patch, offset: usize) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        let max = match patch {
+            Patch::Short => MAX_SHORT_ADDRESS,
+            Patch::Long => 0xFFFF,
+        };
+        match self.lookup_value(&token.text) {
+            Some(value) if value < 0 || value > max => Err(token.error(format!(
+                "address {} is out of range, expected 0 to {}",
+                token.text, max
+            ))),
+            Some(value) => Ok(value as u16),
+            None if parse_register(&token.text).is_some() || token.text.starts_with(':') => {
+                Err(token.error(format!("expected an address, found {}", token.text)))
+            }
+            None => {
+                self.references.push(Reference {
+                    offset: self.position + offset,
+                    patch,
+                    token,
+                });
+                Ok(0)
+            }
+        }
+    }
+
+    // Names can't shadow registers or be defined twice
+    fn define_name(&mut self) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if parse_register(&token.text).is_some() || parse_number(&token.text).is_some() {
+            return Err(token.error(format!("{} can't be used as a name", token.text)));
+        }
+        if self.labels.contains_key(&token.text)
+            || self.constants.contains_key(&token.text)
+            || self.aliases.contains_key(&token.text)
+            || self.macros.contains_key(&token.text)
+        {
+            return Err(token.error(format!("{} is already defined", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        match token.text.as_str() {
+            text if self.macros.contains_key(text) => self.expand_macro(&token)?,
+            ":" => {
+                let name = self.define_name()?;
+                if name.text == "main" {
+                    self.drop_jump_to_main();
+                }
+                let address = self.here()?;
+                self.labels.insert(name.text, address);
+            }
+            ":const" => {
+                let name = self.define_name()?;
+                let value = self.value_in_range(-0x8000, 0xFFFF)?;
+                self.constants.insert(name.text, value as f64);
+            }
+            ":calc" => {
+                let name = self.define_name()?;
+                let value = self.calc()?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.define_name()?;
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":macro" => {
+                let name = self.define_name()?;
+                let mut params = Vec::new();
+                while self.peek() != Some("{") {
+                    params.push(self.next()?.text);
+                }
+                let body = self.braced_tokens()?;
+                self.macros.insert(name.text, Macro { params, body });
+            }
+            ":byte" => {
+                let byte = if self.peek() == Some("{") {
+                    (self.calc()?.floor() as i64 & 0xFF) as u8
+                } else {
+                    self.byte()?
+                };
+                self.push(byte);
+            }
+            ":pointer" => {
+                let address = self.address(Patch::Long, 0)?;
+                self.emit_word(address);
+            }
+            ":sprite" => self.sprite(&token)?,
+            ":org" => {
+                let max = self.mode.memory_size() as i32 - 1;
+                let address = self.value_in_range(PROGRAM_START as i32, max)?;
+                self.position = (address - PROGRAM_START as i32) as usize;
+            }
+            ":breakpoint" => {
+                let name = self.next()?;
+                let address = self.here()?;
+                self.breakpoints.insert(address, name.text);
+            }
+            ":monitor" => {
+                let address = self.next()?;
+                let length = self.value_in_range(1, 0xFFFF)?;
+                self.monitors.push((address, length as u16));
+            }
+            ":call" => {
+                let nnn = self.address(Patch::Short, 0)?;
+                self.emit(Instruction::Call { nnn })?;
+            }
+            "native" => {
+                let nnn = self.address(Patch::Short, 0)?;
+                self.emit(Instruction::MachineCall { nnn })?;
+            }
+            "clear" => self.emit(Instruction::Clear)?,
+            "return" => self.emit(Instruction::Return)?,
+            "scroll-right" => self.emit(Instruction::ScrollRight)?,
+            "scroll-left" => self.emit(Instruction::ScrollLeft)?,
+            "exit" => self.emit(Instruction::Exit)?,
+            "lores" => self.emit(Instruction::Lores)?,
+            "hires" => self.emit(Instruction::Hires)?,
+            "audio" => self.emit(Instruction::LoadAudio)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollDown { n })?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollUp { n })?;
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::SelectPlanes { n })?;
+            }
+            "jump" => {
+                let nnn = self.address(Patch::Short, 0)?;
+                self.emit(Instruction::Jump { nnn })?;
+            }
+            "jump0" => {
+                let nnn = self.address(Patch::Short, 0)?;
+                self.emit(Instruction::JumpOffset { nnn })?;
+            }
+            "if" => self.if_statement()?,
+            "else" => match self.blocks.pop() {
+                Some(Block::Begin { jump, .. }) => {
+                    let end_jump = self.emit_jump()?;
+                    self.patch_jump(jump)?;
+                    self.blocks.push(Block::Else {
+                        jump: end_jump,
+                        token,
+                    });
+                }
+                _ => return Err(token.error("else without a matching begin".to_string())),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::Begin { jump, .. }) | Some(Block::Else { jump, .. }) => {
+                    self.patch_jump(jump)?;
+                }
+                _ => return Err(token.error("end without a matching begin".to_string())),
+            },
+            "loop" => {
+                let start = self.here()?;
+                self.blocks.push(Block::Loop {
+                    start,
+                    exits: Vec::new(),
+                    token,
+                });
+            }
+            "while" => {
+                let (setup, skip) = self.condition()?;
+                for instruction in setup {
+                    self.emit(instruction)?;
+                }
+                self.emit(invert_skip(skip))?;
+                let exit = self.emit_jump()?;
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|block| matches!(block, Block::Loop { .. }))
+                {
+                    Some(Block::Loop { exits, .. }) => exits.push(exit),
+                    _ => return Err(token.error("while outside of a loop".to_string())),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits, .. }) => {
+                    self.emit(Instruction::Jump { nnn: start })?;
+                    for exit in exits {
+                        self.patch_jump(exit)?;
+                    }
+                }
+                _ => return Err(token.error("again without a matching loop".to_string())),
+            },
+            "save" | "load" => self.load_store(&token)?,
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Instruction::Draw { x, y, n })?;
+            }
+            "i" => self.index_assignment()?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(match token.text.as_str() {
+                    "delay" => Instruction::SetDelay { x },
+                    "buzzer" => Instruction::SetSound { x },
+                    _ => Instruction::SetPitch { x },
+                })?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::Bcd { x })?;
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::SaveFlags { x })?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LoadFlags { x })?;
+            }
+            text if self.is_register(text) => self.register_assignment(&token)?,
+            text if parse_number(text).is_some() || self.constants.contains_key(text) => {
+                self.tokens.push(token);
+                let byte = self.byte()?;
+                self.push(byte);
+            }
+            text if text.starts_with(':') => {
+                return Err(token.error(format!("unknown directive {}", text)));
+            }
+            _ => {
+                // A bare name is a call to that label
+                self.tokens.push(token);
+                let nnn = self.address(Patch::Short, 0)?;
+                self.emit(Instruction::Call { nnn })?;
+            }
+        }
+        Ok(())
+    }
+
+    // When main would directly follow the jump to it the jump isn't needed,
+    // as long as nothing else has been placed after the jump already
+    fn drop_jump_to_main(&mut self) {
+        if self.jump_to_main
+            && self.position == 2
+            && self.rom.len() == 2
+            && self.labels.is_empty()
+            && self.breakpoints.is_empty()
+        {
+            self.rom.clear();
+            self.position = 0;
+            self.references.retain(|reference| reference.offset != 0);
+        }
+        self.jump_to_main = false;
+    }
+
+    // The tokens between a `{` and its matching `}`
+    fn braced_tokens(&mut self) -> Result<Vec<Token>, AsmError> {
+        let open = self.expect("{")?;
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self
+                .tokens
+                .pop()
+                .ok_or_else(|| open.error("{ is never closed".to_string()))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    // Arguments replace the parameter names in a copy of the body, which is
+    // then assembled as if it had been written out
+    fn expand_macro(&mut self, name: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return Err(name.error(format!(
+                "too many macro expansions, does {} expand itself?",
+                name.text
+            )));
+        }
+        let definition = self.macros[&name.text].clone();
+        let mut arguments = HashMap::new();
+        for param in definition.params.iter() {
+            arguments.insert(param.clone(), self.next()?);
+        }
+        for token in definition.body.iter().rev() {
+            self.tokens
+                .push(arguments.get(&token.text).unwrap_or(token).clone());
+        }
+        Ok(())
+    }
+
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        let open = self.expect("{")?;
+        let value = self.calc_expression()?;
+        self.expect("}")?;
+        if !value.is_finite() {
+            return Err(open.error("expression is not a finite number".to_string()));
+        }
+        Ok(value)
+    }
+
+    // Like Octo, operators have no precedence and evaluate right to left, so
+    // `2 * 3 + 1` is 8
+    fn calc_expression(&mut self) -> Result<f64, AsmError> {
+        let left = self.calc_term()?;
+        let operator = match self.peek() {
+            Some(text) if CALC_BINARY_OPERATORS.contains(&text) => self.next()?,
+            _ => return Ok(left),
+        };
+        let right = self.calc_expression()?;
+        let (int_left, int_right) = (left.floor() as i64, right.floor() as i64);
+        let flag = |condition: bool| if condition { 1.0 } else { 0.0 };
+        Ok(match operator.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (int_left & int_right) as f64,
+            "|" => (int_left | int_right) as f64,
+            "^" => (int_left ^ int_right) as f64,
+            "<<" => int_left.checked_shl(int_right as u32).unwrap_or(0) as f64,
+            ">>" => int_left.checked_shr(int_right as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => flag(left < right),
+            ">" => flag(left > right),
+            "<=" => flag(left <= right),
+            ">=" => flag(left >= right),
+            "==" => flag(left == right),
+            _ => flag(left != right),
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        let value = match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression()?;
+                self.expect(")")?;
+                value
+            }
+            "HERE" => self.here()? as f64,
+            "PI" => std::f64::consts::PI,
+            "E" => std::f64::consts::E,
+            "-" => -self.calc_term()?,
+            "~" => !(self.calc_term()?.floor() as i64) as f64,
+            "!" => {
+                if self.calc_term()? == 0.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            // The byte assembled so far at an address
+            "@" => {
+                let address = self.calc_term()?.floor() as i64 - PROGRAM_START as i64;
+                match usize::try_from(address)
+                    .ok()
+                    .and_then(|ind| self.rom.get(ind))
+                {
+                    Some(byte) => *byte as f64,
+                    None => return Err(token.error("@ reads outside the program".to_string())),
+                }
+            }
+            text if CALC_UNARY_FUNCTIONS.contains(&text) => {
+                let value = self.calc_term()?;
+                match text {
+                    "sin" => value.sin(),
+                    "cos" => value.cos(),
+                    "tan" => value.tan(),
+                    "exp" => value.exp(),
+                    "log" => value.ln(),
+                    "abs" => value.abs(),
+                    "sqrt" => value.sqrt(),
+                    "sign" => {
+                        if value == 0.0 {
+                            0.0
+                        } else {
+                            value.signum()
+                        }
+                    }
+                    "ceil" => value.ceil(),
+                    _ => value.floor(),
+                }
+            }
+            text => match self.constants.get(text) {
+                Some(constant) => *constant,
+                None => self
+                    .lookup_value(text)
+                    .ok_or_else(|| token.error(format!("{} is not defined", text)))?
+                    as f64,
+            },
+        };
+        Ok(value)
+    }
+
+    // Each row is one byte, `X` or `1` sets a pixel and `.` or `0` leaves it
+    // blank. The rows run to the end of the line.
+    fn sprite(&mut self, directive: &Token) -> Result<(), AsmError> {
+        while self.tokens.last().map(|token| token.line) == Some(directive.line) {
+            let row = self.next()?;
+            if row.text.chars().count() > 8 {
+                return Err(row.error(format!("sprite row {} is wider than 8", row.text)));
+            }
+            let mut byte = 0u8;
+            for (ind, c) in row.text.chars().enumerate() {
+                match c {
+                    'X' | 'x' | '1' => byte |= 0x80 >> ind,
+                    '.' | '0' => {}
+                    _ => {
+                        return Err(row.error(format!("unexpected {} in sprite row", c)));
+                    }
+                }
+            }
+            self.push(byte);
+        }
+        Ok(())
+    }
+
+    // `if <condition> then <statement>` or `if <condition> begin ... end`
+    fn if_statement(&mut self) -> Result<(), AsmError> {
+        let (setup, skip) = self.condition()?;
+        for instruction in setup {
+            self.emit(instruction)?;
+        }
+        let keyword = self.next()?;
+        match keyword.text.as_str() {
+            "then" => self.emit(skip)?,
+            // The inverted skip steps over the jump past the block when the
+            // condition holds
+            "begin" => {
+                self.emit(invert_skip(skip))?;
+                let jump = self.emit_jump()?;
+                self.blocks.push(Block::Begin {
+                    jump,
+                    token: keyword,
+                });
+            }
+            _ => {
+                return Err(
+                    keyword.error(format!("expected then or begin, found {}", keyword.text))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Octo conditions say when the following instruction runs, so they are
+    // returned as the skip with the opposite test. Ordered comparisons first
+    // compute the comparison into VF, which they overwrite.
+    fn condition(&mut self) -> Result<(Vec<Instruction>, Instruction), AsmError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        match operator.text.as_str() {
+            "key" => return Ok((Vec::new(), Instruction::SkipNotKey { x })),
+            "-key" => return Ok((Vec::new(), Instruction::SkipKey { x })),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {}
+            _ => {
+                return Err(
+                    operator.error(format!("expected a comparison, found {}", operator.text))
+                );
+            }
+        }
+        let operand = if self.peek().is_some_and(|text| self.is_register(text)) {
+            Operand::Register(self.register()?)
+        } else {
+            Operand::Byte(self.byte()?)
+        };
+        let condition = match (operator.text.as_str(), operand) {
+            ("==", Operand::Register(y)) => (Vec::new(), Instruction::SkipNeq { x, y }),
+            ("==", Operand::Byte(nn)) => (Vec::new(), Instruction::SkipNeqImm { x, nn }),
+            ("!=", Operand::Register(y)) => (Vec::new(), Instruction::SkipEq { x, y }),
+            ("!=", Operand::Byte(nn)) => (Vec::new(), Instruction::SkipEqImm { x, nn }),
+            (text, operand) => {
+                // VF ends up as the inverted borrow of a subtraction, which
+                // is 1 when vx >= operand, or operand >= vx when swapped
+                let swapped = text == ">" || text == "<=";
+                let setup = match (swapped, operand) {
+                    (false, Operand::Register(y)) => vec![
+                        Instruction::Move { x: 0xF, y: x },
+                        Instruction::Sub { x: 0xF, y },
+                    ],
+                    (false, Operand::Byte(nn)) => vec![
+                        Instruction::LoadImm { x: 0xF, nn },
+                        Instruction::SubReverse { x: 0xF, y: x },
+                    ],
+                    (true, Operand::Register(y)) => vec![
+                        Instruction::Move { x: 0xF, y },
+                        Instruction::Sub { x: 0xF, y: x },
+                    ],
+                    (true, Operand::Byte(nn)) => vec![
+                        Instruction::LoadImm { x: 0xF, nn },
+                        Instruction::Sub { x: 0xF, y: x },
+                    ],
+                };
+                // < and > hold when VF is 0, <= and >= when it is 1
+                let skip = if text == "<" || text == ">" {
+                    Instruction::SkipNeqImm { x: 0xF, nn: 0 }
+                } else {
+                    Instruction::SkipEqImm { x: 0xF, nn: 0 }
+                };
+                (setup, skip)
+            }
+        };
+        Ok(condition)
+    }
+
+    // `save vx` or `save vx - vy`, and the same for load
+    fn load_store(&mut self, directive: &Token) -> Result<(), AsmError> {
+        let x = self.register()?;
+        let save = directive.text == "save";
+        let instruction = if self.peek() == Some("-") {
+            self.next()?;
+            let y = self.register()?;
+            if save {
+                Instruction::SaveRange { x, y }
+            } else {
+                Instruction::LoadRange { x, y }
+            }
+        } else if save {
+            Instruction::Store { x }
+        } else {
+            Instruction::Load { x }
+        };
+        self.emit(instruction)
+    }
+
+    fn index_assignment(&mut self) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        match operator.text.as_str() {
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Instruction::AddI { x })?;
+            }
+            ":=" => match self.peek() {
+                Some("long") => {
+                    self.next()?;
+                    let address = self.address(Patch::Long, 2)?;
+                    self.emit(Instruction::LoadILong)?;
+                    self.emit_word(address);
+                }
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Instruction::LoadFont { x })?;
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Instruction::LoadBigFont { x })?;
+                }
+                _ => {
+                    let nnn = self.address(Patch::Short, 0)?;
+                    self.emit(Instruction::LoadI { nnn })?;
+                }
+            },
+            _ => {
+                return Err(operator.error(format!("expected := or +=, found {}", operator.text)));
+            }
+        }
+        Ok(())
+    }
+
+    fn register_assignment(&mut self, target: &Token) -> Result<(), AsmError> {
+        self.tokens.push(target.clone());
+        let x = self.register()?;
+        let operator = self.next()?;
+        let source_is_register = self.peek().is_some_and(|text| self.is_register(text));
+        let instruction = match operator.text.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let nn = self.byte()?;
+                    Instruction::Random { x, nn }
+                }
+                Some("delay") => {
+                    self.next()?;
+                    Instruction::GetDelay { x }
+                }
+                Some("key") => {
+                    self.next()?;
+                    Instruction::WaitKey { x }
+                }
+                _ if source_is_register => {
+                    let y = self.register()?;
+                    Instruction::Move { x, y }
+                }
+                _ => {
+                    let nn = self.byte()?;
+                    Instruction::LoadImm { x, nn }
+                }
+            },
+            "+=" if !source_is_register => {
+                let nn = self.byte()?;
+                Instruction::AddImm { x, nn }
+            }
+            "+=" | "|=" | "&=" | "^=" | "-=" | "=-" | ">>=" | "<<=" => {
+                let y = self.register()?;
+                match operator.text.as_str() {
+                    "+=" => Instruction::Add { x, y },
+                    "|=" => Instruction::Or { x, y },
+                    "&=" => Instruction::And { x, y },
+                    "^=" => Instruction::Xor { x, y },
+                    "-=" => Instruction::Sub { x, y },
+                    "=-" => Instruction::SubReverse { x, y },
+                    ">>=" => Instruction::ShiftRight { x, y },
+                    _ => Instruction::ShiftLeft { x, y },
+                }
+            }
+            _ => {
+                return Err(operator.error(format!(
+                    "expected an assignment operator, found {}",
+                    operator.text
+                )));
+            }
+        };
+        self.emit(instruction)
+    }
+
+    fn resolve_references(&mut self) -> Result<(), AsmError> {
+        for reference in std::mem::take(&mut self.references) {
+            let address = *self.labels.get(&reference.token.text).ok_or_else(|| {
+                reference
+                    .token
+                    .error(format!("undefined label {}", reference.token.text))
+            })?;
+            let offset = reference.offset;
+            match reference.patch {
+                Patch::Short => {
+                    if address as i32 > MAX_SHORT_ADDRESS {
+                        // Only loads into i have a long form to suggest
+                        let hint = if self.rom[offset] & 0xF0 == 0xA0 {
+                            "is out of range, use i := long".to_string()
+                        } else {
+                            format!("is beyond {:03X}", MAX_SHORT_ADDRESS)
+                        };
+                        return Err(reference.token.error(format!(
+                            "label {} at {:04X} {}",
+                            reference.token.text, address, hint
+                        )));
+                    }
+                    self.write_short(offset, address);
+                }
+                Patch::Long => {
+                    self.rom[offset] = (address >> 8) as u8;
+                    self.rom[offset + 1] = address as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu::{Mode, CPU};
+    use crate::chip8::disasm::{disassemble, Syntax};
+    #[test]
+    fn assembles_instructions_and_labels() {
+        let source = "
+            : main
+                v0 := 0x0A      # comment
+                i := digit
+                sprite v0 v1 5
+                :call helper
+                helper
+                jump main
+            : helper
+                return
+            : digit
+                0xF0 0x90 -1
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                0x60, 0x0A, 0xA2, 0x0E, 0xD0, 0x15, 0x22, 0x0C, 0x22, 0x0C, 0x12, 0x00, 0x00, 0xEE,
+                0xF0, 0x90, 0xFF
+            ])
+        );
+    }
+    #[test]
+    fn assembles_constants_aliases_and_data() {
+        let source = "
+            :const SPEED 3
+            :alias score v5
+            score += SPEED
+            if score != 0x10 then score := random 0b111
+            save v0 - score
+            :byte 200
+            :pointer table
+            : table
+            :sprite XX..XX.. ..1111.. .......X
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                0x75, 0x03, 0x35, 0x10, 0xC5, 0x07, 0x50, 0x52, 0xC8, 0x02, 0x0B, 0xCC, 0x3C, 0x01
+            ])
+        );
+    }
+    #[test]
+    fn assembles_long_loads() {
+        let source = "i := long target\n: target\naudio";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![0xF0, 0x00, 0x02, 0x04, 0xF0, 0x02])
+        );
+    }
+    #[test]
+    fn reports_errors_with_position() {
+        assert_eq!(
+            assemble("clear\n  v0 := 256"),
+            Err(AsmError {
+                line: 2,
+                column: 9,
+                message: "256 is out of range, expected -128 to 255".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("jump nowhere").unwrap_err().to_string(),
+            "1:6: undefined label nowhere"
+        );
+        assert_eq!(
+            assemble(": x\n: x").unwrap_err().to_string(),
+            "2:3: x is already defined"
+        );
+        assert_eq!(
+            assemble("sprite v0 v1").unwrap_err().to_string(),
+            "1:11: unexpected end of input after v1"
+        );
+        assert_eq!(
+            assemble("if v0 ~ 3 then").unwrap_err().to_string(),
+            "1:7: expected a comparison, found ~"
+        );
+        // Puts the label past 0xFFF after the instruction referring to it
+        let far = |code| format!("{}\n{}: far", code, "0 ".repeat(0xE00));
+        assert_eq!(
+            assemble(&far("i := far")).unwrap_err().to_string(),
+            "1:6: label far at 1002 is out of range, use i := long"
+        );
+        assert_eq!(
+            assemble(&far("jump far")).unwrap_err().to_string(),
+            "1:6: label far at 1002 is beyond FFF"
+        );
+    }
+    #[test]
+    fn round_trips_through_the_disassembler() {
+        let roms: [(&[u8], Mode); 4] = [
+            (include_bytes!("../../roms/ibm_logo.ch8"), Mode::Chip8),
+            (include_bytes!("../../roms/test_opcode.ch8"), Mode::Chip8),
+            (include_bytes!("../../roms/BC_test.ch8"), Mode::Chip8),
+            (include_bytes!("../../roms/octo.ch8"), Mode::XoChip),
+        ];
+        for (rom, mode) in roms.iter() {
+            let source = disassemble(rom, *mode).render(Syntax::Octo);
+            assert_eq!(assemble(&source).as_deref(), Ok(*rom));
+            let program = compile(&source, *mode).unwrap();
+            assert_eq!(program.rom, *rom);
+        }
+    }
+    #[test]
+    fn assembled_programs_run() {
+        let source = "
+            :alias counter v3
+            : main
+                counter := 0
+            : loop
+                counter += 1
+                if counter != 5 then jump loop
+                v4 := counter
+                v4 <<= v4
+                v5 := 200
+                v5 += v5
+            : done
+                jump done
+        ";
+        let rom = assemble(source).unwrap();
+        let mut cpu = CPU::new(&rom).unwrap();
+        for _ in 0..32 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.v_reg[3], 5);
+        assert_eq!(cpu.v_reg[4], 10);
+        assert_eq!(cpu.v_reg[5], 144);
+        assert_eq!(cpu.v_reg[0xF], 1);
+    }
+    #[test]
+    fn compiles_control_flow_and_comparisons() {
+        let source = "
+            :alias sum v1
+            :alias flags v2
+            : main
+                v0 := 1
+                sum := 0
+                loop
+                    while v0 <= 10
+                    sum += v0
+                    v0 += 1
+                again
+                if sum == 55 begin
+                    v3 := 1
+                else
+                    v3 := 2
+                end
+                if v0 > 10 then flags += 1
+                if v0 < 11 then flags += 2
+                v4 := 11
+                if v0 >= v4 then flags += 4
+                if v4 != 3 begin flags += 8 end
+            :breakpoint done
+                loop again
+        ";
+        let program = compile(source, Mode::Chip8).unwrap();
+        let done = *program.breakpoints.keys().next().unwrap();
+        assert_eq!(program.breakpoints[&done], "done");
+        let mut cpu = CPU::new(&program.rom).unwrap();
+        for _ in 0..500 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.prog_counter, done);
+        assert_eq!(cpu.v_reg[1], 55);
+        assert_eq!(cpu.v_reg[3], 1);
+        assert_eq!(cpu.v_reg[2], 0b1101);
+    }
+    #[test]
+    fn compiles_macros_calc_and_org() {
+        let source = "
+            :macro add-twice register amount {
+                register += amount
+                register += amount
+            }
+            :const SPEED 3
+            :calc DOUBLE { SPEED * 2 }
+            : main
+                add-twice v0 DOUBLE
+                i := table
+            :org 0x210
+            : table
+                :byte { 2 * 3 + 1 }
+                :byte { ( 2 * 3 ) + 1 }
+                :byte { HERE - table }
+            :monitor table 3
+        ";
+        let program = compile(source, Mode::Chip8).unwrap();
+        assert_eq!(
+            program.rom,
+            vec![0x70, 0x06, 0x70, 0x06, 0xA2, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 7, 2]
+        );
+        assert_eq!(
+            program.monitors,
+            vec![Monitor {
+                name: "table".to_string(),
+                address: 0x210,
+                length: 3
+            }]
+        );
+    }
+    #[test]
+    fn jumps_to_main_when_it_is_not_first() {
+        let source = ": helper return\n: main helper";
+        assert_eq!(
+            compile(source, Mode::Chip8).unwrap().rom,
+            vec![0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]
+        );
+    }
+    #[test]
+    fn reports_compile_errors() {
+        let error = |source: &str| compile(source, Mode::Chip8).unwrap_err().to_string();
+        assert_eq!(
+            error(": main\n  hires"),
+            "2:3: hires needs schip mode, the target is chip8"
+        );
+        assert_eq!(
+            error(": start clear"),
+            "1:1: this program has no : main label"
+        );
+        assert_eq!(error(": main loop clear"), "1:8: loop is never closed");
+        assert_eq!(error(": main end"), "1:8: end without a matching begin");
+        assert_eq!(
+            error(":macro again-and-again { again-and-again }\n: main again-and-again"),
+            "1:26: too many macro expansions, does again-and-again expand itself?"
+        );
+        assert!(compile(": main hires", Mode::SuperChip).is_ok());
+        assert_eq!(
+            compile_or_assemble(": start clear\n: main jump start", Mode::Chip8)
+                .unwrap()
+                .rom,
+            vec![0x12, 0x04, 0x00, 0xE0, 0x12, 0x02]
+        );
+        assert_eq!(
+            compile_or_assemble(": start clear", Mode::Chip8)
+                .unwrap()
+                .rom,
+            vec![0x00, 0xE0]
+        );
+        assert_eq!(
+            compile_or_assemble("hires", Mode::Chip8)
+                .unwrap_err()
+                .to_string(),
+            "1:1: hires needs schip mode, the target is chip8"
+        );
+        assert_eq!(
+            compile(": main\n:org 0xFFFF\n0x00\n: tail", Mode::XoChip)
+                .unwrap_err()
+                .to_string(),
+            "4:1: the program runs past the end of memory"
+        );
+    }
+}
@@ -0,0 +1,215 @@
+pub const DEFAULT_TONE_HZ: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+// XO-CHIP pitch register value that plays the pattern at 4000 bits per second
+pub const DEFAULT_PITCH: u8 = 64;
+
+// XO-CHIP 1-bit audio pattern, 128 samples played in a loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioPattern {
+    pub pattern: [u8; 16],
+    pub pitch: u8,
+}
+
+impl AudioPattern {
+    // Bits per second for the pitch set by FX3A
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioConfig {
+    pub frequency: f32,
+    // Between 0.0 and 1.0
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl AudioConfig {
+    fn effective_volume(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume.clamp(0.0, 1.0)
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            frequency: DEFAULT_TONE_HZ,
+            volume: DEFAULT_VOLUME,
+            muted: false,
+        }
+    }
+}
+
+// Buzzer tone generator, kept free of any audio backend so it can be tested
+#[derive(Debug, Clone)]
+pub struct SquareWave {
+    phase: f32,
+    phase_inc: f32,
+    volume: f32,
+}
+
+impl SquareWave {
+    pub fn new(config: &AudioConfig, sample_rate: u32) -> Self {
+        SquareWave {
+            phase: 0.0,
+            phase_inc: config.frequency / sample_rate as f32,
+            volume: config.effective_volume(),
+        }
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = if self.phase < 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+        }
+    }
+}
+
+// Loops over an XO-CHIP pattern at the rate given by its pitch
+#[derive(Debug, Clone)]
+pub struct PatternWave {
+    pattern: [u8; 16],
+    // Position in bits, from 0 up to 128
+    position: f32,
+    position_inc: f32,
+    volume: f32,
+}
+
+impl PatternWave {
+    pub fn new(pattern: &AudioPattern, volume: f32, sample_rate: u32) -> Self {
+        PatternWave {
+            pattern: pattern.pattern,
+            position: 0.0,
+            position_inc: pattern.playback_rate() / sample_rate as f32,
+            volume,
+        }
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            let bit = self.position as usize;
+            let set = self.pattern[bit / 8] >> (7 - bit % 8) & 1 == 1;
+            *sample = if set { self.volume } else { -self.volume };
+            self.position = (self.position + self.position_inc) % 128.0;
+        }
+    }
+}
+
+// What the audio backend plays, the plain square wave until a ROM loads an
+// XO-CHIP pattern
+#[derive(Debug, Clone)]
+pub struct Buzzer {
+    config: AudioConfig,
+    sample_rate: u32,
+    square: SquareWave,
+    pattern: Option<PatternWave>,
+}
+
+impl Buzzer {
+    pub fn new(config: AudioConfig, sample_rate: u32) -> Self {
+        Buzzer {
+            config,
+            sample_rate,
+            square: SquareWave::new(&config, sample_rate),
+            pattern: None,
+        }
+    }
+
+    pub fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        self.pattern = pattern.map(|pattern| {
+            PatternWave::new(&pattern, self.config.effective_volume(), self.sample_rate)
+        });
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        match &mut self.pattern {
+            Some(pattern) => pattern.fill(out),
+            None => self.square.fill(out),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn generates_square_wave() {
+        let config = AudioConfig {
+            frequency: 1000.0,
+            volume: 0.5,
+            muted: false,
+        };
+        // 8 samples per period
+        let mut wave = SquareWave::new(&config, 8000);
+        let mut out = [0.0; 16];
+        wave.fill(&mut out);
+        assert_eq!(out[0..4], [0.5; 4]);
+        assert_eq!(out[4..8], [-0.5; 4]);
+        assert_eq!(out[8..16], out[0..8]);
+    }
+    #[test]
+    fn derives_playback_rate_from_pitch() {
+        let mut pattern = AudioPattern {
+            pattern: [0; 16],
+            pitch: DEFAULT_PITCH,
+        };
+        assert_eq!(pattern.playback_rate(), 4000.0);
+        pattern.pitch = 112;
+        assert_eq!(pattern.playback_rate(), 8000.0);
+    }
+    #[test]
+    fn plays_pattern_bits() {
+        let mut pattern = AudioPattern {
+            pattern: [0; 16],
+            pitch: DEFAULT_PITCH,
+        };
+        pattern.pattern[0] = 0b1010_0000;
+        // One sample per bit
+        let mut wave = PatternWave::new(&pattern, 1.0, 4000);
+        let mut out = [0.0; 130];
+        wave.fill(&mut out);
+        assert_eq!(out[0..4], [1.0, -1.0, 1.0, -1.0]);
+        assert_eq!(out[127], -1.0);
+        // Loops back to the start
+        assert_eq!(out[128..130], [1.0, -1.0]);
+    }
+    #[test]
+    fn buzzer_falls_back_to_square_wave() {
+        let config = AudioConfig {
+            frequency: 1000.0,
+            volume: 0.5,
+            muted: false,
+        };
+        let mut buzzer = Buzzer::new(config, 8000);
+        buzzer.set_pattern(Some(AudioPattern {
+            pattern: [0; 16],
+            pitch: DEFAULT_PITCH,
+        }));
+        let mut out = [0.0; 8];
+        buzzer.fill(&mut out);
+        assert_eq!(out, [-0.5; 8]);
+        buzzer.set_pattern(None);
+        buzzer.fill(&mut out);
+        assert_eq!(out[0..4], [0.5; 4]);
+    }
+    #[test]
+    fn muted_wave_is_silent() {
+        let config = AudioConfig {
+            muted: true,
+            ..AudioConfig::default()
+        };
+        let mut wave = SquareWave::new(&config, 44100);
+        let mut out = [1.0; 64];
+        wave.fill(&mut out);
+        assert!(out.iter().all(|sample| *sample == 0.0));
+    }
+}
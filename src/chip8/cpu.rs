@@ -1,7 +1,19 @@
-use std::time::Duration;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
+use super::audio::{AudioPattern, DEFAULT_PITCH};
+use super::debugger::Debugger;
 use super::display;
-use rand::Rng;
+use super::error::EmulatorError;
+use super::frontend::Frontend;
+use super::instruction::Instruction;
+use super::keypad::Keypad;
+use super::movie::Recorder;
+use super::quirks::{IndexIncrement, Quirks};
+use super::random::Random;
+use super::rewind::{Rewind, DEFAULT_REWIND_FRAMES};
+use super::savestate::{self, SlotCommand};
+use super::trace::{Registers, TraceEntry, Tracer};
 
 const FONTS: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -22,13 +34,140 @@ const FONTS: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// SUPER-CHIP 8x10 digits, A-F follow Octo
+const BIG_FONTS: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFE, 0xFE, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFE, 0xFE, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+pub const TIMER_HZ: u32 = 60;
+// Roughly 600 instructions per second
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+
 trait FontMemStart {
     const FONT_MEM_START: usize = 0x050;
+    const BIG_FONT_MEM_START: usize = 0x0A0;
+}
+
+// Which instruction set the machine understands
+// Ordered so that every mode understands the instructions of the ones before it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Mode {
+    #[default]
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+pub const MODE_NAMES: [&str; 3] = ["chip8", "schip", "xochip"];
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chip8" => Some(Mode::Chip8),
+            "schip" => Some(Mode::SuperChip),
+            "xochip" => Some(Mode::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Chip8 => "chip8",
+            Mode::SuperChip => "schip",
+            Mode::XoChip => "xochip",
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Mode::Chip8 | Mode::SuperChip => 4096,
+            Mode::XoChip => 65536,
+        }
+    }
+
+    // Quirks the programs written for this mode usually expect
+    pub fn default_quirks(self) -> Quirks {
+        match self {
+            Mode::Chip8 => Quirks::cosmac_vip(),
+            Mode::SuperChip => Quirks::superchip(),
+            Mode::XoChip => Quirks::modern(),
+        }
+    }
+}
+
+// What the machine does with an opcode its mode doesn't define
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodePolicy {
+    // Stops with an error naming the opcode and its address
+    #[default]
+    Halt,
+    // Steps over it and notes it in `skipped_opcodes`
+    Skip,
+    // Treats 0NNN as a call to machine code that returns straight away, the
+    // way most VIP programs used it, and halts on anything else
+    Vip,
+}
+
+pub const OPCODE_POLICY_NAMES: [&str; 3] = ["halt", "skip", "vip"];
+
+impl OpcodePolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "halt" => Some(OpcodePolicy::Halt),
+            "skip" => Some(OpcodePolicy::Skip),
+            "vip" => Some(OpcodePolicy::Vip),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OpcodePolicy::Halt => "halt",
+            OpcodePolicy::Skip => "skip",
+            OpcodePolicy::Vip => "vip",
+        }
+    }
+}
+
+// Memory touched by an instruction, recorded for the debugger's watchpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read { address: u16, length: u16 },
+    Write { address: u16, length: u16 },
+    // DXYN also notes where the sprite starts
+    Sprite { address: u16 },
+}
+
+// Optional tools the run loop drives along with the machine
+#[derive(Debug, Default)]
+pub struct Session<'a> {
+    // Decides when instructions execute
+    pub debugger: Option<&'a mut Debugger>,
+    // Records the keypad of every frame into a movie
+    pub recorder: Option<&'a mut Recorder>,
+    // Writes every executed instruction to a trace
+    pub tracer: Option<&'a mut Tracer>,
 }
 
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
 pub struct CPU {
-    pub memory: [u8; 4096],
+    pub memory: Vec<u8>,
     pub v_reg: [u8; 16],
     pub i_reg: u16,
     pub delay_reg: u8,
@@ -38,14 +177,58 @@ pub struct CPU {
     pub stack: [u16; 16],
     pub opcodes: Vec<u16>,
     pub display: display::Display,
+    pub keypad: Keypad,
+    pub instructions_per_frame: u32,
+    pub quirks: Quirks,
+    pub mode: Mode,
+    // SUPER-CHIP persistent user flags, saved by FX75 and loaded by FX85
+    pub rpl_flags: [u8; 16],
+    // XO-CHIP audio pattern loaded by F002 and its pitch set by FX3A
+    pub audio_buffer: Option<[u8; 16]>,
+    pub audio_pitch: u8,
+    // Set by 00FD, the machine stops executing instructions
+    pub halted: bool,
+    // Key seen held down by FX0A, which completes once it is released
+    pub waiting_key: Option<u8>,
+    // Source of CXNN's random bytes
+    pub random: Random,
+    // Memory accesses made since the log was last cleared, only kept while
+    // it is Some
+    pub access_log: Option<Vec<MemoryAccess>>,
+    // Identifies the ROM so save states from other ROMs are rejected
+    pub rom_hash: u64,
+    // ROM the save state slots are named after, None disables them
+    pub rom_path: Option<PathBuf>,
+    // Frames of history the run loop keeps for rewinding, 0 disables it
+    pub rewind_frames: usize,
+    pub opcode_policy: OpcodePolicy,
+    // Unknown opcodes stepped over by OpcodePolicy::Skip, once per address
+    pub skipped_opcodes: Vec<EmulatorError>,
+    // Instructions executed so far
+    pub cycles: u64,
+    // Instructions executed since the log was last cleared, only kept while
+    // it is Some
+    pub trace_log: Option<Vec<TraceEntry>>,
 }
 impl FontMemStart for CPU {}
 
 impl CPU {
-    pub fn new(rom_buf: &[u8]) -> Self {
+    pub fn new(rom_buf: &[u8]) -> Result<Self, EmulatorError> {
+        CPU::with_mode(rom_buf, Mode::default())
+    }
+
+    // Sizes the memory for the given mode and picks its usual quirks
+    pub fn with_mode(rom_buf: &[u8], mode: Mode) -> Result<Self, EmulatorError> {
+        let max = mode.memory_size() - 0x200;
+        if rom_buf.len() > max {
+            return Err(EmulatorError::RomTooLarge {
+                size: rom_buf.len(),
+                max,
+            });
+        }
         let opcodes = CPU::convert_rom_to_opcodes(rom_buf);
         let mut cpu = CPU {
-            memory: [0; 4096],
+            memory: vec![0; mode.memory_size()],
             v_reg: [0; 16],
             i_reg: 0,
             delay_reg: 0,
@@ -54,21 +237,43 @@ impl CPU {
             stack_ptr: 0,
             stack: [0; 16],
             opcodes, // Is used for debugging purposes
-            display: display::Display::new(640, 320),
+            display: display::Display::default(),
+            keypad: Keypad::default(),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            quirks: mode.default_quirks(),
+            mode,
+            rpl_flags: [0; 16],
+            audio_buffer: None,
+            audio_pitch: DEFAULT_PITCH,
+            halted: false,
+            waiting_key: None,
+            random: Random::default(),
+            access_log: None,
+            rom_hash: savestate::rom_hash(rom_buf),
+            rom_path: None,
+            rewind_frames: DEFAULT_REWIND_FRAMES,
+            opcode_policy: OpcodePolicy::default(),
+            skipped_opcodes: Vec::new(),
+            cycles: 0,
+            trace_log: None,
         };
         // Initialize fonts in the interpreter btw. 0x000-0x1FF
         // Fonts will be stored between 0x050-0x09F
+        // Big fonts will be stored between 0x0A0-0x13F
         cpu.init_fonts();
         // Load the rom into memory starting from 0x200
         // PC will point to 0x200 initially
         cpu.load_rom_into_memory(rom_buf);
-        cpu
+        Ok(cpu)
     }
 
     fn init_fonts(&mut self) {
         for (ind, font) in FONTS.iter().enumerate() {
             self.memory[CPU::FONT_MEM_START + ind] = *font;
         }
+        for (ind, font) in BIG_FONTS.iter().enumerate() {
+            self.memory[CPU::BIG_FONT_MEM_START + ind] = *font;
+        }
     }
 
     fn load_rom_into_memory(&mut self, rom_buf: &[u8]) {
@@ -89,86 +294,352 @@ impl CPU {
     }
 
     fn fetch_current_instruction(&mut self) -> u16 {
-        ((self.memory[self.prog_counter as usize] as u16) << 8)
-            | (self.memory[(self.prog_counter + 1) as usize] as u16)
+        self.read_word(self.prog_counter)
     }
 
-    pub fn run(&mut self) {
-        'running: loop {
-            let opcode = self.fetch_current_instruction();
-            println!("{:04X}", opcode);
-            self.run_instruction(opcode);
+    // Bytes past the end of memory read as 0, instructions check their
+    // accesses before they get here
+    pub fn read_word(&self, address: u16) -> u16 {
+        let byte = |address: usize| self.memory.get(address).copied().unwrap_or(0) as u16;
+        (byte(address as usize) << 8) | byte(address as usize + 1)
+    }
+
+    // Notes memory an instruction touched while the debugger is watching
+    fn record_access(&mut self, access: MemoryAccess) {
+        if let Some(log) = &mut self.access_log {
+            log.push(access);
+        }
+    }
 
-            let should_break = self.display.update();
-            if should_break {
-                break 'running;
+    // XO-CHIP skips have to step over the whole 4 byte F000 NNNN
+    fn skip_next_instruction(&mut self) -> Result<(), EmulatorError> {
+        let next = self.read_word(self.prog_counter + 2);
+        let length = if self.mode == Mode::XoChip && next == 0xF000 {
+            4
+        } else {
+            2
+        };
+        // The skip itself still has to land inside memory
+        self.check_pc(self.prog_counter as usize + length + 2)?;
+        self.prog_counter += length as u16;
+        Ok(())
+    }
+
+    // The PC never wraps, an instruction that would move it past the end of
+    // memory fails instead
+    fn check_pc(&self, address: usize) -> Result<(), EmulatorError> {
+        if address >= self.memory.len() {
+            return Err(EmulatorError::MemoryOutOfBounds {
+                pc: self.prog_counter,
+                address,
+            });
+        }
+        Ok(())
+    }
+
+    // Instructions that carry on with the one after them, checked before
+    // they run so that a failure doesn't leave them half done
+    fn check_fall_through(&self, opcode: u16) -> Result<(), EmulatorError> {
+        match Instruction::decode(opcode) {
+            Ok(Instruction::Return)
+            | Ok(Instruction::Exit)
+            | Ok(Instruction::Jump { .. })
+            | Ok(Instruction::Call { .. })
+            | Ok(Instruction::JumpOffset { .. }) => Ok(()),
+            _ if opcode == 0xF000 && self.mode == Mode::XoChip => {
+                self.check_pc(self.prog_counter as usize + 4)
             }
+            _ => self.check_pc(self.prog_counter as usize + 2),
+        }
+    }
+
+    // Fails before touching memory past its end
+    fn check_bounds(&self, address: usize, length: usize) -> Result<(), EmulatorError> {
+        if address + length > self.memory.len() {
+            return Err(EmulatorError::MemoryOutOfBounds {
+                pc: self.prog_counter,
+                address: address.max(self.memory.len()),
+            });
+        }
+        Ok(())
+    }
+
+    // Fetches and executes a single instruction without touching any frontend.
+    // A failed instruction leaves the machine as it was before it.
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        if self.halted {
+            return Ok(());
+        }
+        self.check_bounds(self.prog_counter as usize, 2)?;
+        let opcode = self.fetch_current_instruction();
+        let pc = self.prog_counter;
+        let result = self
+            .check_fall_through(opcode)
+            .and_then(|()| self.run_instruction(opcode));
+        if self.trace_log.is_some() {
+            let entry = TraceEntry {
+                cycle: self.cycles,
+                pc,
+                opcode,
+                long_operand: self.read_word(pc.wrapping_add(2)),
+                after: Registers::of(self),
+            };
+            self.trace_log.get_or_insert_with(Vec::new).push(entry);
+        }
+        self.cycles += 1;
+        result
+    }
+
+    // Executes one frame worth of instructions followed by a timer tick
+    pub fn run_frame(&mut self) -> Result<(), EmulatorError> {
+        for _ in 0..self.instructions_per_frame {
+            self.step()?;
+        }
+        self.tick_timers();
+        Ok(())
+    }
+
+    // Timers count down at 60 Hz regardless of the instruction rate
+    pub fn tick_timers(&mut self) {
+        if self.delay_reg > 0 {
+            self.delay_reg -= 1;
+        }
+        if self.sound_reg > 0 {
+            self.sound_reg -= 1;
+        }
+    }
+
+    // Pattern the buzzer should play, None for the classic tone
+    pub fn audio_pattern(&self) -> Option<AudioPattern> {
+        self.audio_buffer.map(|pattern| AudioPattern {
+            pattern,
+            pitch: self.audio_pitch,
+        })
+    }
 
-            if self.delay_reg > 0 {
-                self.delay_reg -= 1;
+    pub fn run(&mut self, frontend: &mut dyn Frontend) -> Result<(), EmulatorError> {
+        self.run_session(frontend, Session::default())
+    }
+
+    // Runs until the user quits or the program exits, stops early when an
+    // instruction fails outside the debugger
+    pub fn run_session(
+        &mut self,
+        frontend: &mut dyn Frontend,
+        session: Session,
+    ) -> Result<(), EmulatorError> {
+        let Session {
+            mut debugger,
+            mut recorder,
+            mut tracer,
+        } = session;
+        let frame_duration = Duration::from_secs(1) / TIMER_HZ;
+        let mut next_frame = Instant::now();
+        let mut audio_pattern = None;
+        let mut rewind = Rewind::new(self.rewind_frames);
+        rewind.capture(self);
+        let mut skips_reported = self.skipped_opcodes.len();
+        if tracer.is_some() {
+            self.trace_log = Some(Vec::new());
+        }
+        let result = 'running: loop {
+            let should_break = frontend.poll_input(&mut self.keypad);
+            if should_break || self.halted {
+                break 'running Ok(());
+            }
+            for command in frontend.slot_commands() {
+                // Movies only hold the keypad, a replay couldn't follow a load
+                if recorder.is_some() && matches!(command, SlotCommand::Load(_)) {
+                    eprintln!("Slots can't be loaded while recording");
+                    continue;
+                }
+                savestate::run_slot_command(self, command);
             }
-            if self.sound_reg > 0 {
-                if self.sound_reg == 1 {
-                    println!("BEEP");
+
+            if self.audio_pattern() != audio_pattern {
+                audio_pattern = self.audio_pattern();
+                frontend.set_audio_pattern(audio_pattern);
+            }
+            let paused = debugger.as_ref().is_some_and(|debugger| debugger.paused);
+            let rewinding = frontend.rewind_held();
+            frontend.set_buzzer(self.sound_reg > 0 && !paused && !rewinding);
+            if rewinding {
+                // One frame back per frame plays the game backwards in real
+                // time, once the history runs out it holds still. The movie
+                // forgets the frames that were undone.
+                if rewind.step_back(self) {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.drop_last_frame();
+                    }
+                }
+            } else {
+                match debugger.as_mut() {
+                    Some(debugger) => {
+                        if debugger.run_frame(self, frontend) {
+                            break 'running Ok(());
+                        }
+                    }
+                    None => {
+                        if let Err(e) = self.run_frame() {
+                            break 'running Err(e);
+                        }
+                        for error in self.skipped_opcodes[skips_reported..].iter() {
+                            eprintln!("Skipped {}", error);
+                        }
+                        skips_reported = self.skipped_opcodes.len();
+                    }
+                }
+                if !debugger.as_ref().is_some_and(|debugger| debugger.paused) {
+                    rewind.capture(self);
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.record_frame(self);
+                    }
                 }
-                self.sound_reg -= 1;
             }
+            self.write_trace(&mut tracer);
 
-            ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+            // The debugger overlay stays up while paused
+            let paused = debugger.as_ref().is_some_and(|debugger| debugger.paused);
+            if self.display.dirty && !paused {
+                frontend.present(&self.display);
+                self.display.dirty = false;
+            }
+
+            // Sleep against a fixed schedule so the timers keep real time, but
+            // don't try to catch up after a long stall
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                ::std::thread::sleep(next_frame - now);
+            } else if now - next_frame > frame_duration * 4 {
+                next_frame = now;
+            }
+        };
+        // Include the instructions up to a failure
+        self.write_trace(&mut tracer);
+        if let Some(tracer) = tracer {
+            if let Err(e) = tracer.flush() {
+                eprintln!("Could not finish the trace, {}", e);
+            }
         }
+        self.trace_log = None;
+        result
     }
 
-    pub fn run_instruction(&mut self, opcode: u16) {
-        let (op0, op1, op2, op3): (u8, u8, u8, u8) = (
-            ((opcode & 0xF000) >> 12) as u8,
-            ((opcode & 0x0F00) >> 8) as u8,
-            ((opcode & 0x00F0) >> 4) as u8,
-            (opcode & 0x000F) as u8,
-        );
+    // Hands the instructions run since the last call to the tracer, tracing
+    // stops when the trace can't be written
+    fn write_trace(&mut self, tracer: &mut Option<&mut Tracer>) {
+        let writer = match tracer {
+            Some(writer) => writer,
+            None => return,
+        };
+        let entries = self.trace_log.replace(Vec::new()).unwrap_or_default();
+        if let Err(e) = entries.iter().try_for_each(|entry| writer.record(entry)) {
+            eprintln!("Stopped tracing, {}", e);
+            *tracer = None;
+            self.trace_log = None;
+        }
+    }
+
+    pub fn run_instruction(&mut self, opcode: u16) -> Result<(), EmulatorError> {
+        match Instruction::decode(opcode) {
+            Ok(instruction) if instruction.required_mode() <= self.mode => {
+                self.execute(instruction)
+            }
+            _ => self.unknown_opcode(opcode),
+        }
+    }
+
+    fn unknown_opcode(&mut self, opcode: u16) -> Result<(), EmulatorError> {
+        let error = EmulatorError::UnknownOpcode {
+            pc: self.prog_counter,
+            opcode,
+        };
+        match self.opcode_policy {
+            OpcodePolicy::Skip => {
+                if !self.skipped_opcodes.contains(&error) {
+                    self.skipped_opcodes.push(error);
+                }
+                self.prog_counter += 2;
+                Ok(())
+            }
+            OpcodePolicy::Vip if opcode & 0xF000 == 0 => {
+                self.prog_counter += 2;
+                Ok(())
+            }
+            _ => Err(error),
+        }
+    }
 
-        let nnn = ((op1 as u16) << 8) | ((op2 as u16) << 4) | (op3 as u16);
-        let nn: u8 = ((op2 as u8) << 4) | (op3 as u8);
-        let n = op3;
-        let vx = op1;
-        let vy = op2;
-
-        match (op0, op1, op2, op3) {
-            (0x0, 0x0, 0xE, 0x0) => self.clear_display(),
-            (0x0, 0x0, 0xE, 0xE) => self.return_from_subroutine(),
-            (0x1, _, _, _) => self.jump_to_address(nnn),
-            (0x2, _, _, _) => self.call_subroutine_at_address(nnn),
-            (0x3, _, _, _) => self.skip_if_vx_eq_nn(vx, nn),
-            (0x4, _, _, _) => self.skip_if_vx_neq_nn(vx, nn),
-            (0x5, _, _, _) => self.skip_if_vx_eq_vy(vx, vy),
-            (0x6, _, _, _) => self.set_vx_to_nn(vx, nn),
-            (0x7, _, _, _) => self.add_vx_nn(vx, nn),
-            (0x8, _, _, 0x0) => self.set_vx_to_vy(vx, vy),
-            (0x8, _, _, 0x1) => self.set_vx_to_vx_or_vy(vx, vy),
-            (0x8, _, _, 0x2) => self.set_vx_to_vx_and_vy(vx, vy),
-            (0x8, _, _, 0x3) => self.set_vx_to_vx_xor_vy(vx, vy),
-            (0x8, _, _, 0x4) => self.add_vx_vy(vx, vy),
-            (0x8, _, _, 0x5) => self.sub_vx_vy(vx, vy),
-            (0x8, _, _, 0x6) => self.shift_vx_right(vx),
-            (0x8, _, _, 0x7) => self.sub_vy_vx(vx, vy),
-            (0x8, _, _, 0xE) => self.shift_vx_left(vx),
-            (0x9, _, _, _) => self.skip_if_vx_neq_vy(vx, vy),
-            (0xA, _, _, _) => self.set_ind_reg_to_address(nnn),
-            (0xB, _, _, _) => self.jump_to_v0_plus_address(nnn),
-            (0xC, _, _, _) => self.set_vx_to_rnd_and_nn(vx, nn),
-            (0xD, _, _, _) => self.display_sprite(vx, vy, n),
-            (0xE, _, 0x9, 0xE) => self.skip_if_key_eq_vx_pressed(vx),
-            (0xE, _, 0xA, 0x1) => self.skip_if_key_eq_vx_not_pressed(vx),
-            (0xF, _, 0x0, 0x7) => self.set_vx_to_delay_timer(vx),
-            (0xF, _, 0x0, 0xA) => self.set_vx_to_key_press(vx),
-            (0xF, _, 0x1, 0x5) => self.set_delay_timer_to_vx(vx),
-            (0xF, _, 0x1, 0x8) => self.set_sound_timer_to_vx(vx),
-            (0xF, _, 0x1, 0xE) => self.add_ind_reg_vx(vx),
-            (0xF, _, 0x2, 0x9) => self.set_ind_reg_to_loc_of_sprite_for_digit_vx(vx),
-            (0xF, _, 0x3, 0x3) => self.store_bcd_vx_in_ind_reg(vx),
-            (0xF, _, 0x5, 0x5) => self.store_v_reg_in_memory_from_ind_reg(vx),
-            (0xF, _, 0x6, 0x5) => self.read_v_reg_from_ind_reg(vx),
-            _ => println!("NEXT_INST"),
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), EmulatorError> {
+        match instruction {
+            Instruction::ScrollDown { n } => self.scroll_display_down(n),
+            Instruction::ScrollUp { n } => self.scroll_display_up(n),
+            Instruction::Clear => self.clear_display(),
+            Instruction::Return => self.return_from_subroutine()?,
+            Instruction::ScrollRight => self.scroll_display_right(),
+            Instruction::ScrollLeft => self.scroll_display_left(),
+            Instruction::Exit => self.exit_interpreter(),
+            Instruction::Lores => self.set_lores(),
+            Instruction::Hires => self.set_hires(),
+            // There is no 1802 to run machine code on
+            Instruction::MachineCall { .. } => {
+                let opcode = self.fetch_current_instruction();
+                return self.unknown_opcode(opcode);
+            }
+            Instruction::Jump { nnn } => self.jump_to_address(nnn),
+            Instruction::Call { nnn } => self.call_subroutine_at_address(nnn)?,
+            Instruction::SkipEqImm { x, nn } => self.skip_if_vx_eq_nn(x, nn)?,
+            Instruction::SkipNeqImm { x, nn } => self.skip_if_vx_neq_nn(x, nn)?,
+            Instruction::SkipEq { x, y } => self.skip_if_vx_eq_vy(x, y)?,
+            Instruction::SaveRange { x, y } => self.store_vx_to_vy_in_memory_from_ind_reg(x, y)?,
+            Instruction::LoadRange { x, y } => self.read_vx_to_vy_from_ind_reg(x, y)?,
+            Instruction::LoadImm { x, nn } => self.set_vx_to_nn(x, nn),
+            Instruction::AddImm { x, nn } => self.add_vx_nn(x, nn),
+            Instruction::Move { x, y } => self.set_vx_to_vy(x, y),
+            Instruction::Or { x, y } => self.set_vx_to_vx_or_vy(x, y),
+            Instruction::And { x, y } => self.set_vx_to_vx_and_vy(x, y),
+            Instruction::Xor { x, y } => self.set_vx_to_vx_xor_vy(x, y),
+            Instruction::Add { x, y } => self.add_vx_vy(x, y),
+            Instruction::Sub { x, y } => self.sub_vx_vy(x, y),
+            Instruction::ShiftRight { x, y } => self.shift_vx_right(x, y),
+            Instruction::SubReverse { x, y } => self.sub_vy_vx(x, y),
+            Instruction::ShiftLeft { x, y } => self.shift_vx_left(x, y),
+            Instruction::SkipNeq { x, y } => self.skip_if_vx_neq_vy(x, y)?,
+            Instruction::LoadI { nnn } => self.set_ind_reg_to_address(nnn),
+            Instruction::JumpOffset { nnn } => self.jump_to_v0_plus_address((nnn >> 8) as u8, nnn),
+            Instruction::Random { x, nn } => self.set_vx_to_rnd_and_nn(x, nn),
+            Instruction::Draw { x, y, n } => self.display_sprite(x, y, n)?,
+            Instruction::SkipKey { x } => self.skip_if_key_eq_vx_pressed(x)?,
+            Instruction::SkipNotKey { x } => self.skip_if_key_eq_vx_not_pressed(x)?,
+            Instruction::LoadILong => self.set_ind_reg_to_long_address(),
+            Instruction::SelectPlanes { n } => self.select_planes(n),
+            Instruction::LoadAudio => self.load_audio_pattern_from_ind_reg()?,
+            Instruction::GetDelay { x } => self.set_vx_to_delay_timer(x),
+            Instruction::WaitKey { x } => self.set_vx_to_key_press(x),
+            Instruction::SetDelay { x } => self.set_delay_timer_to_vx(x),
+            Instruction::SetSound { x } => self.set_sound_timer_to_vx(x),
+            Instruction::AddI { x } => self.add_ind_reg_vx(x),
+            Instruction::LoadFont { x } => self.set_ind_reg_to_loc_of_sprite_for_digit_vx(x),
+            Instruction::LoadBigFont { x } => self.set_ind_reg_to_loc_of_big_sprite_for_digit_vx(x),
+            Instruction::Bcd { x } => self.store_bcd_vx_in_ind_reg(x)?,
+            Instruction::SetPitch { x } => self.set_audio_pitch_to_vx(x),
+            Instruction::Store { x } => self.store_v_reg_in_memory_from_ind_reg(x)?,
+            Instruction::Load { x } => self.read_v_reg_from_ind_reg(x)?,
+            Instruction::SaveFlags { x } => self.store_v_reg_in_rpl_flags(x),
+            Instruction::LoadFlags { x } => self.read_v_reg_from_rpl_flags(x),
         }
+        Ok(())
+    }
+    // 00CN
+    fn scroll_display_down(&mut self, n: u8) {
+        self.display.scroll(0, n as i32);
+        self.prog_counter += 2;
+    }
+    // 00DN
+    fn scroll_display_up(&mut self, n: u8) {
+        self.display.scroll(0, -(n as i32));
+        self.prog_counter += 2;
     }
     // 00E0
     fn clear_display(&mut self) {
@@ -176,43 +647,124 @@ impl CPU {
         self.prog_counter += 2;
     }
     // 00EE
-    fn return_from_subroutine(&mut self) {
+    fn return_from_subroutine(&mut self) -> Result<(), EmulatorError> {
+        if self.stack_ptr == 0 {
+            return Err(EmulatorError::StackUnderflow {
+                pc: self.prog_counter,
+            });
+        }
+        let address = self.stack[self.stack_ptr as usize - 1];
+        self.check_pc(address as usize + 2)?;
         self.stack_ptr -= 1;
-        self.prog_counter = self.stack[(self.stack_ptr as usize)];
-        self.prog_counter += 2;
+        self.prog_counter = address + 2;
         self.stack[self.stack_ptr as usize] = 0;
+        Ok(())
+    }
+    // 00FB
+    fn scroll_display_right(&mut self) {
+        self.display.scroll(4, 0);
+        self.prog_counter += 2;
+    }
+    // 00FC
+    fn scroll_display_left(&mut self) {
+        self.display.scroll(-4, 0);
+        self.prog_counter += 2;
+    }
+    // 00FD
+    fn exit_interpreter(&mut self) {
+        self.halted = true;
+    }
+    // 00FE
+    fn set_lores(&mut self) {
+        self.display
+            .resize(display::BASE_WIDTH, display::BASE_HEIGHT);
+        self.prog_counter += 2;
+    }
+    // 00FF
+    fn set_hires(&mut self) {
+        self.display
+            .resize(display::HIRES_WIDTH, display::HIRES_HEIGHT);
+        self.prog_counter += 2;
     }
     // 1NNN
     fn jump_to_address(&mut self, address: u16) {
         self.prog_counter = address;
     }
     // 2NNN
-    fn call_subroutine_at_address(&mut self, address: u16) {
+    fn call_subroutine_at_address(&mut self, address: u16) -> Result<(), EmulatorError> {
+        if self.stack_ptr as usize == self.stack.len() {
+            return Err(EmulatorError::StackOverflow {
+                pc: self.prog_counter,
+            });
+        }
         // Store the program counter in the stack
-        self.stack[(self.stack_ptr as usize)] = self.prog_counter;
+        self.stack[self.stack_ptr as usize] = self.prog_counter;
         self.stack_ptr += 1;
         self.prog_counter = address;
+        Ok(())
     }
     // 3XNN
-    fn skip_if_vx_eq_nn(&mut self, vx: u8, nn: u8) {
-        if self.v_reg[(vx as usize)] == nn {
-            self.prog_counter += 2;
+    fn skip_if_vx_eq_nn(&mut self, vx: u8, nn: u8) -> Result<(), EmulatorError> {
+        if self.v_reg[vx as usize] == nn {
+            self.skip_next_instruction()?;
         }
         self.prog_counter += 2;
+        Ok(())
     }
     // 4XNN
-    fn skip_if_vx_neq_nn(&mut self, vx: u8, nn: u8) {
+    fn skip_if_vx_neq_nn(&mut self, vx: u8, nn: u8) -> Result<(), EmulatorError> {
         if self.v_reg[vx as usize] != nn {
-            self.prog_counter += 2;
+            self.skip_next_instruction()?;
         }
         self.prog_counter += 2;
+        Ok(())
     }
     // 5XY0
-    fn skip_if_vx_eq_vy(&mut self, vx: u8, vy: u8) {
+    fn skip_if_vx_eq_vy(&mut self, vx: u8, vy: u8) -> Result<(), EmulatorError> {
         if self.v_reg[vx as usize] == self.v_reg[vy as usize] {
-            self.prog_counter += 2;
+            self.skip_next_instruction()?;
+        }
+        self.prog_counter += 2;
+        Ok(())
+    }
+    // 5XY2
+    fn store_vx_to_vy_in_memory_from_ind_reg(
+        &mut self,
+        vx: u8,
+        vy: u8,
+    ) -> Result<(), EmulatorError> {
+        self.check_bounds(self.i_reg as usize, CPU::register_range(vx, vy).count())?;
+        self.record_access(MemoryAccess::Write {
+            address: self.i_reg,
+            length: CPU::register_range(vx, vy).count() as u16,
+        });
+        for (offset, reg) in CPU::register_range(vx, vy).enumerate() {
+            self.memory[self.i_reg as usize + offset] = self.v_reg[reg];
+        }
+        self.prog_counter += 2;
+        Ok(())
+    }
+    // 5XY3
+    fn read_vx_to_vy_from_ind_reg(&mut self, vx: u8, vy: u8) -> Result<(), EmulatorError> {
+        self.check_bounds(self.i_reg as usize, CPU::register_range(vx, vy).count())?;
+        self.record_access(MemoryAccess::Read {
+            address: self.i_reg,
+            length: CPU::register_range(vx, vy).count() as u16,
+        });
+        for (offset, reg) in CPU::register_range(vx, vy).enumerate() {
+            self.v_reg[reg] = self.memory[self.i_reg as usize + offset];
         }
         self.prog_counter += 2;
+        Ok(())
+    }
+    // Registers from X to Y inclusive, in reverse when Y is below X
+    fn register_range(vx: u8, vy: u8) -> Box<dyn Iterator<Item = usize>> {
+        let (vx, vy) = (vx as usize, vy as usize);
+        if vx <= vy {
+            Box::new(vx..=vy)
+        } else {
+            Box::new((vy..=vx).rev())
+        }
     }
     // 6XNN
     fn set_vx_to_nn(&mut self, vx: u8, nn: u8) {
@@ -232,18 +784,27 @@ impl CPU {
     // 8XY1
     fn set_vx_to_vx_or_vy(&mut self, vx: u8, vy: u8) {
         self.v_reg[vx as usize] |= self.v_reg[vy as usize];
+        self.reset_vf_after_logic();
         self.prog_counter += 2;
     }
     // 8XY2
     fn set_vx_to_vx_and_vy(&mut self, vx: u8, vy: u8) {
         self.v_reg[vx as usize] &= self.v_reg[vy as usize];
+        self.reset_vf_after_logic();
         self.prog_counter += 2;
     }
     // 8XY3
     fn set_vx_to_vx_xor_vy(&mut self, vx: u8, vy: u8) {
         self.v_reg[vx as usize] ^= self.v_reg[vy as usize];
+        self.reset_vf_after_logic();
         self.prog_counter += 2;
     }
+    // The VIP runs 8XY1/2/3 through a routine that clobbers VF
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v_reg[0xF] = 0;
+        }
+    }
     // 8XY4
     fn add_vx_vy(&mut self, vx: u8, vy: u8) {
         let val_x = self.v_reg[vx as usize];
@@ -259,15 +820,16 @@ impl CPU {
         let val_x = self.v_reg[vx as usize];
         let val_y = self.v_reg[vy as usize];
 
-        self.v_reg[0xF] = if val_x > val_y { 1 } else { 0 };
+        // VF is the inverted borrow, set last so it survives VF -= VY
         self.v_reg[vx as usize] = val_x.wrapping_sub(val_y);
+        self.v_reg[0xF] = if val_x >= val_y { 1 } else { 0 };
         self.prog_counter += 2;
     }
     // 8XY6
-    fn shift_vx_right(&mut self, vx: u8) {
-        let val_x = self.v_reg[vx as usize];
-        self.v_reg[0xF] = val_x & 1;
-        self.v_reg[vx as usize] >>= 1;
+    fn shift_vx_right(&mut self, vx: u8, vy: u8) {
+        let val = self.shift_source(vx, vy);
+        self.v_reg[vx as usize] = val >> 1;
+        self.v_reg[0xF] = val & 1;
         self.prog_counter += 2;
     }
     // 8XY7
@@ -275,22 +837,31 @@ impl CPU {
         let val_x = self.v_reg[vx as usize];
         let val_y = self.v_reg[vy as usize];
 
-        self.v_reg[0xF] = if val_y > val_x { 1 } else { 0 };
         self.v_reg[vx as usize] = val_y.wrapping_sub(val_x);
+        self.v_reg[0xF] = if val_y >= val_x { 1 } else { 0 };
         self.prog_counter += 2;
     }
     // 8XYE
-    fn shift_vx_left(&mut self, vx: u8) {
-        self.v_reg[0xF] = (self.v_reg[vx as usize] & 0b10000000) >> 7;
-        self.v_reg[vx as usize] <<= 1;
+    fn shift_vx_left(&mut self, vx: u8, vy: u8) {
+        let val = self.shift_source(vx, vy);
+        self.v_reg[vx as usize] = val << 1;
+        self.v_reg[0xF] = (val & 0b10000000) >> 7;
         self.prog_counter += 2;
     }
+    fn shift_source(&self, vx: u8, vy: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.v_reg[vy as usize]
+        } else {
+            self.v_reg[vx as usize]
+        }
+    }
     // 9XY0
-    fn skip_if_vx_neq_vy(&mut self, vx: u8, vy: u8) {
+    fn skip_if_vx_neq_vy(&mut self, vx: u8, vy: u8) -> Result<(), EmulatorError> {
         if self.v_reg[vx as usize] != self.v_reg[vy as usize] {
-            self.prog_counter += 2;
+            self.skip_next_instruction()?;
         }
         self.prog_counter += 2;
+        Ok(())
     }
     // ANNN
     fn set_ind_reg_to_address(&mut self, address: u16) {
@@ -298,47 +869,125 @@ impl CPU {
         self.prog_counter += 2;
     }
     // BNNN
-    fn jump_to_v0_plus_address(&mut self, address: u16) {
-        self.prog_counter = (self.v_reg[0] as u16) + address;
+    fn jump_to_v0_plus_address(&mut self, vx: u8, address: u16) {
+        // CHIP-48 and SUPER-CHIP read BXNN as a jump to XNN + VX
+        let offset_reg = if self.quirks.jump_uses_vx { vx } else { 0 };
+        self.prog_counter = (self.v_reg[offset_reg as usize] as u16) + address;
     }
     // CXNN
     fn set_vx_to_rnd_and_nn(&mut self, vx: u8, nn: u8) {
-        let mut rng = rand::thread_rng();
-        let rnd: u8 = rng.gen();
+        let rnd = self.random.next_byte();
         self.v_reg[vx as usize] = rnd & nn;
         self.prog_counter += 2;
     }
-    // DXYN
-    fn display_sprite(&mut self, vx: u8, vy: u8, n: u8) {
-        let x_coords = self.v_reg[vx as usize];
-        let y_coords = self.v_reg[vy as usize];
-        self.v_reg[0xF] = 0;
+    // DXYN, DXY0 draws a 16x16 sprite on SUPER-CHIP and XO-CHIP
+    fn display_sprite(&mut self, vx: u8, vy: u8, n: u8) -> Result<(), EmulatorError> {
+        let (width, height) = (self.display.width, self.display.height);
+        let (rows, bytes_per_row) = if n == 0 && self.mode != Mode::Chip8 {
+            (16, 2)
+        } else {
+            (n as u32, 1)
+        };
+        // The starting position always wraps, only the overflowing part of
+        // the sprite is subject to clipping
+        let x_coords = self.v_reg[vx as usize] as u32 % width;
+        let y_coords = self.v_reg[vy as usize] as u32 % height;
+        let mut collision = 0;
+        let planes = self.display.planes.count_ones();
+        self.check_bounds(
+            self.i_reg as usize,
+            (rows * bytes_per_row * planes) as usize,
+        )?;
+        let mut address = self.i_reg as u32;
+        self.record_access(MemoryAccess::Sprite {
+            address: self.i_reg,
+        });
 
-        for row in 0..n {
-            if (self.i_reg + row as u16) >= 4096 {
+        // XO-CHIP draws the sprite once per selected plane, the data for the
+        // second plane directly follows the first
+        for plane in [1u8, 2u8].iter().copied() {
+            if self.display.planes & plane == 0 {
                 continue;
             }
-            let sprite = self.memory[(self.i_reg as usize) + row as usize];
-            let y_coord = (y_coords + row) as u32 % display::BASE_HEIGHT;
-            for bit in 0..8u8 {
-                let x_coord = (x_coords + bit) as u32 % display::BASE_WIDTH;
-                let pixel = self.display.get_pixel(x_coord, y_coord);
-                let sprite_bit = sprite >> (7 - bit) & 1;
-                self.v_reg[0x0F] = pixel & sprite_bit;
-                self.display.set_pixel(x_coord, y_coord, pixel ^ sprite_bit);
+            self.record_access(MemoryAccess::Read {
+                address: address as u16,
+                length: (rows * bytes_per_row) as u16,
+            });
+            for row in 0..rows {
+                let row_address = address + row * bytes_per_row;
+                let mut sprite: u16 = 0;
+                for byte in 0..bytes_per_row {
+                    sprite = (sprite << 8) | self.memory[(row_address + byte) as usize] as u16;
+                }
+                let sprite_width = bytes_per_row * 8;
+                let y_coord = y_coords + row;
+                if y_coord >= height && self.quirks.clip_sprites {
+                    break;
+                }
+                for bit in 0..sprite_width {
+                    let x_coord = x_coords + bit;
+                    if x_coord >= width && self.quirks.clip_sprites {
+                        break;
+                    }
+                    let sprite_bit = (sprite >> (sprite_width - 1 - bit) & 1) as u8;
+                    if sprite_bit == 0 {
+                        continue;
+                    }
+                    let (x_coord, y_coord) = (x_coord % width, y_coord % height);
+                    let pixel = self.display.get_pixel(x_coord, y_coord);
+                    if pixel & plane != 0 {
+                        collision = 1;
+                    }
+                    self.display.set_pixel(x_coord, y_coord, pixel ^ plane);
+                }
             }
+            address += rows * bytes_per_row;
         }
 
-        self.display.draw(false);
+        self.v_reg[0xF] = collision;
+
         self.prog_counter += 2;
+        Ok(())
     }
     // EX9E
-    fn skip_if_key_eq_vx_pressed(&mut self, _vx: u8) {
+    fn skip_if_key_eq_vx_pressed(&mut self, vx: u8) -> Result<(), EmulatorError> {
+        if self.keypad.is_pressed(self.v_reg[vx as usize]) {
+            self.skip_next_instruction()?;
+        }
         self.prog_counter += 2;
+        Ok(())
     }
     // EXA1
-    fn skip_if_key_eq_vx_not_pressed(&mut self, _vx: u8) {
+    fn skip_if_key_eq_vx_not_pressed(&mut self, vx: u8) -> Result<(), EmulatorError> {
+        if !self.keypad.is_pressed(self.v_reg[vx as usize]) {
+            self.skip_next_instruction()?;
+        }
+        self.prog_counter += 2;
+        Ok(())
+    }
+    // F000 NNNN
+    fn set_ind_reg_to_long_address(&mut self) {
+        self.i_reg = self.read_word(self.prog_counter + 2);
+        self.prog_counter += 4;
+    }
+    // FN01
+    fn select_planes(&mut self, n: u8) {
+        self.display.planes = n & 0b11;
+        self.prog_counter += 2;
+    }
+    // F002
+    fn load_audio_pattern_from_ind_reg(&mut self) -> Result<(), EmulatorError> {
+        self.check_bounds(self.i_reg as usize, 16)?;
+        self.record_access(MemoryAccess::Read {
+            address: self.i_reg,
+            length: 16,
+        });
+        let mut pattern = [0; 16];
+        let start = self.i_reg as usize;
+        pattern.copy_from_slice(&self.memory[start..start + 16]);
+        self.audio_buffer = Some(pattern);
         self.prog_counter += 2;
+        Ok(())
     }
     // FX07
     fn set_vx_to_delay_timer(&mut self, vx: u8) {
@@ -346,8 +995,18 @@ impl CPU {
         self.prog_counter += 2;
     }
     // FX0A
-    fn set_vx_to_key_press(&mut self, _vx: u8) {
-        self.prog_counter += 2;
+    // Blocks by not advancing the PC until a key is pressed and then released,
+    // like the COSMAC VIP does
+    fn set_vx_to_key_press(&mut self, vx: u8) {
+        match self.waiting_key {
+            None => self.waiting_key = self.keypad.first_pressed(),
+            Some(key) if !self.keypad.is_pressed(key) => {
+                self.v_reg[vx as usize] = key;
+                self.waiting_key = None;
+                self.prog_counter += 2;
+            }
+            Some(_) => {}
+        }
     }
     // FX15
     fn set_delay_timer_to_vx(&mut self, vx: u8) {
@@ -361,44 +1020,96 @@ impl CPU {
     }
     // FX1E
     fn add_ind_reg_vx(&mut self, vx: u8) {
-        self.i_reg += self.v_reg[vx as usize] as u16;
+        self.i_reg = self.i_reg.wrapping_add(self.v_reg[vx as usize] as u16);
         self.v_reg[0xF] = if self.i_reg > 0x0F00 { 1 } else { 0 };
         self.prog_counter += 2;
     }
     // FX29
     fn set_ind_reg_to_loc_of_sprite_for_digit_vx(&mut self, vx: u8) {
-        let x = self.v_reg[vx as usize];
-        self.i_reg = (x as u16) * 5;
+        let x = self.v_reg[vx as usize] & 0xF;
+        self.i_reg = (CPU::FONT_MEM_START as u16) + (x as u16) * 5;
+        self.prog_counter += 2;
+    }
+    // FX30
+    fn set_ind_reg_to_loc_of_big_sprite_for_digit_vx(&mut self, vx: u8) {
+        let x = self.v_reg[vx as usize] & 0xF;
+        self.i_reg = (CPU::BIG_FONT_MEM_START as u16) + (x as u16) * 10;
         self.prog_counter += 2;
     }
     // FX33
-    fn store_bcd_vx_in_ind_reg(&mut self, vx: u8) {
+    fn store_bcd_vx_in_ind_reg(&mut self, vx: u8) -> Result<(), EmulatorError> {
+        self.check_bounds(self.i_reg as usize, 3)?;
         let ones = self.v_reg[vx as usize] % 10;
         let tens = (self.v_reg[vx as usize] / 10) % 10;
-        let hundreds = (self.v_reg[vx as usize] % 100) % 10;
-        self.memory[(self.i_reg) as usize] = hundreds;
-        self.memory[(self.i_reg + 1) as usize] = tens;
-        self.memory[(self.i_reg + 2) as usize] = ones;
+        let hundreds = self.v_reg[vx as usize] / 100;
+        self.record_access(MemoryAccess::Write {
+            address: self.i_reg,
+            length: 3,
+        });
+        let address = self.i_reg as usize;
+        self.memory[address] = hundreds;
+        self.memory[address + 1] = tens;
+        self.memory[address + 2] = ones;
+        self.prog_counter += 2;
+        Ok(())
+    }
+    // FX3A
+    fn set_audio_pitch_to_vx(&mut self, vx: u8) {
+        self.audio_pitch = self.v_reg[vx as usize];
         self.prog_counter += 2;
     }
     // FX55
-    fn store_v_reg_in_memory_from_ind_reg(&mut self, vx: u8) {
+    fn store_v_reg_in_memory_from_ind_reg(&mut self, vx: u8) -> Result<(), EmulatorError> {
+        self.check_bounds(self.i_reg as usize, vx as usize + 1)?;
+        self.record_access(MemoryAccess::Write {
+            address: self.i_reg,
+            length: vx as u16 + 1,
+        });
         for ind in 0..=(vx as usize) {
             self.memory[(self.i_reg as usize) + ind] = self.v_reg[ind];
         }
+        self.increment_ind_reg_after_load_store(vx);
         self.prog_counter += 2;
+        Ok(())
     }
     // FX65
-    fn read_v_reg_from_ind_reg(&mut self, vx: u8) {
+    fn read_v_reg_from_ind_reg(&mut self, vx: u8) -> Result<(), EmulatorError> {
+        self.check_bounds(self.i_reg as usize, vx as usize + 1)?;
+        self.record_access(MemoryAccess::Read {
+            address: self.i_reg,
+            length: vx as u16 + 1,
+        });
         for ind in 0..=(vx as usize) {
             self.v_reg[ind] = self.memory[(self.i_reg as usize) + ind];
         }
+        self.increment_ind_reg_after_load_store(vx);
+        self.prog_counter += 2;
+        Ok(())
+    }
+    fn increment_ind_reg_after_load_store(&mut self, vx: u8) {
+        match self.quirks.load_store_index {
+            IndexIncrement::Unchanged => {}
+            IndexIncrement::ByX => self.i_reg = self.i_reg.wrapping_add(vx as u16),
+            IndexIncrement::ByXPlusOne => self.i_reg = self.i_reg.wrapping_add(vx as u16 + 1),
+        }
+    }
+    // FX75
+    fn store_v_reg_in_rpl_flags(&mut self, vx: u8) {
+        let count = vx as usize + 1;
+        self.rpl_flags[..count].copy_from_slice(&self.v_reg[..count]);
+        self.prog_counter += 2;
+    }
+    // FX85
+    fn read_v_reg_from_rpl_flags(&mut self, vx: u8) {
+        let count = vx as usize + 1;
+        self.v_reg[..count].copy_from_slice(&self.rpl_flags[..count]);
         self.prog_counter += 2;
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::frontend::Headless;
     use super::*;
     use std::fs::File;
     use std::io::prelude::*;
@@ -412,7 +1123,7 @@ mod tests {
     #[test]
     fn converts_opcode() {
         let test_opcode = read_test_opcode();
-        let cpu = CPU::new(&test_opcode);
+        let cpu = CPU::new(&test_opcode).unwrap();
         // Just test the first few to make sure they're correct
         assert_eq!(
             cpu.opcodes[0..=5],
@@ -423,59 +1134,76 @@ mod tests {
     }
     #[test]
     fn jumps_to_address() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let addr = 0x300;
         cpu.jump_to_address(addr);
         assert_eq!(cpu.prog_counter, addr);
     }
     #[test]
     fn calls_and_returns_from_subroutine() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let addr = 0x300;
-        cpu.call_subroutine_at_address(addr);
+        cpu.call_subroutine_at_address(addr).unwrap();
         assert_eq!(cpu.prog_counter, addr);
-        cpu.return_from_subroutine();
-        assert_eq!(cpu.prog_counter, 0x200);
+        cpu.return_from_subroutine().unwrap();
+        // Returns to the instruction after the 2NNN call
+        assert_eq!(cpu.prog_counter, 0x202);
+    }
+    #[test]
+    fn reports_stack_overflow_and_underflow() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        assert_eq!(
+            cpu.return_from_subroutine(),
+            Err(EmulatorError::StackUnderflow { pc: 0x200 })
+        );
+        for _ in 0..16 {
+            cpu.call_subroutine_at_address(0x200).unwrap();
+        }
+        assert_eq!(
+            cpu.call_subroutine_at_address(0x300),
+            Err(EmulatorError::StackOverflow { pc: 0x200 })
+        );
+        assert_eq!(cpu.stack_ptr, 16);
     }
     #[test]
     fn skips_if_vx_eq_nn() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let val = 0xCC;
         cpu.v_reg[0] = val;
         // Skip
-        cpu.skip_if_vx_eq_nn(0, val);
+        cpu.skip_if_vx_eq_nn(0, val).unwrap();
         assert_eq!(cpu.prog_counter, 0x0204);
     }
     #[test]
     fn skips_if_vx_neq_nn() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let val = 0xCC;
         cpu.v_reg[0] = val;
         // Skip
-        cpu.skip_if_vx_neq_nn(0, 0xCD);
+        cpu.skip_if_vx_neq_nn(0, 0xCD).unwrap();
         assert_eq!(cpu.prog_counter, 0x204);
     }
     #[test]
     fn skips_if_vx_eq_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let val = 0xCC;
         let vx: u8 = 0;
         let vy: u8 = 1;
-        cpu.v_reg[(vx as usize)] = val;
-        cpu.v_reg[(vy as usize)] = val;
-        cpu.skip_if_vx_eq_vy(vx, vy);
+        cpu.v_reg[vx as usize] = val;
+        cpu.v_reg[vy as usize] = val;
+        cpu.skip_if_vx_eq_vy(vx, vy).unwrap();
         assert_eq!(cpu.prog_counter, 0x204);
     }
     #[test]
     fn sets_vx_to_nn() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let val = 0xFF;
         cpu.set_vx_to_nn(0, val);
         assert_eq!(cpu.v_reg[0], val);
     }
     #[test]
     fn adds_vx_nn() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let val = 0x01;
         cpu.v_reg[0] = 0x02;
         cpu.add_vx_nn(0, val);
@@ -483,7 +1211,7 @@ mod tests {
     }
     #[test]
     fn sets_vx_to_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x02;
         cpu.v_reg[1] = 0x03;
         cpu.set_vx_to_vy(0, 1);
@@ -491,7 +1219,7 @@ mod tests {
     }
     #[test]
     fn sets_vx_to_vx_or_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x02;
         cpu.v_reg[1] = 0x03;
         cpu.set_vx_to_vx_or_vy(0, 1);
@@ -499,7 +1227,7 @@ mod tests {
     }
     #[test]
     fn sets_vx_to_vx_and_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x02;
         cpu.v_reg[1] = 0x03;
         cpu.set_vx_to_vx_and_vy(0, 1);
@@ -507,7 +1235,7 @@ mod tests {
     }
     #[test]
     fn sets_vx_to_vx_xor_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x02;
         cpu.v_reg[1] = 0x03;
         cpu.set_vx_to_vx_xor_vy(0, 1);
@@ -515,7 +1243,7 @@ mod tests {
     }
     #[test]
     fn adds_vx_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x02;
         cpu.v_reg[1] = 0x03;
         cpu.add_vx_vy(0, 1);
@@ -529,7 +1257,7 @@ mod tests {
     }
     #[test]
     fn subs_vx_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x03;
         cpu.v_reg[1] = 0x02;
         cpu.sub_vx_vy(0, 1);
@@ -540,18 +1268,23 @@ mod tests {
         cpu.sub_vx_vy(0, 1);
         assert_eq!(cpu.v_reg[0], 0xFF);
         assert_eq!(cpu.v_reg[0xF], 0);
+        // No borrow when both are equal
+        cpu.v_reg[0xF] = 0x05;
+        cpu.v_reg[1] = 0x05;
+        cpu.sub_vx_vy(0xF, 1);
+        assert_eq!(cpu.v_reg[0xF], 1);
     }
     #[test]
     fn shifts_vx_right() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x03;
-        cpu.shift_vx_right(0);
+        cpu.shift_vx_right(0, 0);
         assert_eq!(cpu.v_reg[0], 1);
         assert_eq!(cpu.v_reg[0xF], 1);
     }
     #[test]
     fn subs_vy_vx() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x02;
         cpu.v_reg[1] = 0x04;
         cpu.sub_vy_vx(0, 1);
@@ -565,128 +1298,595 @@ mod tests {
     }
     #[test]
     fn shifts_vx_left() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x0F;
-        cpu.shift_vx_left(0);
+        cpu.shift_vx_left(0, 0);
         assert_eq!(cpu.v_reg[0], 0x1E);
         assert_eq!(cpu.v_reg[0xF], 0);
         cpu.v_reg[0] = 0xFF;
-        cpu.shift_vx_left(0);
+        cpu.shift_vx_left(0, 0);
         assert_eq!(cpu.v_reg[0xF], 1);
     }
     #[test]
     fn skips_if_vx_neq_vy() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let val = 0xCC;
         cpu.v_reg[0] = val;
         cpu.v_reg[1] = val + 1;
-        cpu.skip_if_vx_neq_vy(0, 1);
+        cpu.skip_if_vx_neq_vy(0, 1).unwrap();
         assert_eq!(cpu.prog_counter, 0x204);
     }
     #[test]
     fn sets_ind_reg_to_address() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         let address = 0x0ABC;
         cpu.set_ind_reg_to_address(address);
         assert_eq!(cpu.i_reg, address);
     }
     #[test]
     fn jumps_to_v0_plus_address() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0xFF;
         let address = 0xABC;
-        cpu.jump_to_v0_plus_address(address);
+        cpu.jump_to_v0_plus_address(0, address);
         assert_eq!(cpu.prog_counter, 0xFF + address);
     }
     #[test]
     fn sets_vx_to_rnd_and_nn() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.set_vx_to_rnd_and_nn(0, 0x0F);
         assert_eq!(cpu.v_reg[0] & 0xF0, 0);
+        // The same seed gives the same bytes
+        let rolls = |cpu: &mut CPU| {
+            cpu.random = Random::seeded(1234);
+            (0..4)
+                .map(|_| {
+                    cpu.set_vx_to_rnd_and_nn(0, 0xFF);
+                    cpu.v_reg[0]
+                })
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(rolls(&mut cpu), rolls(&mut CPU::new(&[]).unwrap()));
     }
     #[test]
-    #[ignore = "not yet implemented"]
     fn displays_sprite() {
-        todo!();
+        let mut cpu = CPU::new(&[]).unwrap();
+        // Font sprite for 0 is 0xF0, 0x90, 0x90, 0x90, 0xF0
+        cpu.i_reg = CPU::FONT_MEM_START as u16;
+        cpu.v_reg[0] = 1;
+        cpu.v_reg[1] = 2;
+        cpu.display_sprite(0, 1, 5).unwrap();
+        assert_eq!(cpu.display.get_pixel(1, 2), 1);
+        assert_eq!(cpu.display.get_pixel(2, 3), 0);
+        assert_eq!(cpu.display.get_pixel(4, 6), 1);
+        assert_eq!(cpu.v_reg[0xF], 0);
+        // Drawing the same sprite again erases it
+        cpu.display_sprite(0, 1, 5).unwrap();
+        assert!(cpu.display.pixels.iter().all(|pixel| *pixel == 0));
+    }
+    #[test]
+    fn presents_frames_to_frontend() {
+        let mut cpu = CPU::new(&read_test_opcode()).unwrap();
+        let mut frontend = Headless::new(Some(10));
+        cpu.run(&mut frontend).unwrap();
+        assert_eq!(frontend.frames_polled, 10);
+        // At most once per frame, no matter how many sprites were drawn
+        assert!(frontend.frames_presented > 0 && frontend.frames_presented <= 9);
+        assert_eq!(frontend.last_frame, cpu.display.pixels);
+    }
+    #[test]
+    fn runs_instructions_per_frame_and_ticks_timers() {
+        // 7005 repeated, adds 5 to V0 every instruction
+        let rom: Vec<u8> = [0x70, 0x05].repeat(64);
+        let mut cpu = CPU::new(&rom).unwrap();
+        cpu.instructions_per_frame = 4;
+        cpu.delay_reg = 10;
+        cpu.sound_reg = 1;
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.v_reg[0], 20);
+        assert_eq!(cpu.prog_counter, 0x208);
+        assert_eq!(cpu.delay_reg, 9);
+        assert_eq!(cpu.sound_reg, 0);
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.delay_reg, 8);
+        assert_eq!(cpu.sound_reg, 0);
+    }
+    #[test]
+    fn runs_ibm_logo_headless() {
+        let mut cpu = CPU::new(&read_test_opcode()).unwrap();
+        for _ in 0..100 {
+            cpu.step().unwrap();
+        }
+        // The logo ends in an infinite jump to itself
+        assert_eq!(cpu.prog_counter, 0x228);
+        assert!(cpu.display.pixels.contains(&1));
     }
     #[test]
-    #[ignore = "not yet implemented"]
     fn skips_if_key_eq_vx_pressed() {
-        todo!();
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.v_reg[0] = 0xA;
+        cpu.skip_if_key_eq_vx_pressed(0).unwrap();
+        assert_eq!(cpu.prog_counter, 0x202);
+        cpu.keypad.press(0xA);
+        cpu.skip_if_key_eq_vx_pressed(0).unwrap();
+        assert_eq!(cpu.prog_counter, 0x206);
     }
     #[test]
-    #[ignore = "not yet implemented"]
     fn skips_if_key_eq_vx_not_pressed() {
-        todo!();
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.v_reg[0] = 0xA;
+        cpu.keypad.press(0xA);
+        cpu.skip_if_key_eq_vx_not_pressed(0).unwrap();
+        assert_eq!(cpu.prog_counter, 0x202);
+        cpu.keypad.release(0xA);
+        cpu.skip_if_key_eq_vx_not_pressed(0).unwrap();
+        assert_eq!(cpu.prog_counter, 0x206);
     }
     #[test]
     fn sets_vx_to_delay_timer() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.delay_reg = 0x03;
         cpu.set_vx_to_delay_timer(0);
         assert_eq!(cpu.v_reg[0], cpu.delay_reg);
     }
     #[test]
-    #[ignore = "not yet implemented"]
     fn sets_vx_to_key_press() {
-        todo!();
+        let mut cpu = CPU::new(&[]).unwrap();
+        // Blocks while no key is pressed
+        cpu.set_vx_to_key_press(0);
+        assert_eq!(cpu.prog_counter, 0x200);
+        // Still blocks while the key is held down
+        cpu.keypad.press(0x7);
+        cpu.set_vx_to_key_press(0);
+        cpu.set_vx_to_key_press(0);
+        assert_eq!(cpu.prog_counter, 0x200);
+        // Completes once the key is released
+        cpu.keypad.release(0x7);
+        cpu.set_vx_to_key_press(0);
+        assert_eq!(cpu.v_reg[0], 0x7);
+        assert_eq!(cpu.prog_counter, 0x202);
     }
     #[test]
     fn sets_delay_timer_to_vx() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x03;
         cpu.set_delay_timer_to_vx(0);
         assert_eq!(cpu.v_reg[0], cpu.delay_reg);
     }
     #[test]
     fn sets_sound_timer_to_vx() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 0x03;
         cpu.set_sound_timer_to_vx(0);
         assert_eq!(cpu.v_reg[0], cpu.sound_reg);
     }
     #[test]
     fn adds_ind_reg_vx() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.i_reg = 0x02;
         cpu.v_reg[0] = 0x03;
         cpu.add_ind_reg_vx(0);
         assert_eq!(cpu.i_reg, 0x05);
     }
     #[test]
-    #[ignore = "not yet implemented"]
     fn sets_ind_reg_to_loc_of_sprite_for_digit_vx() {
-        todo!();
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.v_reg[0] = 0xA;
+        cpu.set_ind_reg_to_loc_of_sprite_for_digit_vx(0);
+        assert_eq!(cpu.i_reg, 0x050 + 0xA * 5);
+        assert_eq!(cpu.memory[cpu.i_reg as usize], 0xF0);
+        assert_eq!(cpu.memory[cpu.i_reg as usize + 4], 0x90);
     }
     #[test]
     fn stores_bcd_vx_in_ind_reg() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 143;
-        cpu.store_bcd_vx_in_ind_reg(0);
+        cpu.store_bcd_vx_in_ind_reg(0).unwrap();
         assert_eq!(cpu.memory[cpu.i_reg as usize], 1);
         assert_eq!(cpu.memory[(cpu.i_reg + 1) as usize], 4);
         assert_eq!(cpu.memory[(cpu.i_reg + 2) as usize], 3);
     }
     #[test]
     fn stores_v_reg_in_memory_from_ind_reg() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.v_reg[0] = 143;
         cpu.v_reg[1] = 255;
         cpu.v_reg[2] = 12;
-        cpu.store_v_reg_in_memory_from_ind_reg(2);
-        assert_eq!(cpu.memory[cpu.i_reg as usize], 143);
-        assert_eq!(cpu.memory[(cpu.i_reg + 1) as usize], 255);
-        assert_eq!(cpu.memory[(cpu.i_reg + 2) as usize], 12);
+        let start = cpu.i_reg as usize;
+        cpu.store_v_reg_in_memory_from_ind_reg(2).unwrap();
+        assert_eq!(cpu.memory[start], 143);
+        assert_eq!(cpu.memory[start + 1], 255);
+        assert_eq!(cpu.memory[start + 2], 12);
     }
     #[test]
     fn reads_v_reg_from_ind_reg() {
-        let mut cpu = CPU::new(&[]);
+        let mut cpu = CPU::new(&[]).unwrap();
         cpu.memory[cpu.i_reg as usize] = 143;
         cpu.memory[(cpu.i_reg + 1) as usize] = 255;
         cpu.memory[(cpu.i_reg + 2) as usize] = 3;
-        cpu.read_v_reg_from_ind_reg(2);
+        cpu.read_v_reg_from_ind_reg(2).unwrap();
         assert_eq!(cpu.v_reg[0], 143);
         assert_eq!(cpu.v_reg[1], 255);
         assert_eq!(cpu.v_reg[2], 3);
     }
+    #[test]
+    fn increments_ind_reg_after_load_store_per_quirk() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.i_reg = 0x300;
+        cpu.quirks.load_store_index = IndexIncrement::ByXPlusOne;
+        cpu.store_v_reg_in_memory_from_ind_reg(2).unwrap();
+        assert_eq!(cpu.i_reg, 0x303);
+        cpu.quirks.load_store_index = IndexIncrement::ByX;
+        cpu.read_v_reg_from_ind_reg(2).unwrap();
+        assert_eq!(cpu.i_reg, 0x305);
+        cpu.quirks.load_store_index = IndexIncrement::Unchanged;
+        cpu.read_v_reg_from_ind_reg(2).unwrap();
+        assert_eq!(cpu.i_reg, 0x305);
+    }
+    #[test]
+    fn shifts_vy_or_vx_per_quirk() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.v_reg[0] = 0x10;
+        cpu.v_reg[1] = 0x81;
+        cpu.quirks.shift_uses_vy = true;
+        cpu.shift_vx_right(0, 1);
+        assert_eq!(cpu.v_reg[0], 0x40);
+        assert_eq!(cpu.v_reg[0xF], 1);
+        cpu.quirks.shift_uses_vy = false;
+        cpu.shift_vx_left(0, 1);
+        assert_eq!(cpu.v_reg[0], 0x80);
+        assert_eq!(cpu.v_reg[0xF], 0);
+    }
+    #[test]
+    fn jumps_to_vx_plus_address_per_quirk() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.quirks = Quirks::superchip();
+        cpu.v_reg[0] = 0x01;
+        cpu.v_reg[3] = 0x10;
+        cpu.jump_to_v0_plus_address(3, 0x345);
+        assert_eq!(cpu.prog_counter, 0x355);
+    }
+    #[test]
+    fn resets_vf_after_logic_per_quirk() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.quirks.logic_resets_vf = true;
+        cpu.v_reg[0xF] = 1;
+        cpu.set_vx_to_vx_or_vy(0, 1);
+        assert_eq!(cpu.v_reg[0xF], 0);
+        cpu.quirks.logic_resets_vf = false;
+        cpu.v_reg[0xF] = 1;
+        cpu.set_vx_to_vx_xor_vy(0, 1);
+        assert_eq!(cpu.v_reg[0xF], 1);
+    }
+    #[test]
+    fn clips_or_wraps_sprites_per_quirk() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.i_reg = CPU::FONT_MEM_START as u16;
+        cpu.v_reg[0] = 62;
+        cpu.v_reg[1] = 30;
+        cpu.quirks.clip_sprites = true;
+        cpu.display_sprite(0, 1, 5).unwrap();
+        assert_eq!(cpu.display.get_pixel(63, 30), 1);
+        assert_eq!(cpu.display.get_pixel(62, 31), 1);
+        assert_eq!(cpu.display.get_pixel(0, 30), 0);
+        assert_eq!(cpu.display.get_pixel(1, 0), 0);
+        cpu.display.clear();
+        cpu.quirks.clip_sprites = false;
+        cpu.display_sprite(0, 1, 5).unwrap();
+        assert_eq!(cpu.display.get_pixel(63, 30), 1);
+        assert_eq!(cpu.display.get_pixel(0, 30), 1);
+        assert_eq!(cpu.display.get_pixel(1, 0), 1);
+        assert_eq!(cpu.display.get_pixel(1, 2), 1);
+    }
+    #[test]
+    fn sets_vf_on_any_collision() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        // Only the first pixel of the sprite overlaps
+        cpu.i_reg = CPU::FONT_MEM_START as u16;
+        cpu.display.set_pixel(0, 0, 1);
+        cpu.display_sprite(0, 0, 1).unwrap();
+        assert_eq!(cpu.v_reg[0xF], 1);
+    }
+    fn superchip() -> CPU {
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.mode = Mode::SuperChip;
+        cpu.quirks = Mode::SuperChip.default_quirks();
+        cpu
+    }
+    #[test]
+    fn ignores_superchip_opcodes_in_chip8_mode() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        assert_eq!(
+            cpu.run_instruction(0x00FF),
+            Err(EmulatorError::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x00FF
+            })
+        );
+        assert!(!cpu.display.is_hires());
+        assert_eq!(cpu.prog_counter, 0x200);
+    }
+    #[test]
+    fn applies_the_unknown_opcode_policy() {
+        let rom = [0x01, 0x23, 0x50, 0x01, 0x50, 0x01, 0x60, 0x07];
+        let mut cpu = CPU::new(&rom).unwrap();
+        cpu.opcode_policy = OpcodePolicy::Vip;
+        cpu.step().unwrap();
+        assert_eq!(cpu.prog_counter, 0x202);
+        assert_eq!(
+            cpu.step(),
+            Err(EmulatorError::UnknownOpcode {
+                pc: 0x202,
+                opcode: 0x5001
+            })
+        );
+        cpu.opcode_policy = OpcodePolicy::Skip;
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.v_reg[0], 7);
+        cpu.prog_counter = 0x202;
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.skipped_opcodes,
+            [
+                EmulatorError::UnknownOpcode {
+                    pc: 0x202,
+                    opcode: 0x5001
+                },
+                EmulatorError::UnknownOpcode {
+                    pc: 0x204,
+                    opcode: 0x5001
+                }
+            ]
+        );
+        cpu.opcode_policy = OpcodePolicy::Halt;
+        cpu.prog_counter = 0x200;
+        assert!(cpu.step().is_err());
+    }
+    #[test]
+    fn switches_resolution() {
+        let mut cpu = superchip();
+        cpu.run_instruction(0x00FF).unwrap();
+        assert!(cpu.display.is_hires());
+        assert_eq!(cpu.display.pixels.len(), 128 * 64);
+        cpu.run_instruction(0x00FE).unwrap();
+        assert!(!cpu.display.is_hires());
+        assert_eq!(cpu.prog_counter, 0x204);
+    }
+    #[test]
+    fn scrolls_display() {
+        let mut cpu = superchip();
+        cpu.display.set_pixel(8, 8, 1);
+        cpu.run_instruction(0x00C3).unwrap();
+        assert_eq!(cpu.display.get_pixel(8, 11), 1);
+        cpu.run_instruction(0x00FB).unwrap();
+        assert_eq!(cpu.display.get_pixel(12, 11), 1);
+        cpu.run_instruction(0x00FC).unwrap();
+        cpu.run_instruction(0x00FC).unwrap();
+        assert_eq!(cpu.display.get_pixel(4, 11), 1);
+        assert_eq!(
+            cpu.display
+                .pixels
+                .iter()
+                .filter(|pixel| **pixel == 1)
+                .count(),
+            1
+        );
+    }
+    #[test]
+    fn displays_16x16_sprite() {
+        let mut cpu = superchip();
+        cpu.run_instruction(0x00FF).unwrap();
+        cpu.i_reg = 0x300;
+        for ind in 0..32 {
+            cpu.memory[0x300 + ind] = 0xFF;
+        }
+        cpu.v_reg[0] = 100;
+        cpu.v_reg[1] = 40;
+        cpu.run_instruction(0xD010).unwrap();
+        assert_eq!(
+            cpu.display
+                .pixels
+                .iter()
+                .filter(|pixel| **pixel == 1)
+                .count(),
+            256
+        );
+        assert_eq!(cpu.display.get_pixel(115, 55), 1);
+        assert_eq!(cpu.display.get_pixel(116, 56), 0);
+    }
+    #[test]
+    fn sets_ind_reg_to_big_digit() {
+        let mut cpu = superchip();
+        cpu.v_reg[2] = 3;
+        cpu.run_instruction(0xF230).unwrap();
+        assert_eq!(cpu.i_reg, 0x0A0 + 30);
+        assert_eq!(
+            cpu.memory[cpu.i_reg as usize..cpu.i_reg as usize + 10],
+            BIG_FONTS[30..40]
+        );
+    }
+    #[test]
+    fn saves_and_loads_rpl_flags() {
+        let mut cpu = superchip();
+        cpu.v_reg[0] = 1;
+        cpu.v_reg[1] = 2;
+        cpu.v_reg[2] = 3;
+        cpu.run_instruction(0xF175).unwrap();
+        cpu.v_reg = [0; 16];
+        cpu.run_instruction(0xF285).unwrap();
+        assert_eq!(cpu.v_reg[..3], [1, 2, 0]);
+    }
+    #[test]
+    fn sizes_memory_for_mode() {
+        assert_eq!(CPU::new(&[]).unwrap().memory.len(), 4096);
+        let cpu = CPU::with_mode(&[], Mode::XoChip).unwrap();
+        assert_eq!(cpu.memory.len(), 65536);
+        assert_eq!(cpu.quirks, Quirks::modern());
+    }
+    #[test]
+    fn rejects_roms_that_dont_fit() {
+        assert!(CPU::new(&[0; 4096 - 0x200]).is_ok());
+        assert_eq!(
+            CPU::new(&[0; 4096 - 0x1FF]).unwrap_err(),
+            EmulatorError::RomTooLarge {
+                size: 4096 - 0x1FF,
+                max: 4096 - 0x200
+            }
+        );
+        assert!(CPU::with_mode(&[0; 4096], Mode::XoChip).is_ok());
+    }
+    #[test]
+    fn reports_memory_access_past_the_end() {
+        let mut cpu = CPU::new(&[0xF3, 0x55, 0xF0, 0x33]).unwrap();
+        cpu.i_reg = 0xFFE;
+        assert_eq!(
+            cpu.step(),
+            Err(EmulatorError::MemoryOutOfBounds {
+                pc: 0x200,
+                address: 0x1000
+            })
+        );
+        assert_eq!(cpu.memory[0xFFE..], [0, 0]);
+        cpu.prog_counter = 0x202;
+        assert!(cpu.step().is_err());
+        cpu.i_reg = 0xFFD;
+        cpu.step().unwrap();
+        cpu.prog_counter = 0xFFF;
+        assert!(matches!(
+            cpu.step(),
+            Err(EmulatorError::MemoryOutOfBounds { pc: 0xFFF, .. })
+        ));
+    }
+    #[test]
+    fn stops_the_pc_at_the_end_of_memory() {
+        let mut cpu = CPU::with_mode(&[], Mode::XoChip).unwrap();
+        cpu.memory[0xFFFE..].copy_from_slice(&[0x60, 0x05]);
+        cpu.prog_counter = 0xFFFE;
+        assert_eq!(
+            cpu.step(),
+            Err(EmulatorError::MemoryOutOfBounds {
+                pc: 0xFFFE,
+                address: 0x10000
+            })
+        );
+        assert_eq!(cpu.v_reg[0], 0);
+        assert_eq!(cpu.prog_counter, 0xFFFE);
+        // A skip fails when it would land past the end, jumps still work
+        cpu.memory[0xFFFC..].copy_from_slice(&[0x30, 0x00, 0x12, 0x00]);
+        cpu.prog_counter = 0xFFFC;
+        assert_eq!(
+            cpu.step(),
+            Err(EmulatorError::MemoryOutOfBounds {
+                pc: 0xFFFC,
+                address: 0x10000
+            })
+        );
+        cpu.v_reg[0] = 1;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.prog_counter, 0x200);
+    }
+    #[test]
+    fn fails_sprites_and_ranges_past_the_end_of_memory() {
+        let mut cpu = CPU::new(&[]).unwrap();
+        cpu.i_reg = 0xFFF;
+        let error = Err(EmulatorError::MemoryOutOfBounds {
+            pc: 0x200,
+            address: 0x1000,
+        });
+        assert_eq!(cpu.run_instruction(0xD005), error);
+        assert!(cpu.display.pixels.iter().all(|&pixel| pixel == 0));
+        let mut cpu = CPU::with_mode(&[], Mode::XoChip).unwrap();
+        cpu.i_reg = 0xFFFF;
+        let error = Err(EmulatorError::MemoryOutOfBounds {
+            pc: 0x200,
+            address: 0x10000,
+        });
+        assert_eq!(cpu.run_instruction(0x5012), error);
+        assert_eq!(cpu.run_instruction(0x5013), error);
+        assert_eq!(cpu.run_instruction(0xF002), error);
+        assert_eq!(cpu.prog_counter, 0x200);
+    }
+    #[test]
+    fn loads_long_ind_reg() {
+        let mut cpu = CPU::with_mode(&[0xF0, 0x00, 0xBE, 0xEF], Mode::XoChip).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.i_reg, 0xBEEF);
+        assert_eq!(cpu.prog_counter, 0x204);
+    }
+    #[test]
+    fn skips_over_long_ind_reg_load() {
+        let mut cpu = CPU::with_mode(&[0x30, 0x00, 0xF0, 0x00, 0xBE, 0xEF], Mode::XoChip).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.prog_counter, 0x206);
+    }
+    #[test]
+    fn saves_and_loads_register_range() {
+        let mut cpu = CPU::with_mode(&[], Mode::XoChip).unwrap();
+        cpu.i_reg = 0x400;
+        cpu.v_reg[2] = 7;
+        cpu.v_reg[3] = 8;
+        cpu.v_reg[4] = 9;
+        cpu.run_instruction(0x5242).unwrap();
+        assert_eq!(cpu.memory[0x400..0x403], [7, 8, 9]);
+        assert_eq!(cpu.i_reg, 0x400);
+        // Reversed range loads in reverse order
+        cpu.run_instruction(0x5753).unwrap();
+        assert_eq!(cpu.v_reg[5..8], [9, 8, 7]);
+    }
+    #[test]
+    fn draws_on_selected_planes() {
+        let mut cpu = CPU::with_mode(&[], Mode::XoChip).unwrap();
+        cpu.i_reg = 0x300;
+        // Plane 1 row followed by plane 2 row
+        cpu.memory[0x300] = 0b1000_0000;
+        cpu.memory[0x301] = 0b1100_0000;
+        cpu.run_instruction(0xF301).unwrap();
+        cpu.run_instruction(0xD011).unwrap();
+        assert_eq!(cpu.display.get_pixel(0, 0), 0b11);
+        assert_eq!(cpu.display.get_pixel(1, 0), 0b10);
+        assert_eq!(cpu.v_reg[0xF], 0);
+        // Only the second plane collides
+        cpu.run_instruction(0xF201).unwrap();
+        cpu.i_reg = 0x301;
+        cpu.run_instruction(0xD011).unwrap();
+        assert_eq!(cpu.display.get_pixel(0, 0), 0b01);
+        assert_eq!(cpu.display.get_pixel(1, 0), 0);
+        assert_eq!(cpu.v_reg[0xF], 1);
+    }
+    #[test]
+    fn scrolls_display_up() {
+        let mut cpu = CPU::with_mode(&[], Mode::XoChip).unwrap();
+        cpu.display.set_pixel(3, 10, 1);
+        cpu.run_instruction(0x00D4).unwrap();
+        assert_eq!(cpu.display.get_pixel(3, 6), 1);
+    }
+    #[test]
+    fn loads_audio_pattern_and_pitch() {
+        let mut cpu = CPU::with_mode(&[], Mode::XoChip).unwrap();
+        assert_eq!(cpu.audio_pattern(), None);
+        cpu.i_reg = 0x300;
+        for ind in 0..16 {
+            cpu.memory[0x300 + ind] = ind as u8;
+        }
+        cpu.v_reg[1] = 100;
+        cpu.run_instruction(0xF002).unwrap();
+        cpu.run_instruction(0xF13A).unwrap();
+        let pattern = cpu.audio_pattern().unwrap();
+        assert_eq!(pattern.pattern[15], 15);
+        assert_eq!(pattern.pitch, 100);
+    }
+    #[test]
+    fn exits_interpreter() {
+        let mut cpu = superchip();
+        cpu.run_instruction(0x00FD).unwrap();
+        assert!(cpu.halted);
+        cpu.memory[0x200] = 0x60;
+        cpu.memory[0x201] = 0x05;
+        cpu.step().unwrap();
+        assert_eq!(cpu.v_reg[0], 0);
+        assert_eq!(cpu.prog_counter, 0x200);
+    }
 }
@@ -0,0 +1,957 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use super::asm::{Monitor, Program};
+use super::cpu::{MemoryAccess, CPU};
+use super::disasm::{self, Syntax};
+use super::frontend::Frontend;
+use super::gdb::GdbStub;
+use super::instruction::Instruction;
+
+// Instructions shown on either side of the program counter
+const DISASSEMBLY_CONTEXT: u16 = 5;
+
+pub const HELP: &str = "Debugger commands, addresses are hex:
+    c, continue            Resume execution
+    p, pause               Pause execution
+    s, step                Execute one instruction
+    n, next                Step over 2NNN calls
+    o, out                 Run until the current subroutine returns
+    b <addr> [if <cond>]   Set a breakpoint, conditions look like v3 == 5
+    d <addr>               Delete a breakpoint
+    w r|w|x <addr>[-<end>] Stop on reads, writes or execution in a range
+    w <register>           Stop when v0-vf or i changes
+    w sprite <addr>        Stop when a sprite is drawn from the address
+    u <watch>              Remove a watch, same arguments as w
+    l, list                List breakpoints and watches
+    r, regs                Show registers, stack, timers and disassembly
+    h, help                Show this help
+    q, quit                Quit the emulator
+In the window F5 pauses and resumes, F11 steps, F10 steps over and Shift+F11
+steps out.";
+
+// Machine state a breakpoint condition can test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    Delay,
+    Sound,
+}
+
+impl Register {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "i" => Some(Register::I),
+            "dt" => Some(Register::Delay),
+            "st" => Some(Register::Sound),
+            name => {
+                let digit = name.strip_prefix('v')?;
+                match u8::from_str_radix(digit, 16) {
+                    Ok(x) if digit.len() == 1 => Some(Register::V(x)),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    pub fn read(self, cpu: &CPU) -> u16 {
+        match self {
+            Register::V(x) => cpu.v_reg[x as usize] as u16,
+            Register::I => cpu.i_reg,
+            Register::Delay => cpu.delay_reg as u16,
+            Register::Sound => cpu.sound_reg as u16,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "v{:x}", x),
+            Register::I => write!(f, "i"),
+            Register::Delay => write!(f, "dt"),
+            Register::Sound => write!(f, "st"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Eq),
+    ("!=", Comparison::Ne),
+    ("<", Comparison::Lt),
+    ("<=", Comparison::Le),
+    (">", Comparison::Gt),
+    (">=", Comparison::Ge),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    // Parses conditions such as `v3 == 5` or `i >= 0x300`
+    pub fn parse(words: &[&str]) -> Result<Self, String> {
+        let (register, operator, value) = match words {
+            [register, operator, value] => (register, operator, value),
+            _ => return Err("conditions look like v3 == 5".to_string()),
+        };
+        let register = Register::from_name(register)
+            .ok_or_else(|| format!("{} isn't v0-vf, i, dt or st", register))?;
+        let comparison = COMPARISONS
+            .iter()
+            .find(|(name, _)| name == operator)
+            .map(|(_, comparison)| *comparison)
+            .ok_or_else(|| format!("{} isn't one of ==, !=, <, <=, >, >=", operator))?;
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| format!("{} isn't a number", value))?;
+        Ok(Condition {
+            register,
+            comparison,
+            value,
+        })
+    }
+
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        let current = self.register.read(cpu);
+        match self.comparison {
+            Comparison::Eq => current == self.value,
+            Comparison::Ne => current != self.value,
+            Comparison::Lt => current < self.value,
+            Comparison::Le => current <= self.value,
+            Comparison::Gt => current > self.value,
+            Comparison::Ge => current >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operator = COMPARISONS
+            .iter()
+            .find(|(_, comparison)| *comparison == self.comparison)
+            .map_or("?", |(name, _)| name);
+        write!(f, "{} {} {}", self.register, operator, self.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+// Stops execution when something happens instead of at an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    // Memory from start to end inclusive
+    Memory {
+        access: Access,
+        start: u16,
+        end: u16,
+    },
+    // Any change to a V register or I
+    Register(Register),
+    // DXYN drawing a sprite that starts at the address
+    Sprite(u16),
+}
+
+impl Watch {
+    // Parses the arguments of `w` and `u`, such as `w 300-30f` or `v3`
+    pub fn parse(words: &[&str]) -> Result<Self, String> {
+        let access = match words.first() {
+            Some(&"r") => Access::Read,
+            Some(&"w") => Access::Write,
+            Some(&"x") => Access::Execute,
+            Some(&"sprite") if words.len() == 2 => {
+                return Ok(Watch::Sprite(parse_address(words.get(1))?));
+            }
+            Some(name) if words.len() == 1 => {
+                return match Register::from_name(name) {
+                    Some(Register::Delay) | Some(Register::Sound) | None => {
+                        Err(format!("{} isn't v0-vf or i", name))
+                    }
+                    Some(register) => Ok(Watch::Register(register)),
+                };
+            }
+            _ => return Err("watches look like w 300-30f, v3 or sprite 300".to_string()),
+        };
+        if words.len() != 2 {
+            return Err("memory watches take a single address range".to_string());
+        }
+        let (start, end) = match words[1].split_once('-') {
+            Some((start, end)) => (parse_address(Some(&start))?, parse_address(Some(&end))?),
+            None => {
+                let address = parse_address(words.get(1))?;
+                (address, address)
+            }
+        };
+        if end < start {
+            return Err(format!("{} ends before it starts", words[1]));
+        }
+        Ok(Watch::Memory { access, start, end })
+    }
+
+    fn overlaps(start: u16, end: u16, address: u16, length: u16) -> bool {
+        let (start, end, address) = (start as u32, end as u32, address as u32);
+        length > 0 && address <= end && address + length as u32 > start
+    }
+
+    // Whether an instruction's memory accesses set off the watch
+    fn hit_by(&self, accesses: &[MemoryAccess]) -> bool {
+        accesses.iter().any(|access| match (*self, *access) {
+            (
+                Watch::Memory {
+                    access: Access::Read,
+                    start,
+                    end,
+                },
+                MemoryAccess::Read { address, length },
+            )
+            | (
+                Watch::Memory {
+                    access: Access::Write,
+                    start,
+                    end,
+                },
+                MemoryAccess::Write { address, length },
+            ) => Watch::overlaps(start, end, address, length),
+            (Watch::Sprite(watched), MemoryAccess::Sprite { address }) => watched == address,
+            _ => false,
+        })
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watch::Memory { access, start, end } => {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                    Access::Execute => "execute",
+                };
+                if start == end {
+                    write!(f, "{} {:04X}", access, start)
+                } else {
+                    write!(f, "{} {:04X}-{:04X}", access, start, end)
+                }
+            }
+            Watch::Register(register) => write!(f, "{} changes", register),
+            Watch::Sprite(address) => write!(f, "sprite drawn from {:04X}", address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    Pause,
+    // Continue when paused and pause otherwise, for single key frontends
+    TogglePause,
+    Step,
+    StepOver,
+    StepOut,
+    Break {
+        address: u16,
+        condition: Option<Condition>,
+    },
+    Delete {
+        address: u16,
+    },
+    Watch(Watch),
+    Unwatch(Watch),
+    List,
+    Dump,
+    Help,
+    Quit,
+}
+
+fn parse_address(text: Option<&&str>) -> Result<u16, String> {
+    let text = text.ok_or_else(|| "expected an address".to_string())?;
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} isn't a hex address", text))
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.first().copied().unwrap_or("") {
+            "c" | "continue" => Command::Continue,
+            "p" | "pause" => Command::Pause,
+            "s" | "step" => Command::Step,
+            "n" | "next" => Command::StepOver,
+            "o" | "out" => Command::StepOut,
+            "b" | "break" => {
+                let address = parse_address(words.get(1))?;
+                let condition = match words.get(2) {
+                    None => None,
+                    Some(&"if") => Some(Condition::parse(&words[3..])?),
+                    Some(word) => return Err(format!("expected if, found {}", word)),
+                };
+                return Ok(Command::Break { address, condition });
+            }
+            "d" | "delete" => {
+                let address = parse_address(words.get(1))?;
+                return match words.len() {
+                    2 => Ok(Command::Delete { address }),
+                    _ => Err("d takes a single address".to_string()),
+                };
+            }
+            "w" | "watch" => return Ok(Command::Watch(Watch::parse(&words[1..])?)),
+            "u" | "unwatch" => return Ok(Command::Unwatch(Watch::parse(&words[1..])?)),
+            "l" | "list" => Command::List,
+            "r" | "regs" => Command::Dump,
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            word => return Err(format!("unknown command {}, h shows help", word)),
+        };
+        match words.len() {
+            1 => Ok(command),
+            _ => Err(format!("{} takes no arguments", words[0])),
+        }
+    }
+}
+
+// Where execution stops again after stepping over or out of a call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunUntil {
+    // The instruction after a call, once the call has returned
+    Return { address: u16, depth: u8 },
+    // Anywhere after the current subroutine returned
+    StackBelow(u8),
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub paused: bool,
+    // Breakpoints on the program counter, which only stop when their
+    // condition holds
+    pub breakpoints: BTreeMap<u16, Option<Condition>>,
+    // Names of the breakpoints set by `:breakpoint` in Octo sources
+    pub names: BTreeMap<u16, String>,
+    pub monitors: Vec<Monitor>,
+    pub watches: Vec<Watch>,
+    run_until: Option<RunUntil>,
+    // The next instruction runs without checking breakpoints, so resuming
+    // from one doesn't stop straight away
+    resuming: bool,
+    // Lines typed on the terminal, None when there is no console
+    console: Option<Receiver<String>>,
+    // Remote GDB session driving the debugger
+    gdb: Option<GdbStub>,
+    // Why execution last stopped, shown above the overlay
+    status: String,
+    // Skipped opcodes already printed
+    skips_reported: usize,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    // Reads commands from stdin on a separate thread so the emulator keeps
+    // running while waiting for input
+    pub fn attach_console(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        self.console = Some(receiver);
+    }
+
+    pub fn attach_gdb(&mut self, gdb: GdbStub) {
+        self.gdb = Some(gdb);
+    }
+
+    // Picks up the `:breakpoint` and `:monitor` markers of a compiled program
+    pub fn load_markers(&mut self, program: &Program) {
+        for (address, name) in program.breakpoints.iter() {
+            self.breakpoints.insert(*address, None);
+            self.names.insert(*address, name.clone());
+        }
+        self.monitors.extend(program.monitors.iter().cloned());
+    }
+
+    fn print(&self, lines: &[String]) {
+        if self.console.is_some() {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+    }
+
+    fn console_commands(&mut self) -> Vec<Command> {
+        let mut commands = Vec::new();
+        let mut errors = Vec::new();
+        if let Some(console) = &self.console {
+            loop {
+                match console.try_recv() {
+                    Ok(line) => match Command::parse(&line) {
+                        Ok(command) => commands.push(command),
+                        Err(e) => errors.push(e),
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    // Stdin was closed, keep running without a console
+                    Err(TryRecvError::Disconnected) => {
+                        self.console = None;
+                        break;
+                    }
+                }
+            }
+        }
+        self.print(&errors);
+        commands
+    }
+
+    fn breakpoint_name(&self, address: u16) -> String {
+        match self.names.get(&address) {
+            Some(name) => format!("{} ({:04X})", name, address),
+            None => format!("{:04X}", address),
+        }
+    }
+
+    // Why execution should stop before the instruction at the program counter
+    fn should_break(&self, cpu: &CPU) -> Option<String> {
+        let pc = cpu.prog_counter;
+        if let Some(condition) = self.breakpoints.get(&pc) {
+            match condition {
+                Some(condition) if condition.holds(cpu) => {
+                    return Some(format!(
+                        "breakpoint {} hit, {}",
+                        self.breakpoint_name(pc),
+                        condition
+                    ));
+                }
+                None => return Some(format!("breakpoint {} hit", self.breakpoint_name(pc))),
+                _ => {}
+            }
+        }
+        let executed = self.watches.iter().find(|watch| match watch {
+            Watch::Memory {
+                access: Access::Execute,
+                start,
+                end,
+            } => (*start..=*end).contains(&pc),
+            _ => false,
+        });
+        if let Some(watch) = executed {
+            return Some(format!("watch {} hit at {:04X}", watch, pc));
+        }
+        match self.run_until {
+            Some(RunUntil::Return { address, depth })
+                if pc == address && cpu.stack_ptr == depth =>
+            {
+                Some("stepped over call".to_string())
+            }
+            Some(RunUntil::StackBelow(depth)) if cpu.stack_ptr < depth => {
+                Some("stepped out of subroutine".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    // Executes one instruction and reports the first watch it set off, or
+    // why the instruction failed
+    fn step_watched(&mut self, cpu: &mut CPU) -> Option<String> {
+        if self.watches.is_empty() {
+            return cpu.step().err().map(|e| e.to_string());
+        }
+        let pc = cpu.prog_counter;
+        let before: Vec<u16> = self
+            .watches
+            .iter()
+            .map(|watch| match watch {
+                Watch::Register(register) => register.read(cpu),
+                _ => 0,
+            })
+            .collect();
+        cpu.access_log = Some(Vec::new());
+        let result = cpu.step();
+        let accesses = cpu.access_log.take().unwrap_or_default();
+        if let Err(e) = result {
+            return Some(e.to_string());
+        }
+        self.watches
+            .iter()
+            .zip(before)
+            .find_map(|(watch, before)| match watch {
+                Watch::Register(register) if register.read(cpu) != before => Some(format!(
+                    "{} changed from {:02X} to {:02X} at {:04X}",
+                    register,
+                    before,
+                    register.read(cpu),
+                    pc
+                )),
+                _ if watch.hit_by(&accesses) => Some(format!("watch {} hit at {:04X}", watch, pc)),
+                _ => None,
+            })
+    }
+
+    fn stop(&mut self, reason: String) -> String {
+        self.paused = true;
+        self.run_until = None;
+        self.status = reason.clone();
+        reason
+    }
+
+    // Runs up to `count` instructions, stopping and pausing before one that
+    // hits a breakpoint, or after one that sets off a watch or fails. Returns
+    // why it stopped.
+    pub fn run_instructions(&mut self, cpu: &mut CPU, count: u32) -> Option<String> {
+        for _ in 0..count {
+            if !self.resuming {
+                if let Some(reason) = self.should_break(cpu) {
+                    return Some(self.stop(reason));
+                }
+            }
+            self.resuming = false;
+            if let Some(reason) = self.step_watched(cpu) {
+                return Some(self.stop(reason));
+            }
+        }
+        None
+    }
+
+    fn resume(&mut self, cpu: &mut CPU) -> Vec<String> {
+        self.paused = false;
+        self.resuming = true;
+        // Redraw the plain framebuffer over the overlay
+        cpu.display.dirty = true;
+        vec!["running".to_string()]
+    }
+
+    // Applies a command and returns its output. Quitting is up to the caller.
+    pub fn execute(&mut self, command: Command, cpu: &mut CPU) -> Vec<String> {
+        match command {
+            Command::Continue => {
+                self.run_until = None;
+                self.resume(cpu)
+            }
+            Command::Pause => {
+                self.paused = true;
+                self.run_until = None;
+                self.status = "paused".to_string();
+                self.dump(cpu)
+            }
+            Command::TogglePause if self.paused => self.execute(Command::Continue, cpu),
+            Command::TogglePause => self.execute(Command::Pause, cpu),
+            Command::Step => {
+                let reason = self.step_watched(cpu);
+                self.stop(reason.unwrap_or_else(|| "stepped".to_string()));
+                self.dump(cpu)
+            }
+            Command::StepOver => match Instruction::decode(cpu.read_word(cpu.prog_counter)) {
+                Ok(Instruction::Call { .. }) => {
+                    self.run_until = Some(RunUntil::Return {
+                        address: cpu.prog_counter.wrapping_add(2),
+                        depth: cpu.stack_ptr,
+                    });
+                    self.resume(cpu)
+                }
+                _ => self.execute(Command::Step, cpu),
+            },
+            Command::StepOut if cpu.stack_ptr == 0 => {
+                vec!["not inside a subroutine".to_string()]
+            }
+            Command::StepOut => {
+                self.run_until = Some(RunUntil::StackBelow(cpu.stack_ptr));
+                self.resume(cpu)
+            }
+            Command::Break { address, condition } => {
+                self.breakpoints.insert(address, condition);
+                match condition {
+                    Some(condition) => {
+                        vec![format!("breakpoint at {:04X} if {}", address, condition)]
+                    }
+                    None => vec![format!("breakpoint at {:04X}", address)],
+                }
+            }
+            Command::Delete { address } => match self.breakpoints.remove(&address) {
+                Some(_) => {
+                    self.names.remove(&address);
+                    vec![format!("deleted breakpoint at {:04X}", address)]
+                }
+                None => vec![format!("no breakpoint at {:04X}", address)],
+            },
+            Command::Watch(watch) => {
+                if !self.watches.contains(&watch) {
+                    self.watches.push(watch);
+                }
+                vec![format!("watching {}", watch)]
+            }
+            Command::Unwatch(watch) => match self.watches.iter().position(|w| *w == watch) {
+                Some(ind) => {
+                    self.watches.remove(ind);
+                    vec![format!("stopped watching {}", watch)]
+                }
+                None => vec![format!("not watching {}", watch)],
+            },
+            Command::List if self.breakpoints.is_empty() && self.watches.is_empty() => {
+                vec!["no breakpoints or watches".to_string()]
+            }
+            Command::List => self
+                .breakpoints
+                .iter()
+                .map(|(address, condition)| match condition {
+                    Some(condition) => {
+                        format!("{} if {}", self.breakpoint_name(*address), condition)
+                    }
+                    None => self.breakpoint_name(*address),
+                })
+                .chain(self.watches.iter().map(|watch| format!("watch {}", watch)))
+                .collect(),
+            Command::Dump => self.dump(cpu),
+            Command::Help => HELP.lines().map(str::to_string).collect(),
+            Command::Quit => Vec::new(),
+        }
+    }
+
+    // Registers, stack, timers, monitored memory and the instructions around
+    // the program counter
+    pub fn dump(&self, cpu: &CPU) -> Vec<String> {
+        let mut lines = vec![format!(
+            "PC {:04X}  I {:04X}  SP {:X}  DT {:02X}  ST {:02X}",
+            cpu.prog_counter, cpu.i_reg, cpu.stack_ptr, cpu.delay_reg, cpu.sound_reg
+        )];
+        for row in cpu.v_reg.chunks(8).enumerate() {
+            let (row, values) = row;
+            lines.push(
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(ind, value)| format!("V{:X} {:02X}", row * 8 + ind, value))
+                    .collect::<Vec<_>>()
+                    .join("  "),
+            );
+        }
+        let stack: Vec<String> = cpu.stack[..cpu.stack_ptr as usize]
+            .iter()
+            .map(|address| format!("{:04X}", address))
+            .collect();
+        lines.push(format!("stack {}", stack.join(" ")));
+        for monitor in self.monitors.iter() {
+            let start = monitor.address as usize;
+            let end = (start + monitor.length as usize).min(cpu.memory.len());
+            let bytes: Vec<String> = cpu.memory[start.min(end)..end]
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            lines.push(format!("{} {}", monitor.name, bytes.join(" ")));
+        }
+        lines.push(String::new());
+        lines.extend(self.disassembly_window(cpu));
+        lines
+    }
+
+    fn disassembly_window(&self, cpu: &CPU) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut address = cpu.prog_counter.saturating_sub(DISASSEMBLY_CONTEXT * 2);
+        while lines.len() < (DISASSEMBLY_CONTEXT * 2 + 1) as usize
+            && (address as usize) + 1 < cpu.memory.len()
+        {
+            let opcode = cpu.read_word(address);
+            let (text, size) = match Instruction::decode(opcode) {
+                Ok(instruction) if instruction.required_mode() <= cpu.mode => {
+                    let long_operand = cpu.read_word(address.wrapping_add(2));
+                    let text = disasm::format_instruction(
+                        &instruction,
+                        long_operand,
+                        Syntax::Octo,
+                        &self.names,
+                    );
+                    (text, instruction.size())
+                }
+                _ => (format!("0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF), 2),
+            };
+            let pc_marker = if address == cpu.prog_counter {
+                '>'
+            } else {
+                ' '
+            };
+            let break_marker = if self.breakpoints.contains_key(&address) {
+                '*'
+            } else {
+                ' '
+            };
+            lines.push(format!(
+                "{}{} {:04X}  {:04X}  {}",
+                pc_marker, break_marker, address, opcode, text
+            ));
+            address = address.wrapping_add(size);
+        }
+        lines
+    }
+
+    // One frame of the run loop: applies pending commands, then runs the
+    // frame's instructions and ticks the timers unless paused. Returns true
+    // when the user asked to quit.
+    pub fn run_frame(&mut self, cpu: &mut CPU, frontend: &mut dyn Frontend) -> bool {
+        if let Some(mut gdb) = self.gdb.take() {
+            match gdb.poll(cpu, self) {
+                Ok(true) => return true,
+                Ok(false) if gdb.attached => self.gdb = Some(gdb),
+                Ok(false) => println!("GDB detached"),
+                Err(e) => eprintln!("GDB connection failed: {}", e),
+            }
+        }
+        let mut commands = frontend.debug_commands();
+        commands.extend(self.console_commands());
+        let mut changed = false;
+        for command in commands {
+            if command == Command::Quit {
+                return true;
+            }
+            let output = self.execute(command, cpu);
+            self.print(&output);
+            changed = true;
+        }
+        if !self.paused {
+            if let Some(reason) = self.run_instructions(cpu, cpu.instructions_per_frame) {
+                self.print(&[reason]);
+                self.print(&self.dump(cpu));
+                changed = true;
+            }
+            let skipped: Vec<String> = cpu.skipped_opcodes[self.skips_reported..]
+                .iter()
+                .map(|error| format!("skipped {}", error))
+                .collect();
+            self.print(&skipped);
+            self.skips_reported = cpu.skipped_opcodes.len();
+            cpu.tick_timers();
+        }
+        if self.paused && changed {
+            let mut lines = vec![self.status.clone()];
+            lines.extend(self.dump(cpu));
+            frontend.present_overlay(&cpu.display, &lines);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::asm::{assemble, compile};
+    use crate::chip8::cpu::Mode;
+    const PROGRAM: &str = "
+        : main
+            v0 := 1
+            add-two
+            v1 := 5
+        : spin
+            jump spin
+        : add-two
+            v0 += 1
+            v0 += 1
+            return
+    ";
+    #[test]
+    fn stops_before_breakpoints_and_resumes() {
+        let mut cpu = CPU::new(&assemble(PROGRAM).unwrap()).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute(
+            Command::Break {
+                address: 0x204,
+                condition: None,
+            },
+            &mut cpu,
+        );
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("breakpoint 0204 hit"));
+        assert!(debugger.paused);
+        assert_eq!(cpu.prog_counter, 0x204);
+        assert_eq!(cpu.v_reg[1], 0);
+        debugger.execute(Command::Continue, &mut cpu);
+        assert_eq!(debugger.run_instructions(&mut cpu, 10), None);
+        assert_eq!(cpu.v_reg[1], 5);
+    }
+    #[test]
+    fn only_stops_when_the_condition_holds() {
+        let mut cpu = CPU::new(&assemble(PROGRAM).unwrap()).unwrap();
+        let mut debugger = Debugger::new();
+        let command = Command::parse("b 20c if v0 == 3").unwrap();
+        debugger.execute(command, &mut cpu);
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("breakpoint 020C hit, v0 == 3"));
+        assert_eq!(cpu.v_reg[0], 3);
+        let command = Command::parse("b 0x20C if v0 == 9").unwrap();
+        debugger.execute(command, &mut cpu);
+        debugger.execute(Command::Continue, &mut cpu);
+        assert_eq!(debugger.run_instructions(&mut cpu, 100), None);
+    }
+    #[test]
+    fn steps_over_and_out_of_calls() {
+        let mut cpu = CPU::new(&assemble(PROGRAM).unwrap()).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute(Command::Step, &mut cpu);
+        assert_eq!(cpu.prog_counter, 0x202);
+        debugger.execute(Command::StepOver, &mut cpu);
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("stepped over call"));
+        assert_eq!(cpu.prog_counter, 0x204);
+        assert_eq!(cpu.v_reg[0], 3);
+
+        let mut cpu = CPU::new(&assemble(PROGRAM).unwrap()).unwrap();
+        debugger.execute(Command::Step, &mut cpu);
+        debugger.execute(Command::Step, &mut cpu);
+        assert_eq!(cpu.prog_counter, 0x208);
+        debugger.execute(Command::Step, &mut cpu);
+        debugger.execute(Command::StepOut, &mut cpu);
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("stepped out of subroutine"));
+        assert_eq!(cpu.prog_counter, 0x204);
+        assert_eq!(
+            debugger.execute(Command::StepOut, &mut cpu),
+            vec!["not inside a subroutine"]
+        );
+    }
+    #[test]
+    fn stops_after_watched_memory_accesses() {
+        let source = "
+            : main
+                i := 0x300
+                v0 := 7
+                save v1
+                load v0
+                i := digits
+                sprite v0 v0 5
+            : spin
+                jump spin
+            : digits
+                0xF0 0x90 0x90 0x90 0xF0
+        ";
+        let mut cpu = CPU::new(&assemble(source).unwrap()).unwrap();
+        let mut debugger = Debugger::new();
+        for line in ["w w 301-3ff", "w r 302", "w sprite 20e", "w x 20c"].iter() {
+            debugger.execute(Command::parse(line).unwrap(), &mut cpu);
+        }
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("watch write 0301-03FF hit at 0204"));
+        assert_eq!(cpu.prog_counter, 0x206);
+        debugger.execute(Command::Continue, &mut cpu);
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("watch read 0302 hit at 0206"));
+        debugger.execute(Command::Continue, &mut cpu);
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(
+            reason.as_deref(),
+            Some("watch sprite drawn from 020E hit at 020A")
+        );
+        debugger.execute(Command::Continue, &mut cpu);
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("watch execute 020C hit at 020C"));
+        assert_eq!(cpu.prog_counter, 0x20C);
+    }
+    #[test]
+    fn stops_when_watched_registers_change() {
+        let mut cpu = CPU::new(&assemble(PROGRAM).unwrap()).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute(Command::parse("w v1").unwrap(), &mut cpu);
+        debugger.execute(Command::parse("w i").unwrap(), &mut cpu);
+        let reason = debugger.run_instructions(&mut cpu, 100);
+        assert_eq!(reason.as_deref(), Some("v1 changed from 00 to 05 at 0204"));
+        debugger.execute(Command::parse("u v1").unwrap(), &mut cpu);
+        debugger.execute(Command::Continue, &mut cpu);
+        assert_eq!(debugger.run_instructions(&mut cpu, 100), None);
+        assert_eq!(
+            Command::parse("w dt"),
+            Err("dt isn't v0-vf or i".to_string())
+        );
+        assert!(Command::parse("w r 30f-300").is_err());
+    }
+    #[test]
+    fn pauses_when_an_instruction_fails() {
+        let mut cpu = CPU::new(&assemble("v0 := 1 return").unwrap()).unwrap();
+        let mut debugger = Debugger::new();
+        let reason = debugger.run_instructions(&mut cpu, 10);
+        assert_eq!(
+            reason.as_deref(),
+            Some("stack underflow at 0202, return without a call")
+        );
+        assert!(debugger.paused);
+        assert_eq!(cpu.prog_counter, 0x202);
+    }
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("n"), Ok(Command::StepOver));
+        assert_eq!(
+            Command::parse("b 0x300 if i >= 0x210"),
+            Ok(Command::Break {
+                address: 0x300,
+                condition: Some(Condition {
+                    register: Register::I,
+                    comparison: Comparison::Ge,
+                    value: 0x210
+                })
+            })
+        );
+        assert_eq!(
+            Command::parse("b 300 if vg == 1"),
+            Err("vg isn't v0-vf, i, dt or st".to_string())
+        );
+        assert_eq!(
+            Command::parse("d 300"),
+            Ok(Command::Delete { address: 0x300 })
+        );
+        assert!(Command::parse("step 2").is_err());
+        assert!(Command::parse("jump").is_err());
+    }
+    #[test]
+    fn dumps_registers_and_disassembly() {
+        let program = compile(
+            PROGRAM
+                .replace("jump spin", ":breakpoint spun jump spin")
+                .as_str(),
+            Mode::Chip8,
+        )
+        .unwrap();
+        let mut cpu = CPU::new(&program.rom).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.load_markers(&program);
+        debugger.run_instructions(&mut cpu, 100);
+        cpu.v_reg[0xA] = 0x42;
+        let dump = debugger.dump(&cpu);
+        assert_eq!(dump[0], "PC 0206  I 0000  SP 0  DT 00  ST 00");
+        assert_eq!(
+            dump[1],
+            "V0 03  V1 05  V2 00  V3 00  V4 00  V5 00  V6 00  V7 00"
+        );
+        assert_eq!(
+            dump[2],
+            "V8 00  V9 00  VA 42  VB 00  VC 00  VD 00  VE 00  VF 00"
+        );
+        assert!(dump.contains(&">* 0206  1206  jump spun".to_string()));
+        assert!(dump.contains(&"   0202  2208  :call 0x208".to_string()));
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use super::cpu::Mode;
+use super::instruction::Instruction;
+
+// Programs are loaded and start executing here
+pub const PROGRAM_START: u16 = 0x200;
+// Data runs are split into lines of at most this many bytes
+const DATA_BYTES_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    // Octo assembly, which can be fed back to the assembler
+    Octo,
+    // The mnemonics from Cowgod's Chip-8 technical reference
+    Cowgod,
+}
+
+pub const SYNTAX_NAMES: [&str; 2] = ["octo", "cowgod"];
+
+impl Syntax {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "octo" => Some(Syntax::Octo),
+            "cowgod" => Some(Syntax::Cowgod),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Code {
+        address: u16,
+        instruction: Instruction,
+        bytes: Vec<u8>,
+    },
+    Data {
+        address: u16,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Item {
+    pub fn address(&self) -> u16 {
+        match self {
+            Item::Code { address, .. } | Item::Data { address, .. } => *address,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub items: Vec<Item>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+// Separates code from data by following every path the program can take from
+// PROGRAM_START. Anything that is never reached is treated as data.
+pub fn disassemble(rom: &[u8], mode: Mode) -> Disassembly {
+    let end = PROGRAM_START as usize + rom.len();
+    let word_at = |address: usize| -> Option<u16> {
+        if address < PROGRAM_START as usize || address + 2 > end {
+            return None;
+        }
+        let offset = address - PROGRAM_START as usize;
+        Some(((rom[offset] as u16) << 8) | rom[offset + 1] as u16)
+    };
+
+    let mut code: BTreeMap<u16, Instruction> = BTreeMap::new();
+    let mut is_code = vec![false; rom.len()];
+    let mut call_targets = BTreeSet::new();
+    let mut jump_targets = BTreeSet::new();
+    let mut data_refs = BTreeSet::new();
+    let mut pending = vec![PROGRAM_START as usize];
+
+    while let Some(address) = pending.pop() {
+        let instruction = match word_at(address).map(Instruction::decode) {
+            Some(Ok(instruction)) if instruction.required_mode() <= mode => instruction,
+            _ => continue,
+        };
+        let size = instruction.size() as usize;
+        if address + size > end {
+            continue;
+        }
+        let offset = address - PROGRAM_START as usize;
+        // Already decoded, or overlapping an instruction that was
+        if is_code[offset..offset + size].iter().any(|byte| *byte) {
+            continue;
+        }
+        for byte in is_code[offset..offset + size].iter_mut() {
+            *byte = true;
+        }
+        code.insert(address as u16, instruction);
+
+        let next = address + size;
+        match instruction {
+            Instruction::Jump { nnn } | Instruction::JumpOffset { nnn } => {
+                jump_targets.insert(nnn);
+                pending.push(nnn as usize);
+            }
+            Instruction::Call { nnn } => {
+                call_targets.insert(nnn);
+                pending.push(nnn as usize);
+                pending.push(next);
+            }
+            Instruction::Return | Instruction::Exit => {}
+            Instruction::SkipEqImm { .. }
+            | Instruction::SkipNeqImm { .. }
+            | Instruction::SkipEq { .. }
+            | Instruction::SkipNeq { .. }
+            | Instruction::SkipKey { .. }
+            | Instruction::SkipNotKey { .. } => {
+                let skipped = match word_at(next) {
+                    Some(0xF000) if mode == Mode::XoChip => 4,
+                    _ => 2,
+                };
+                pending.push(next);
+                pending.push(next + skipped);
+            }
+            Instruction::LoadI { nnn } => {
+                data_refs.insert(nnn);
+                pending.push(next);
+            }
+            Instruction::LoadILong => {
+                if let Some(target) = word_at(address + 2) {
+                    data_refs.insert(target);
+                }
+                pending.push(next);
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    let items = collect_items(rom, &code, &call_targets, &jump_targets, &data_refs);
+    let starts: BTreeSet<u16> = items.iter().map(Item::address).collect();
+    let mut labels = BTreeMap::new();
+    // Calls win over jumps, which win over data references
+    for (targets, prefix) in [
+        (&data_refs, "data"),
+        (&jump_targets, "label"),
+        (&call_targets, "sub"),
+    ]
+    .iter()
+    {
+        for target in targets.iter().filter(|target| starts.contains(target)) {
+            labels.insert(*target, format!("{}_{:04X}", prefix, target));
+        }
+    }
+    if code.contains_key(&PROGRAM_START) {
+        labels.insert(PROGRAM_START, "main".to_string());
+    }
+
+    Disassembly { items, labels }
+}
+
+fn collect_items(
+    rom: &[u8],
+    code: &BTreeMap<u16, Instruction>,
+    call_targets: &BTreeSet<u16>,
+    jump_targets: &BTreeSet<u16>,
+    data_refs: &BTreeSet<u16>,
+) -> Vec<Item> {
+    let is_target = |address: u16| {
+        call_targets.contains(&address)
+            || jump_targets.contains(&address)
+            || data_refs.contains(&address)
+    };
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = PROGRAM_START + offset as u16;
+        if let Some(instruction) = code.get(&address) {
+            let size = instruction.size() as usize;
+            items.push(Item::Code {
+                address,
+                instruction: *instruction,
+                bytes: rom[offset..offset + size].to_vec(),
+            });
+            offset += size;
+            continue;
+        }
+        // Data runs until the next instruction or label
+        let mut bytes = vec![rom[offset]];
+        offset += 1;
+        while offset < rom.len() && bytes.len() < DATA_BYTES_PER_LINE {
+            let address = PROGRAM_START + offset as u16;
+            if code.contains_key(&address) || is_target(address) {
+                break;
+            }
+            bytes.push(rom[offset]);
+            offset += 1;
+        }
+        items.push(Item::Data { address, bytes });
+    }
+    items
+}
+
+impl Disassembly {
+    pub fn render(&self, syntax: Syntax) -> String {
+        let mut out = String::new();
+        for item in self.items.iter() {
+            if let Some(label) = self.labels.get(&item.address()) {
+                match syntax {
+                    Syntax::Octo => writeln!(out, ": {}", label).unwrap(),
+                    Syntax::Cowgod => writeln!(out, "{}:", label).unwrap(),
+                }
+            }
+            let line = match item {
+                Item::Code {
+                    address,
+                    instruction,
+                    bytes,
+                } => {
+                    let operand = long_operand(bytes);
+                    let text = format_instruction(instruction, operand, syntax, &self.labels);
+                    render_line(*address, bytes, &text, syntax)
+                }
+                Item::Data { address, bytes } => {
+                    let text = match syntax {
+                        Syntax::Octo => format_bytes(bytes, " "),
+                        Syntax::Cowgod => format!("DB {}", format_bytes(bytes, ", ")),
+                    };
+                    render_line(*address, bytes, &text, syntax)
+                }
+            };
+            writeln!(out, "{}", line).unwrap();
+        }
+        out
+    }
+}
+
+// The address word following F000
+fn long_operand(bytes: &[u8]) -> u16 {
+    if bytes.len() == 4 {
+        ((bytes[2] as u16) << 8) | bytes[3] as u16
+    } else {
+        0
+    }
+}
+
+fn render_line(address: u16, bytes: &[u8], text: &str, syntax: Syntax) -> String {
+    let raw = bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ");
+    match syntax {
+        // Address and raw bytes go in a comment so the output reassembles
+        Syntax::Octo => format!("\t{:<27} # {:04X}  {}", text, address, raw),
+        Syntax::Cowgod => format!("{:04X}  {:<23} {}", address, raw, text),
+    }
+}
+
+fn format_bytes(bytes: &[u8], separator: &str) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("0x{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+// Formats a single instruction. `long_operand` is the address word of
+// F000 NNNN and is ignored for every other instruction.
+pub fn format_instruction(
+    instruction: &Instruction,
+    long_operand: u16,
+    syntax: Syntax,
+    labels: &BTreeMap<u16, String>,
+) -> String {
+    let target = |address: u16| match labels.get(&address) {
+        Some(label) => label.clone(),
+        None => format!("0x{:03X}", address),
+    };
+    match syntax {
+        Syntax::Octo => format_octo(instruction, long_operand, &target),
+        Syntax::Cowgod => format_cowgod(instruction, long_operand, &target),
+    }
+}
+
+fn format_octo(
+    instruction: &Instruction,
+    long_operand: u16,
+    target: &dyn Fn(u16) -> String,
+) -> String {
+    match *instruction {
+        Instruction::ScrollDown { n } => format!("scroll-down {}", n),
+        Instruction::ScrollUp { n } => format!("scroll-up {}", n),
+        Instruction::Clear => "clear".to_string(),
+        Instruction::Return => "return".to_string(),
+        Instruction::ScrollRight => "scroll-right".to_string(),
+        Instruction::ScrollLeft => "scroll-left".to_string(),
+        Instruction::Exit => "exit".to_string(),
+        Instruction::Lores => "lores".to_string(),
+        Instruction::Hires => "hires".to_string(),
+        // Octo has no syntax for native calls, so emit the raw opcode
+        Instruction::MachineCall { nnn } => {
+            format!("0x{:02X} 0x{:02X}", nnn >> 8, nnn & 0xFF)
+        }
+        Instruction::Jump { nnn } => format!("jump {}", target(nnn)),
+        Instruction::Call { nnn } => format!(":call {}", target(nnn)),
+        // Octo conditions describe when the next instruction runs, which is
+        // the opposite of when it is skipped
+        Instruction::SkipEqImm { x, nn } => format!("if v{:x} != 0x{:02X} then", x, nn),
+        Instruction::SkipNeqImm { x, nn } => format!("if v{:x} == 0x{:02X} then", x, nn),
+        Instruction::SkipEq { x, y } => format!("if v{:x} != v{:x} then", x, y),
+        Instruction::SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+        Instruction::LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+        Instruction::LoadImm { x, nn } => format!("v{:x} := 0x{:02X}", x, nn),
+        Instruction::AddImm { x, nn } => format!("v{:x} += 0x{:02X}", x, nn),
+        Instruction::Move { x, y } => format!("v{:x} := v{:x}", x, y),
+        Instruction::Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+        Instruction::And { x, y } => format!("v{:x} &= v{:x}", x, y),
+        Instruction::Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+        Instruction::Add { x, y } => format!("v{:x} += v{:x}", x, y),
+        Instruction::Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+        Instruction::ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+        Instruction::SubReverse { x, y } => format!("v{:x} =- v{:x}", x, y),
+        Instruction::ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+        Instruction::SkipNeq { x, y } => format!("if v{:x} == v{:x} then", x, y),
+        Instruction::LoadI { nnn } => format!("i := {}", target(nnn)),
+        Instruction::JumpOffset { nnn } => format!("jump0 {}", target(nnn)),
+        Instruction::Random { x, nn } => format!("v{:x} := random 0x{:02X}", x, nn),
+        Instruction::Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Instruction::SkipKey { x } => format!("if v{:x} -key then", x),
+        Instruction::SkipNotKey { x } => format!("if v{:x} key then", x),
+        Instruction::LoadILong => format!("i := long {}", target(long_operand)),
+        Instruction::SelectPlanes { n } => format!("plane {}", n),
+        Instruction::LoadAudio => "audio".to_string(),
+        Instruction::GetDelay { x } => format!("v{:x} := delay", x),
+        Instruction::WaitKey { x } => format!("v{:x} := key", x),
+        Instruction::SetDelay { x } => format!("delay := v{:x}", x),
+        Instruction::SetSound { x } => format!("buzzer := v{:x}", x),
+        Instruction::AddI { x } => format!("i += v{:x}", x),
+        Instruction::LoadFont { x } => format!("i := hex v{:x}", x),
+        Instruction::LoadBigFont { x } => format!("i := bighex v{:x}", x),
+        Instruction::Bcd { x } => format!("bcd v{:x}", x),
+        Instruction::SetPitch { x } => format!("pitch := v{:x}", x),
+        Instruction::Store { x } => format!("save v{:x}", x),
+        Instruction::Load { x } => format!("load v{:x}", x),
+        Instruction::SaveFlags { x } => format!("saveflags v{:x}", x),
+        Instruction::LoadFlags { x } => format!("loadflags v{:x}", x),
+    }
+}
+
+fn format_cowgod(
+    instruction: &Instruction,
+    long_operand: u16,
+    target: &dyn Fn(u16) -> String,
+) -> String {
+    match *instruction {
+        Instruction::ScrollDown { n } => format!("SCD {}", n),
+        Instruction::ScrollUp { n } => format!("SCU {}", n),
+        Instruction::Clear => "CLS".to_string(),
+        Instruction::Return => "RET".to_string(),
+        Instruction::ScrollRight => "SCR".to_string(),
+        Instruction::ScrollLeft => "SCL".to_string(),
+        Instruction::Exit => "EXIT".to_string(),
+        Instruction::Lores => "LOW".to_string(),
+        Instruction::Hires => "HIGH".to_string(),
+        Instruction::MachineCall { nnn } => format!("SYS {}", target(nnn)),
+        Instruction::Jump { nnn } => format!("JP {}", target(nnn)),
+        Instruction::Call { nnn } => format!("CALL {}", target(nnn)),
+        Instruction::SkipEqImm { x, nn } => format!("SE V{:X}, 0x{:02X}", x, nn),
+        Instruction::SkipNeqImm { x, nn } => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        Instruction::SkipEq { x, y } => format!("SE V{:X}, V{:X}", x, y),
+        Instruction::SaveRange { x, y } => format!("LD [I], V{:X}-V{:X}", x, y),
+        Instruction::LoadRange { x, y } => format!("LD V{:X}-V{:X}, [I]", x, y),
+        Instruction::LoadImm { x, nn } => format!("LD V{:X}, 0x{:02X}", x, nn),
+        Instruction::AddImm { x, nn } => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        Instruction::Move { x, y } => format!("LD V{:X}, V{:X}", x, y),
+        Instruction::Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+        Instruction::And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+        Instruction::Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+        Instruction::Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+        Instruction::Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+        Instruction::ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+        Instruction::SubReverse { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+        Instruction::ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+        Instruction::SkipNeq { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+        Instruction::LoadI { nnn } => format!("LD I, {}", target(nnn)),
+        Instruction::JumpOffset { nnn } => format!("JP V0, {}", target(nnn)),
+        Instruction::Random { x, nn } => format!("RND V{:X}, 0x{:02X}", x, nn),
+        Instruction::Draw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Instruction::SkipKey { x } => format!("SKP V{:X}", x),
+        Instruction::SkipNotKey { x } => format!("SKNP V{:X}", x),
+        Instruction::LoadILong => format!("LD I, LONG {}", target(long_operand)),
+        Instruction::SelectPlanes { n } => format!("PLANE {}", n),
+        Instruction::LoadAudio => "AUDIO".to_string(),
+        Instruction::GetDelay { x } => format!("LD V{:X}, DT", x),
+        Instruction::WaitKey { x } => format!("LD V{:X}, K", x),
+        Instruction::SetDelay { x } => format!("LD DT, V{:X}", x),
+        Instruction::SetSound { x } => format!("LD ST, V{:X}", x),
+        Instruction::AddI { x } => format!("ADD I, V{:X}", x),
+        Instruction::LoadFont { x } => format!("LD F, V{:X}", x),
+        Instruction::LoadBigFont { x } => format!("LD HF, V{:X}", x),
+        Instruction::Bcd { x } => format!("LD B, V{:X}", x),
+        Instruction::SetPitch { x } => format!("PITCH V{:X}", x),
+        Instruction::Store { x } => format!("LD [I], V{:X}", x),
+        Instruction::Load { x } => format!("LD V{:X}, [I]", x),
+        Instruction::SaveFlags { x } => format!("LD R, V{:X}", x),
+        Instruction::LoadFlags { x } => format!("LD V{:X}, R", x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn separates_code_from_data() {
+        let rom = [
+            0x22, 0x08, // 0200: call 0x208
+            0xA2, 0x0C, // 0202: i := 0x20C
+            0x12, 0x06, // 0204: jump 0x206
+            0x12, 0x06, // 0206: jump 0x206
+            0x00, 0xE0, // 0208: clear
+            0x00, 0xEE, // 020A: return
+            0xF0, 0x90, // 020C: sprite data
+        ];
+        let disassembly = disassemble(&rom, Mode::Chip8);
+        let code: Vec<u16> = disassembly
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Code { address, .. } => Some(*address),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(code, vec![0x200, 0x202, 0x204, 0x206, 0x208, 0x20A]);
+        assert_eq!(
+            disassembly.items.last(),
+            Some(&Item::Data {
+                address: 0x20C,
+                bytes: vec![0xF0, 0x90]
+            })
+        );
+        assert_eq!(disassembly.labels[&0x200], "main");
+        assert_eq!(disassembly.labels[&0x206], "label_0206");
+        assert_eq!(disassembly.labels[&0x208], "sub_0208");
+        assert_eq!(disassembly.labels[&0x20C], "data_020C");
+    }
+    #[test]
+    fn follows_both_sides_of_a_skip() {
+        let rom = [
+            0x30, 0x00, // 0200: skip if v0 == 0
+            0x12, 0x08, // 0202: jump 0x208
+            0x00, 0xE0, // 0204: clear
+            0x00, 0xFD, // 0206: exit (SUPER-CHIP only)
+            0x00, 0xE0, // 0208: clear
+        ];
+        let chip8 = disassemble(&rom, Mode::Chip8);
+        assert!(chip8.items.iter().any(|item| *item
+            == Item::Data {
+                address: 0x206,
+                bytes: vec![0x00, 0xFD]
+            }));
+        let schip = disassemble(&rom, Mode::SuperChip);
+        assert!(schip
+            .items
+            .iter()
+            .all(|item| matches!(item, Item::Code { .. })));
+    }
+    #[test]
+    fn renders_both_syntaxes() {
+        let rom = [0x60, 0x0A, 0xD0, 0x15, 0x12, 0x00];
+        let disassembly = disassemble(&rom, Mode::Chip8);
+        let octo = disassembly.render(Syntax::Octo);
+        assert_eq!(
+            octo.lines().collect::<Vec<_>>(),
+            vec![
+                ": main",
+                "\tv0 := 0x0A                  # 0200  60 0A",
+                "\tsprite v0 v1 5              # 0202  D0 15",
+                "\tjump main                   # 0204  12 00",
+            ]
+        );
+        let cowgod = disassembly.render(Syntax::Cowgod);
+        assert_eq!(
+            cowgod.lines().nth(2),
+            Some("0202  D0 15                   DRW V0, V1, 5")
+        );
+    }
+    #[test]
+    fn renders_long_loads() {
+        let rom = [0xF0, 0x00, 0x12, 0x34, 0x00, 0xFD];
+        let disassembly = disassemble(&rom, Mode::XoChip);
+        let octo = disassembly.render(Syntax::Octo);
+        assert!(octo.contains("i := long 0x1234"));
+        assert!(octo.contains("# 0200  F0 00 12 34"));
+    }
+}
@@ -1,126 +1,125 @@
-use sdl2::{
-    event::Event, keyboard::Keycode, pixels::Color, rect::Rect, render::TextureCreator,
-    video::WindowContext, Sdl,
-};
-use sdl2::{render::Canvas, video::Window};
-use std::fmt;
+pub const BASE_WIDTH: u32 = 64;
+pub const BASE_HEIGHT: u32 = 32;
+// SUPER-CHIP high resolution mode
+pub const HIRES_WIDTH: u32 = 128;
+pub const HIRES_HEIGHT: u32 = 64;
 
+// Plain framebuffer owned by the CPU, frontends only ever read from it
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Display {
     pub width: u32,
     pub height: u32,
+    // One bit per XO-CHIP plane, plain CHIP-8 only ever uses the first
     pub pixels: Vec<u8>,
-    pub sdl_ctx: Sdl,
-    pub canvas: Canvas<Window>,
-    pub scale: (u32, u32),
-    pub texture_creator: TextureCreator<WindowContext>,
+    // Planes affected by drawing, clearing and scrolling
+    pub planes: u8,
+    // Set whenever the pixels change, cleared once a frontend presents them
+    pub dirty: bool,
 }
 
-pub const BASE_WIDTH: u32 = 64;
-pub const BASE_HEIGHT: u32 = 32;
+impl Default for Display {
+    fn default() -> Self {
+        Display::new(BASE_WIDTH, BASE_HEIGHT)
+    }
+}
 
 impl Display {
     pub fn new(width: u32, height: u32) -> Self {
-        let (sdl_ctx, canvas, texture_creator) = Display::init_sdl(width, height);
         Display {
             width,
             height,
-            pixels: vec![0; (BASE_WIDTH as usize) * (BASE_HEIGHT as usize)],
-            sdl_ctx,
-            canvas,
-            scale: (width / BASE_WIDTH, height / BASE_HEIGHT),
-            texture_creator,
+            pixels: vec![0; (width as usize) * (height as usize)],
+            planes: 1,
+            dirty: true,
         }
     }
 
-    fn init_sdl(width: u32, height: u32) -> (Sdl, Canvas<Window>, TextureCreator<WindowContext>) {
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem
-            .window("Chip-8 Emulator", width, height)
-            .position_centered()
-            .opengl()
-            .build()
-            .map_err(|e| e.to_string())
-            .unwrap();
-        let mut canvas = window.into_canvas().build().unwrap();
-        let tex_creator = canvas.texture_creator();
-        canvas.set_draw_color(Color::GREEN);
-        (sdl_context, canvas, tex_creator)
-    }
-
     pub fn get_pixel(&self, x: u32, y: u32) -> u8 {
-        self.pixels[(y * BASE_WIDTH) as usize + x as usize]
+        self.pixels[(y * self.width) as usize + x as usize]
     }
 
     pub fn set_pixel(&mut self, x: u32, y: u32, bit: u8) {
-        self.pixels[(y * BASE_WIDTH) as usize + x as usize] = bit;
+        self.pixels[(y * self.width) as usize + x as usize] = bit;
+        self.dirty = true;
     }
 
-    pub fn update(&mut self) -> bool {
-        for event in self.sdl_ctx.event_pump().unwrap().poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => {
-                    return true;
-                }
-                _ => {}
-            }
-        }
-        false
-    }
     pub fn clear(&mut self) {
         for pixel in self.pixels.iter_mut() {
-            *pixel = 0;
+            *pixel &= !self.planes;
         }
-        self.draw(true);
+        self.dirty = true;
     }
 
-    pub fn draw(&mut self, is_clear: bool) {
-        let mut texture = self
-            .texture_creator
-            .create_texture_target(
-                self.texture_creator.default_pixel_format(),
-                self.width,
-                self.height,
-            )
-            .unwrap();
-        let pixels = &self.pixels;
-        let x_scale = &self.scale.0;
-        let y_scale = &self.scale.1;
-        self.canvas
-            .with_texture_canvas(&mut texture, |texture_canvas| {
-                texture_canvas.set_draw_color(Color::BLACK);
-                texture_canvas.clear();
-                if is_clear {
-                    return;
-                };
-                for (ind, pixel) in pixels.iter().enumerate() {
-                    if *pixel == 1 {
-                        texture_canvas.set_draw_color(Color::YELLOW);
-                        texture_canvas
-                            .fill_rect(Rect::new(
-                                (ind % BASE_WIDTH as usize) as i32 * (*x_scale as i32),
-                                (ind / BASE_WIDTH as usize) as i32 * (*y_scale as i32),
-                                *x_scale,
-                                *y_scale,
-                            ))
-                            .unwrap();
-                    }
-                }
-            })
-            .unwrap();
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    // Switches resolution, which also clears the screen
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let planes = self.planes;
+        *self = Display::new(width, height);
+        self.planes = planes;
+    }
 
-        self.canvas.clear();
-        self.canvas.copy(&texture, None, None).unwrap();
-        self.canvas.present();
+    // Moves every pixel by the given offset, pixels moved in from outside the
+    // screen are blank
+    pub fn scroll(&mut self, dx: i32, dy: i32) {
+        let (width, height) = (self.width as i32, self.height as i32);
+        // Unselected planes stay where they are
+        let mut scrolled: Vec<u8> = self
+            .pixels
+            .iter()
+            .map(|pixel| pixel & !self.planes)
+            .collect();
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    scrolled[(y * width + x) as usize] |=
+                        self.pixels[(src_y * width + src_x) as usize] & self.planes;
+                }
+            }
+        }
+        self.pixels = scrolled;
+        self.dirty = true;
     }
 }
 
-impl fmt::Debug for Display {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("SDL Display").finish()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn scrolls_pixels() {
+        let mut display = Display::default();
+        display.set_pixel(10, 10, 1);
+        display.scroll(0, 4);
+        assert_eq!(display.get_pixel(10, 14), 1);
+        assert_eq!(display.get_pixel(10, 10), 0);
+        display.scroll(-4, 0);
+        assert_eq!(display.get_pixel(6, 14), 1);
+        // Scrolled off screen
+        display.scroll(0, 20);
+        assert!(!display.pixels.contains(&1));
+    }
+    #[test]
+    fn clears_and_scrolls_selected_planes_only() {
+        let mut display = Display::default();
+        display.set_pixel(0, 0, 0b11);
+        display.planes = 0b10;
+        display.scroll(1, 0);
+        assert_eq!(display.get_pixel(0, 0), 0b01);
+        assert_eq!(display.get_pixel(1, 0), 0b10);
+        display.clear();
+        assert_eq!(display.get_pixel(1, 0), 0);
+        assert_eq!(display.get_pixel(0, 0), 0b01);
+    }
+    #[test]
+    fn resizes_and_clears() {
+        let mut display = Display::default();
+        display.set_pixel(0, 0, 1);
+        display.resize(HIRES_WIDTH, HIRES_HEIGHT);
+        assert!(display.is_hires());
+        assert_eq!(display.pixels.len(), 128 * 64);
+        assert!(!display.pixels.contains(&1));
     }
 }
@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::fmt;
+
+// Ways the machine can fail to load or keep running a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmulatorError {
+    // The ROM doesn't fit between 0x200 and the end of memory
+    RomTooLarge { size: usize, max: usize },
+    // A call with all 16 stack entries in use
+    StackOverflow { pc: u16 },
+    // A return without a call to return from
+    StackUnderflow { pc: u16 },
+    // An instruction reached past the end of memory
+    MemoryOutOfBounds { pc: u16, address: usize },
+    // An opcode the current mode doesn't define
+    UnknownOpcode { pc: u16, opcode: u16 },
+    // The frontend couldn't open its window, audio or input
+    Frontend(String),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::RomTooLarge { size, max } => {
+                write!(f, "ROM is {} bytes but only {} fit in memory", size, max)
+            }
+            EmulatorError::StackOverflow { pc } => write!(
+                f,
+                "stack overflow at {:04X}, calls are nested more than 16 deep",
+                pc
+            ),
+            EmulatorError::StackUnderflow { pc } => {
+                write!(f, "stack underflow at {:04X}, return without a call", pc)
+            }
+            EmulatorError::MemoryOutOfBounds { pc, address } => write!(
+                f,
+                "memory access out of bounds at {:04X}, address {:04X} is past the end of memory",
+                pc, address
+            ),
+            EmulatorError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:04X} at {:04X}", opcode, pc)
+            }
+            EmulatorError::Frontend(message) => write!(f, "frontend failed, {}", message),
+        }
+    }
+}
+
+impl Error for EmulatorError {}
@@ -0,0 +1,75 @@
+use super::audio::AudioPattern;
+use super::debugger::Command;
+use super::display::Display;
+use super::keypad::Keypad;
+use super::savestate::SlotCommand;
+
+// The boundary between the machine and whatever shows it to the user. The run
+// loop only talks to this trait, so video, input and audio backends can be
+// swapped without touching the CPU.
+pub trait Frontend {
+    // Shows the current framebuffer
+    fn present(&mut self, display: &Display);
+    // Updates the keypad from pending input, returns true when the user asked
+    // to quit
+    fn poll_input(&mut self, keypad: &mut Keypad) -> bool;
+    // Starts or stops the buzzer, called every tick with the current state
+    fn set_buzzer(&mut self, on: bool);
+    // Switches the buzzer to an XO-CHIP audio pattern, None goes back to the
+    // plain tone. Backends without audio can ignore it.
+    fn set_audio_pattern(&mut self, _pattern: Option<AudioPattern>) {}
+    // Shows the framebuffer with the debugger's text on top while paused.
+    // Backends without an overlay leave it to the terminal.
+    fn present_overlay(&mut self, _display: &Display, _lines: &[String]) {}
+    // Debugger commands from hotkeys since the last call
+    fn debug_commands(&mut self) -> Vec<Command> {
+        Vec::new()
+    }
+    // Save state hotkeys pressed since the last call
+    fn slot_commands(&mut self) -> Vec<SlotCommand> {
+        Vec::new()
+    }
+    // Whether the rewind hotkey is held down
+    fn rewind_held(&self) -> bool {
+        false
+    }
+}
+
+// Frontend without any video or audio, used for headless runs and tests.
+// Quits after `frame_limit` polls when one is given.
+#[derive(Debug, Default, Clone)]
+pub struct Headless {
+    pub frame_limit: Option<u64>,
+    pub frames_polled: u64,
+    pub frames_presented: u64,
+    pub last_frame: Vec<u8>,
+    pub buzzer: bool,
+}
+
+impl Headless {
+    pub fn new(frame_limit: Option<u64>) -> Self {
+        Headless {
+            frame_limit,
+            ..Headless::default()
+        }
+    }
+}
+
+impl Frontend for Headless {
+    fn present(&mut self, display: &Display) {
+        self.frames_presented += 1;
+        self.last_frame = display.pixels.clone();
+    }
+
+    fn poll_input(&mut self, _keypad: &mut Keypad) -> bool {
+        self.frames_polled += 1;
+        match self.frame_limit {
+            Some(limit) => self.frames_polled >= limit,
+            None => false,
+        }
+    }
+
+    fn set_buzzer(&mut self, on: bool) {
+        self.buzzer = on;
+    }
+}
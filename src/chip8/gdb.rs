@@ -0,0 +1,512 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+
+use super::cpu::CPU;
+use super::debugger::{Access, Command, Debugger, Watch};
+
+// Registers in the order GDB numbers them, with their size in bytes. Values
+// go over the wire big endian, the same way CHIP-8 stores words in memory.
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1),
+    ("v1", 1),
+    ("v2", 1),
+    ("v3", 1),
+    ("v4", 1),
+    ("v5", 1),
+    ("v6", 1),
+    ("v7", 1),
+    ("v8", 1),
+    ("v9", 1),
+    ("va", 1),
+    ("vb", 1),
+    ("vc", 1),
+    ("vd", 1),
+    ("ve", 1),
+    ("vf", 1),
+    ("i", 2),
+    ("pc", 2),
+    ("sp", 1),
+    ("dt", 1),
+    ("st", 1),
+];
+
+// Stop replies, the signal numbers GDB expects for a trap and an interrupt
+const STOPPED: &str = "S05";
+const INTERRUPTED: &str = "S02";
+
+fn target_description() -> String {
+    let registers: Vec<String> = REGISTERS
+        .iter()
+        .map(|(name, size)| {
+            let kind = match *name {
+                "pc" => "code_ptr",
+                "i" => "data_ptr",
+                _ => "int",
+            };
+            format!(
+                "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>",
+                name,
+                size * 8,
+                kind
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n  <feature name=\"org.chip8.core\">\n{}\n  </feature>\n</target>\n",
+        registers.join("\n")
+    )
+}
+
+fn read_register(cpu: &CPU, number: usize) -> u16 {
+    match number {
+        0..=15 => cpu.v_reg[number] as u16,
+        16 => cpu.i_reg,
+        17 => cpu.prog_counter,
+        18 => cpu.stack_ptr as u16,
+        19 => cpu.delay_reg as u16,
+        _ => cpu.sound_reg as u16,
+    }
+}
+
+fn write_register(cpu: &mut CPU, number: usize, value: u16) {
+    match number {
+        0..=15 => cpu.v_reg[number] = value as u8,
+        16 => cpu.i_reg = value,
+        17 => cpu.prog_counter = value,
+        // The stack pointer indexes the 16 entry stack
+        18 => cpu.stack_ptr = (value as u8).min(cpu.stack.len() as u8),
+        19 => cpu.delay_reg = value as u8,
+        _ => cpu.sound_reg = value as u8,
+    }
+}
+
+fn encode_register(cpu: &CPU, number: usize) -> String {
+    let value = read_register(cpu, number);
+    match REGISTERS[number].1 {
+        1 => format!("{:02x}", value),
+        _ => format!("{:04x}", value),
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|ind| u8::from_str_radix(text.get(ind..ind + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// Splits `addr,length` as used by the memory and breakpoint packets
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+// The memory an `addr,length` packet names, if it's all there. Both come
+// straight from the client, so the end may not even fit a usize.
+fn memory_range(cpu: &CPU, text: &str) -> Option<Range<usize>> {
+    let (address, length) = parse_range(text)?;
+    let end = address
+        .checked_add(length)
+        .filter(|end| *end <= cpu.memory.len())?;
+    Some(address..end)
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// A GDB remote serial protocol server for one connected debugger. It drives
+// the machine through a `Debugger`, so GDB breakpoints and stepping behave
+// like the built in ones.
+#[derive(Debug)]
+pub struct GdbStub {
+    stream: TcpStream,
+    // Bytes received but not yet handled
+    input: Vec<u8>,
+    // GDB sent a continue and waits for a stop reply
+    running: bool,
+    pub attached: bool,
+}
+
+impl GdbStub {
+    // Waits for GDB to connect to the given local port
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+        GdbStub::new(stream)
+    }
+
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        // Polled once per frame, so reads must never block the emulator
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            input: Vec::new(),
+            running: false,
+            attached: true,
+        })
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.attached = false;
+                    return Ok(());
+                }
+                Ok(read) => self.input.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Handles whatever GDB sent since the last call and reports stops.
+    // Returns true when GDB killed the program.
+    pub fn poll(&mut self, cpu: &mut CPU, debugger: &mut Debugger) -> io::Result<bool> {
+        if self.running && debugger.paused {
+            self.running = false;
+            self.send_packet(STOPPED)?;
+        }
+        self.receive()?;
+        while !self.input.is_empty() {
+            match self.input[0] {
+                // Ctrl-C while the program runs
+                0x03 => {
+                    self.input.remove(0);
+                    debugger.execute(Command::Pause, cpu);
+                    if self.running {
+                        self.running = false;
+                        self.send_packet(INTERRUPTED)?;
+                    }
+                }
+                b'$' => {
+                    let end = match self.input.iter().position(|byte| *byte == b'#') {
+                        Some(end) if self.input.len() >= end + 3 => end,
+                        // Wait for the rest of the packet
+                        _ => break,
+                    };
+                    let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let expected = std::str::from_utf8(&packet[end + 1..])
+                        .ok()
+                        .and_then(|text| u8::from_str_radix(text, 16).ok());
+                    if expected != Some(checksum(data)) {
+                        self.stream.write_all(b"-")?;
+                        continue;
+                    }
+                    self.stream.write_all(b"+")?;
+                    let data = String::from_utf8_lossy(data).into_owned();
+                    match self.handle(&data, cpu, debugger) {
+                        Some(reply) => self.send_packet(&reply)?,
+                        None if data == "k" => return Ok(true),
+                        None => {}
+                    }
+                }
+                // Acknowledgements and line noise
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // The reply to a packet, None when it has to wait or there is none
+    fn handle(&mut self, data: &str, cpu: &mut CPU, debugger: &mut Debugger) -> Option<String> {
+        let (kind, args) = data.split_at(data.len().min(1));
+        let reply = match kind {
+            "?" => STOPPED.to_string(),
+            "g" => (0..REGISTERS.len())
+                .map(|number| encode_register(cpu, number))
+                .collect(),
+            "G" => match decode_hex(args) {
+                Some(bytes) => {
+                    let mut bytes = bytes.into_iter();
+                    for (number, (_, size)) in REGISTERS.iter().enumerate() {
+                        let value = (0..*size)
+                            .filter_map(|_| bytes.next())
+                            .fold(0u16, |value, byte| (value << 8) | byte as u16);
+                        write_register(cpu, number, value);
+                    }
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            "p" => match parse_hex(args) {
+                Some(number) if number < REGISTERS.len() => encode_register(cpu, number),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(number, value)| {
+                    let value = decode_hex(value)?
+                        .iter()
+                        .fold(0u16, |value, byte| (value << 8) | *byte as u16);
+                    Some((parse_hex(number)?, value))
+                });
+                match register {
+                    Some((number, value)) if number < REGISTERS.len() => {
+                        write_register(cpu, number, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match memory_range(cpu, args) {
+                Some(range) => cpu.memory[range]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect(),
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, bytes)| {
+                    Some((memory_range(cpu, range)?, decode_hex(bytes)?))
+                });
+                match write {
+                    Some((range, bytes)) if bytes.len() == range.len() => {
+                        cpu.memory[range].copy_from_slice(&bytes);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "c" => {
+                if let Some(address) = parse_hex(args) {
+                    cpu.prog_counter = address as u16;
+                }
+                debugger.execute(Command::Continue, cpu);
+                self.running = true;
+                return None;
+            }
+            "s" => {
+                if let Some(address) = parse_hex(args) {
+                    cpu.prog_counter = address as u16;
+                }
+                debugger.execute(Command::Step, cpu);
+                STOPPED.to_string()
+            }
+            "Z" | "z" => match self.breakpoint(kind == "Z", args, cpu, debugger) {
+                Some(true) => "OK".to_string(),
+                Some(false) => "E01".to_string(),
+                None => String::new(),
+            },
+            "D" => {
+                debugger.execute(Command::Continue, cpu);
+                self.attached = false;
+                "OK".to_string()
+            }
+            "k" => return None,
+            "H" => "OK".to_string(),
+            _ => self.query(data),
+        };
+        Some(reply)
+    }
+
+    // Z0/Z1 map to breakpoints and Z2/Z3 to write and read watches. Returns
+    // None for the kinds that aren't supported.
+    fn breakpoint(
+        &mut self,
+        insert: bool,
+        args: &str,
+        cpu: &mut CPU,
+        debugger: &mut Debugger,
+    ) -> Option<bool> {
+        let (kind, range) = args.split_once(',')?;
+        // A zero length still covers the byte at the address
+        let (address, end) = match memory_range(cpu, range) {
+            Some(range) if range.start < cpu.memory.len() => {
+                let end = range.end.max(range.start + 1) - 1;
+                (range.start as u16, end as u16)
+            }
+            _ => return Some(false),
+        };
+        let command = match (kind, insert) {
+            ("0", true) | ("1", true) => Command::Break {
+                address,
+                condition: None,
+            },
+            ("0", false) | ("1", false) => Command::Delete { address },
+            ("2", _) | ("3", _) => {
+                let watch = Watch::Memory {
+                    access: if kind == "2" {
+                        Access::Write
+                    } else {
+                        Access::Read
+                    },
+                    start: address,
+                    end,
+                };
+                if insert {
+                    Command::Watch(watch)
+                } else {
+                    Command::Unwatch(watch)
+                }
+            }
+            _ => return None,
+        };
+        debugger.execute(command, cpu);
+        Some(true)
+    }
+
+    fn query(&self, data: &str) -> String {
+        if data.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+".to_string();
+        }
+        if let Some(range) = data.strip_prefix("qXfer:features:read:target.xml:") {
+            let description = target_description();
+            return match parse_range(range) {
+                Some((offset, length)) if offset < description.len() => {
+                    let end = (offset + length).min(description.len());
+                    let more = if end < description.len() { 'm' } else { 'l' };
+                    format!("{}{}", more, &description[offset..end])
+                }
+                Some(_) => "l".to_string(),
+                None => "E01".to_string(),
+            };
+        }
+        match data {
+            "qAttached" => "1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qC" => "QC1".to_string(),
+            // An empty reply tells GDB the packet isn't supported
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::asm::assemble;
+    use std::time::Duration;
+    const PROGRAM: &str = "
+        : main
+            v0 := 1
+            v1 := 2
+            v2 := 3
+        : spin
+            jump spin
+    ";
+    struct Client {
+        stream: TcpStream,
+        stub: GdbStub,
+        cpu: CPU,
+        debugger: Debugger,
+    }
+    impl Client {
+        fn connect() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            let stub = GdbStub::new(listener.accept().unwrap().0).unwrap();
+            let mut debugger = Debugger::new();
+            debugger.paused = true;
+            Client {
+                stream,
+                stub,
+                cpu: CPU::new(&assemble(PROGRAM).unwrap()).unwrap(),
+                debugger,
+            }
+        }
+        // Sends a packet and returns the reply, polling the stub like the
+        // run loop does every frame
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            self.reply()
+        }
+        fn reply(&mut self) -> String {
+            let mut received = Vec::new();
+            for _ in 0..500 {
+                self.stub.poll(&mut self.cpu, &mut self.debugger).unwrap();
+                if !self.debugger.paused {
+                    self.debugger.run_instructions(&mut self.cpu, 10);
+                }
+                let mut buffer = [0; 1024];
+                if let Ok(read) = self.stream.read(&mut buffer) {
+                    received.extend_from_slice(&buffer[..read]);
+                }
+                let text = String::from_utf8_lossy(&received).into_owned();
+                let text = text.trim_start_matches('+');
+                if let (Some(start), Some(end)) = (text.find('$'), text.find('#')) {
+                    if text.len() >= end + 3 {
+                        self.stream.write_all(b"+").unwrap();
+                        return text[start + 1..end].to_string();
+                    }
+                }
+            }
+            panic!("no reply to the request");
+        }
+    }
+    #[test]
+    fn reads_and_writes_registers_and_memory() {
+        let mut client = Client::connect();
+        assert_eq!(client.request("?"), "S05");
+        client.cpu.i_reg = 0x123;
+        client.cpu.v_reg[0xF] = 0xAB;
+        let registers = client.request("g");
+        assert_eq!(registers.len(), 2 * (16 + 2 + 2 + 1 + 1 + 1));
+        assert_eq!(&registers[30..40], "ab01230200");
+        assert_eq!(client.request("p11"), "0200");
+        assert_eq!(client.request("P3=2a"), "OK");
+        assert_eq!(client.cpu.v_reg[3], 0x2A);
+        assert_eq!(client.request("m200,4"), "60016102");
+        assert_eq!(client.request("M300,2:beef"), "OK");
+        assert_eq!(client.cpu.memory[0x300..0x302], [0xBE, 0xEF]);
+        assert_eq!(client.request("mfff0,20"), "E01");
+        assert_eq!(client.request("mffffffffffffffff,1"), "E01");
+        assert_eq!(client.request("Mffffffffffffffff,1:00"), "E01");
+        assert_eq!(client.request("Z2,200,10000"), "E01");
+        assert_eq!(client.request("Z3,ffffffffffffffff,1"), "E01");
+        assert_eq!(client.request("Z2,ffe,2"), "OK");
+        assert!(client
+            .request("qXfer:features:read:target.xml:0,1000")
+            .contains("\"pc\""));
+        assert_eq!(client.request("vMustReplyEmpty"), "");
+    }
+    #[test]
+    fn steps_and_stops_at_breakpoints() {
+        let mut client = Client::connect();
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.cpu.prog_counter, 0x202);
+        assert_eq!(client.request("Z0,204,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.cpu.prog_counter, 0x204);
+        assert_eq!(client.cpu.v_reg[1], 2);
+        assert_eq!(client.request("z0,204,2"), "OK");
+        assert_eq!(client.request("Z2,300,1"), "OK");
+        assert_eq!(client.debugger.watches.len(), 1);
+        client.stream.write_all(b"$c#63").unwrap();
+        while client.debugger.paused {
+            client
+                .stub
+                .poll(&mut client.cpu, &mut client.debugger)
+                .unwrap();
+        }
+        client.debugger.run_instructions(&mut client.cpu, 10);
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(), "S02");
+        assert!(client.debugger.paused);
+        assert_eq!(client.cpu.v_reg[2], 3);
+    }
+}
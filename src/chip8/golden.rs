@@ -0,0 +1,494 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use super::cpu::{Mode, CPU, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use super::display::Display;
+use super::error::EmulatorError;
+use super::png;
+use super::quirks::Quirks;
+use super::random::Random;
+
+const HEADER: &str = "chip8 golden 1";
+
+// Pixel values 0-3 as drawn in the display section, 1 and 2 being the XO-CHIP
+// planes
+const PIXEL_CHARS: [char; 4] = ['.', '#', 'o', '@'];
+// Size of a framebuffer pixel in the PNG diff
+const PNG_SCALE: u32 = 4;
+
+// A key held down from the first to the last frame, both included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyHold {
+    pub first: u32,
+    pub last: u32,
+    pub key: u8,
+}
+
+// What a ROM should show after running headless for a number of frames.
+// Saved as text: settings on a line each, the registers to check and their
+// values, then the display drawn a row per line.
+//
+//     chip8 golden 1
+//     rom ../ibm_logo.ch8
+//     frames 60
+//     keys 10-20:5 30:a
+//     registers pc=0228 v0=05
+//     display 64 32
+//     ....##..
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Golden {
+    // Relative to the golden file
+    pub rom: String,
+    pub mode: Mode,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    pub seed: u64,
+    pub frames: u32,
+    pub keys: Vec<KeyHold>,
+    // Registers to compare, without a value until the golden is updated
+    pub registers: Vec<(String, Option<u16>)>,
+    pub display: Option<Display>,
+    // The setting lines as written, kept when updating
+    settings: Vec<String>,
+}
+
+fn is_register(name: &str) -> bool {
+    match name {
+        "pc" | "i" | "sp" | "dt" | "st" => true,
+        _ => name.len() == 2 && name.starts_with('v') && u8::from_str_radix(&name[1..], 16).is_ok(),
+    }
+}
+
+fn register_value(cpu: &CPU, name: &str) -> u16 {
+    match name {
+        "pc" => cpu.prog_counter,
+        "i" => cpu.i_reg,
+        "sp" => cpu.stack_ptr as u16,
+        "dt" => cpu.delay_reg as u16,
+        "st" => cpu.sound_reg as u16,
+        _ => cpu.v_reg[usize::from_str_radix(&name[1..], 16).unwrap()] as u16,
+    }
+}
+
+fn format_register(name: &str, value: u16) -> String {
+    match name {
+        "pc" | "i" => format!("{}={:04X}", name, value),
+        _ => format!("{}={:02X}", name, value),
+    }
+}
+
+// Parses `10-20:5` or `30:a`
+fn parse_key_hold(text: &str) -> Option<KeyHold> {
+    let (frames, key) = text.split_once(':')?;
+    let (first, last) = match frames.split_once('-') {
+        Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+        None => (frames.parse().ok()?, frames.parse().ok()?),
+    };
+    let key = u8::from_str_radix(key, 16).ok().filter(|key| *key < 16)?;
+    if first <= last {
+        Some(KeyHold { first, last, key })
+    } else {
+        None
+    }
+}
+
+fn display_text(display: &Display) -> String {
+    let mut text = String::new();
+    writeln!(text, "display {} {}", display.width, display.height).unwrap();
+    for row in display.pixels.chunks(display.width as usize) {
+        let line: String = row
+            .iter()
+            .map(|pixel| PIXEL_CHARS[*pixel as usize & 3])
+            .collect();
+        writeln!(text, "{}", line).unwrap();
+    }
+    text
+}
+
+// Marks pixels only the actual display has with +, pixels only the expected
+// one has with - and pixels lit on different planes with *
+fn diff_text(expected: &Display, actual: &Display) -> String {
+    let mut text = String::new();
+    for y in 0..actual.height {
+        let line: String = (0..actual.width)
+            .map(|x| {
+                let (want, got) = (expected.get_pixel(x, y), actual.get_pixel(x, y));
+                match (want, got) {
+                    _ if want == got => PIXEL_CHARS[got as usize & 3],
+                    (0, _) => '+',
+                    (_, 0) => '-',
+                    _ => '*',
+                }
+            })
+            .collect();
+        writeln!(text, "{}", line).unwrap();
+    }
+    text
+}
+
+impl Golden {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate().map(|(ind, line)| (ind + 1, line));
+        let error = |line: usize, message: &str| format!("{}: {}", line, message);
+        match lines.next() {
+            Some((_, line)) if line == HEADER => {}
+            _ => return Err(error(1, "not a golden file from this version")),
+        }
+        let mut golden = Golden {
+            rom: String::new(),
+            mode: Mode::default(),
+            quirks: Mode::default().default_quirks(),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            seed: 0,
+            frames: 0,
+            keys: Vec::new(),
+            registers: Vec::new(),
+            display: None,
+            settings: Vec::new(),
+        };
+        let mut quirks = None;
+        let mut frames = None;
+        let mut display_size = None;
+        for (line, text) in lines.by_ref() {
+            let (name, value) = text.split_once(' ').unwrap_or((text, ""));
+            match name {
+                "" => {}
+                _ if name.starts_with('#') => {}
+                "rom" => golden.rom = value.to_string(),
+                "mode" => {
+                    golden.mode =
+                        Mode::from_name(value).ok_or_else(|| error(line, "unknown mode"))?
+                }
+                "quirks" => {
+                    quirks = Some(
+                        Quirks::from_name(value)
+                            .ok_or_else(|| error(line, "unknown quirks profile"))?,
+                    )
+                }
+                "ipf" => {
+                    golden.instructions_per_frame = value
+                        .parse()
+                        .map_err(|_| error(line, "bad instructions per frame"))?
+                }
+                "seed" => golden.seed = value.parse().map_err(|_| error(line, "bad seed"))?,
+                "frames" => {
+                    frames = Some(value.parse().map_err(|_| error(line, "bad frame count"))?)
+                }
+                "keys" => {
+                    golden.keys = value
+                        .split_whitespace()
+                        .map(parse_key_hold)
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| error(line, "expected keys such as 10-20:5"))?
+                }
+                "registers" => {
+                    golden.registers = value
+                        .split_whitespace()
+                        .map(|register| {
+                            let (name, value) = match register.split_once('=') {
+                                Some((name, value)) => (name, Some(value)),
+                                None => (register, None),
+                            };
+                            if !is_register(name) {
+                                return Err(error(line, &format!("unknown register {}", name)));
+                            }
+                            let value = value
+                                .map(|value| u16::from_str_radix(value, 16))
+                                .transpose()
+                                .map_err(|_| error(line, &format!("bad value for {}", name)))?;
+                            Ok((name.to_string(), value))
+                        })
+                        .collect::<Result<_, _>>()?;
+                    continue;
+                }
+                "display" => {
+                    let size = value.split_once(' ').and_then(|(width, height)| {
+                        Some((width.parse().ok()?, height.parse().ok()?))
+                    });
+                    display_size =
+                        Some(size.ok_or_else(|| error(line, "expected a width and height"))?);
+                    break;
+                }
+                _ => return Err(error(line, &format!("unknown setting {}", name))),
+            }
+            golden.settings.push(text.to_string());
+        }
+        if golden.rom.is_empty() {
+            return Err(error(0, "expected the rom line"));
+        }
+        golden.frames = frames.ok_or_else(|| error(0, "expected the frames line"))?;
+        golden.quirks = quirks.unwrap_or_else(|| golden.mode.default_quirks());
+        if let Some((width, height)) = display_size {
+            let mut display = Display::new(width, height);
+            for y in 0..height {
+                let (line, text) = lines
+                    .next()
+                    .ok_or_else(|| error(0, &format!("expected {} display rows", height)))?;
+                if text.chars().count() != width as usize {
+                    return Err(error(line, &format!("expected {} pixels", width)));
+                }
+                for (x, pixel) in text.chars().enumerate() {
+                    let bit = PIXEL_CHARS
+                        .iter()
+                        .position(|c| *c == pixel)
+                        .ok_or_else(|| error(line, &format!("bad pixel {}", pixel)))?;
+                    display.set_pixel(x as u32, y, bit as u8);
+                }
+            }
+            golden.display = Some(display);
+        }
+        Ok(golden)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "{}", HEADER).unwrap();
+        for setting in self.settings.iter() {
+            writeln!(text, "{}", setting).unwrap();
+        }
+        if !self.registers.is_empty() {
+            let registers: Vec<String> = self
+                .registers
+                .iter()
+                .map(|(name, value)| match value {
+                    Some(value) => format_register(name, *value),
+                    None => name.clone(),
+                })
+                .collect();
+            writeln!(text, "registers {}", registers.join(" ")).unwrap();
+        }
+        if let Some(display) = &self.display {
+            text.push_str(&display_text(display));
+        }
+        text
+    }
+
+    pub fn rom_path(&self, golden_path: &Path) -> PathBuf {
+        golden_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(&self.rom)
+    }
+
+    // Held keys as keypad bits for a frame
+    fn keys_at(&self, frame: u32) -> u16 {
+        self.keys
+            .iter()
+            .filter(|hold| (hold.first..=hold.last).contains(&frame))
+            .fold(0, |bits, hold| bits | 1 << hold.key)
+    }
+
+    // Runs the ROM without a frontend for the golden's frames
+    pub fn run(&self, rom: &[u8]) -> Result<CPU, EmulatorError> {
+        let mut cpu = CPU::with_mode(rom, self.mode)?;
+        cpu.quirks = self.quirks;
+        cpu.instructions_per_frame = self.instructions_per_frame;
+        cpu.random = Random::seeded(self.seed);
+        for frame in 0..self.frames {
+            cpu.keypad.set_bits(self.keys_at(frame));
+            cpu.run_frame()?;
+        }
+        Ok(cpu)
+    }
+
+    // Describes how the machine differs from the golden, empty when it
+    // matches
+    pub fn mismatches(&self, cpu: &CPU) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        for (name, expected) in self.registers.iter() {
+            let found = register_value(cpu, name);
+            match expected {
+                None => mismatches.push(format!("{} has no expected value", name)),
+                Some(expected) if *expected != found => mismatches.push(format!(
+                    "{} instead of {}",
+                    format_register(name, found),
+                    format_register(name, *expected)
+                )),
+                Some(_) => {}
+            }
+        }
+        let actual = &cpu.display;
+        match &self.display {
+            None => mismatches.push("no expected display".to_string()),
+            Some(expected)
+                if (expected.width, expected.height) != (actual.width, actual.height) =>
+            {
+                mismatches.push(format!(
+                    "display is {}x{} instead of {}x{}",
+                    actual.width, actual.height, expected.width, expected.height
+                ))
+            }
+            Some(expected) => {
+                let differing = expected
+                    .pixels
+                    .iter()
+                    .zip(actual.pixels.iter())
+                    .filter(|(want, got)| want != got)
+                    .count();
+                if differing > 0 {
+                    mismatches.push(format!(
+                        "{} pixels differ, + only in this run, - only in the golden:\n{}",
+                        differing,
+                        diff_text(expected, actual).trim_end()
+                    ));
+                }
+            }
+        }
+        mismatches
+    }
+
+    // The golden with the registers and display the machine ended up with
+    pub fn updated(&self, cpu: &CPU) -> Golden {
+        let mut golden = self.clone();
+        for (name, value) in golden.registers.iter_mut() {
+            *value = Some(register_value(cpu, name));
+        }
+        let mut display = Display::new(cpu.display.width, cpu.display.height);
+        display.pixels = cpu.display.pixels.clone();
+        golden.display = Some(display);
+        golden
+    }
+
+    // PNG with the expected display, the actual one and the differences side
+    // by side. Pixels only the actual display has are green, ones only the
+    // expected display has are red.
+    pub fn diff_png(&self, cpu: &CPU) -> Vec<u8> {
+        let actual = &cpu.display;
+        let empty = Display::new(actual.width, actual.height);
+        let expected = self.display.as_ref().unwrap_or(&empty);
+        let panel_width = expected.width.max(actual.width);
+        let panel_height = expected.height.max(actual.height);
+        // A column of background between the panels
+        let width = (panel_width * 3 + 2) * PNG_SCALE;
+        let height = panel_height * PNG_SCALE;
+        let pixel = |display: &Display, x: u32, y: u32| {
+            if x < display.width && y < display.height {
+                display.get_pixel(x, y)
+            } else {
+                0
+            }
+        };
+        let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (panel, px, py) = (
+                    x / PNG_SCALE / (panel_width + 1),
+                    x / PNG_SCALE % (panel_width + 1),
+                    y / PNG_SCALE,
+                );
+                let color: [u8; 3] = if px == panel_width {
+                    [0x40, 0x40, 0x40]
+                } else {
+                    let (want, got) = (pixel(expected, px, py), pixel(actual, px, py));
+                    let lit = [0xFF, 0xFF, 0xFF];
+                    let dark = [0x00, 0x00, 0x00];
+                    match panel {
+                        0 if want != 0 => lit,
+                        1 if got != 0 => lit,
+                        2 if want == got && got != 0 => [0x60, 0x60, 0x60],
+                        2 if want != got && got != 0 => [0x00, 0xD0, 0x00],
+                        2 if want != got => [0xE0, 0x00, 0x00],
+                        _ => dark,
+                    }
+                };
+                rgb.extend_from_slice(&color);
+            }
+        }
+        png::encode_rgb(width, height, &rgb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::asm::assemble;
+    use std::fs;
+    const PROGRAM: &str = "
+        : main
+            v0 := 5
+            v1 := 0
+            i := hex v0
+            sprite v1 v1 5
+            v2 := key
+            v3 := 1
+        : loop
+            jump loop
+    ";
+    fn golden(body: &str) -> Golden {
+        Golden::parse(&format!("{}\nrom test.ch8\n{}", HEADER, body)).unwrap()
+    }
+    #[test]
+    fn parses_settings_and_display() {
+        let golden = golden("ipf 8\nframes 3\nkeys 1-2:5 4:a\nregisters pc v0=05\ndisplay 2 1\n#o");
+        assert_eq!(golden.instructions_per_frame, 8);
+        assert_eq!(golden.frames, 3);
+        assert_eq!(golden.keys_at(0), 0);
+        assert_eq!(golden.keys_at(2), 1 << 5);
+        assert_eq!(golden.keys_at(4), 1 << 0xA);
+        assert_eq!(
+            golden.registers,
+            vec![("pc".to_string(), None), ("v0".to_string(), Some(5))]
+        );
+        assert_eq!(golden.display.as_ref().unwrap().pixels, [1, 2]);
+        assert_eq!(
+            Golden::parse("chip8 golden 1\nrom a\nframes 1\nregisters vg=1"),
+            Err("4: unknown register vg".to_string())
+        );
+        assert_eq!(
+            Golden::parse("chip8 golden 1\nrom a\nframes 1\ndisplay 2 1\n#x"),
+            Err("5: bad pixel x".to_string())
+        );
+        assert_eq!(
+            Golden::parse("chip8 golden 1\nrom a"),
+            Err("0: expected the frames line".to_string())
+        );
+    }
+    #[test]
+    fn updates_and_then_matches() {
+        let rom = assemble(PROGRAM).unwrap();
+        let golden = golden("frames 4\nkeys 2:6\nregisters pc v2 v3");
+        let cpu = golden.run(&rom).unwrap();
+        assert_eq!(golden.mismatches(&cpu).len(), 4);
+        let updated = golden.updated(&cpu);
+        let text = updated.to_text();
+        assert!(text.contains("\nregisters pc=020C v2=06 v3=01\ndisplay 64 32\n####......."));
+        let reread = Golden::parse(&text).unwrap();
+        assert_eq!(reread, updated);
+        assert!(reread.mismatches(&reread.run(&rom).unwrap()).is_empty());
+    }
+    #[test]
+    fn describes_differences() {
+        let rom = assemble(PROGRAM).unwrap();
+        let golden = golden("frames 1\nregisters v0=04");
+        let mut cpu = golden.run(&rom).unwrap();
+        let mut expected = golden.updated(&cpu);
+        expected.registers[0].1 = Some(4);
+        cpu.display.set_pixel(5, 0, 1);
+        cpu.display.set_pixel(0, 0, 0);
+        let mismatches = expected.mismatches(&cpu);
+        assert_eq!(mismatches[0], "v0=05 instead of v0=04");
+        assert!(mismatches[1].starts_with("2 pixels differ"));
+        assert!(mismatches[1].contains("\n-###.+.."));
+        let png = expected.diff_png(&cpu);
+        assert_eq!(&png[1..4], b"PNG");
+    }
+    #[test]
+    fn checked_in_goldens_pass() {
+        let mut checked = 0;
+        for entry in fs::read_dir("roms/golden").unwrap() {
+            let path = entry.unwrap().path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "golden")
+            {
+                continue;
+            }
+            let golden = Golden::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+            let rom = fs::read(golden.rom_path(&path)).unwrap();
+            let cpu = golden.run(&rom).unwrap();
+            assert_eq!(golden.mismatches(&cpu), Vec::<String>::new(), "{:?}", path);
+            checked += 1;
+        }
+        assert!(checked >= 3);
+    }
+}
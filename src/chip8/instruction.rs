@@ -0,0 +1,452 @@
+use std::error::Error;
+use std::fmt;
+
+use super::cpu::Mode;
+
+// A decoded opcode. Field names follow the usual XNNN notation, x and y are
+// register indices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    // 00CN
+    ScrollDown { n: u8 },
+    // 00DN
+    ScrollUp { n: u8 },
+    // 00E0
+    Clear,
+    // 00EE
+    Return,
+    // 00FB
+    ScrollRight,
+    // 00FC
+    ScrollLeft,
+    // 00FD
+    Exit,
+    // 00FE
+    Lores,
+    // 00FF
+    Hires,
+    // 0NNN, a call into native COSMAC VIP machine code
+    MachineCall { nnn: u16 },
+    // 1NNN
+    Jump { nnn: u16 },
+    // 2NNN
+    Call { nnn: u16 },
+    // 3XNN
+    SkipEqImm { x: u8, nn: u8 },
+    // 4XNN
+    SkipNeqImm { x: u8, nn: u8 },
+    // 5XY0
+    SkipEq { x: u8, y: u8 },
+    // 5XY2
+    SaveRange { x: u8, y: u8 },
+    // 5XY3
+    LoadRange { x: u8, y: u8 },
+    // 6XNN
+    LoadImm { x: u8, nn: u8 },
+    // 7XNN
+    AddImm { x: u8, nn: u8 },
+    // 8XY0
+    Move { x: u8, y: u8 },
+    // 8XY1
+    Or { x: u8, y: u8 },
+    // 8XY2
+    And { x: u8, y: u8 },
+    // 8XY3
+    Xor { x: u8, y: u8 },
+    // 8XY4
+    Add { x: u8, y: u8 },
+    // 8XY5
+    Sub { x: u8, y: u8 },
+    // 8XY6
+    ShiftRight { x: u8, y: u8 },
+    // 8XY7
+    SubReverse { x: u8, y: u8 },
+    // 8XYE
+    ShiftLeft { x: u8, y: u8 },
+    // 9XY0
+    SkipNeq { x: u8, y: u8 },
+    // ANNN
+    LoadI { nnn: u16 },
+    // BNNN
+    JumpOffset { nnn: u16 },
+    // CXNN
+    Random { x: u8, nn: u8 },
+    // DXYN
+    Draw { x: u8, y: u8, n: u8 },
+    // EX9E
+    SkipKey { x: u8 },
+    // EXA1
+    SkipNotKey { x: u8 },
+    // F000 NNNN, the address is the word following the opcode
+    LoadILong,
+    // FN01
+    SelectPlanes { n: u8 },
+    // F002
+    LoadAudio,
+    // FX07
+    GetDelay { x: u8 },
+    // FX0A
+    WaitKey { x: u8 },
+    // FX15
+    SetDelay { x: u8 },
+    // FX18
+    SetSound { x: u8 },
+    // FX1E
+    AddI { x: u8 },
+    // FX29
+    LoadFont { x: u8 },
+    // FX30
+    LoadBigFont { x: u8 },
+    // FX33
+    Bcd { x: u8 },
+    // FX3A
+    SetPitch { x: u8 },
+    // FX55
+    Store { x: u8 },
+    // FX65
+    Load { x: u8 },
+    // FX75
+    SaveFlags { x: u8 },
+    // FX85
+    LoadFlags { x: u8 },
+}
+
+// Broad groups of instructions, for picking out the interesting ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionClass {
+    // Jumps, calls, returns and skips on registers
+    Flow,
+    // Register loads and arithmetic
+    Alu,
+    // I and everything that reads or writes memory through it
+    Memory,
+    Display,
+    // Key skips and waiting for a key
+    Input,
+    Timer,
+    Sound,
+}
+
+pub const CLASS_NAMES: [&str; 7] = [
+    "flow", "alu", "memory", "display", "input", "timer", "sound",
+];
+
+impl InstructionClass {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flow" => Some(InstructionClass::Flow),
+            "alu" => Some(InstructionClass::Alu),
+            "memory" => Some(InstructionClass::Memory),
+            "display" => Some(InstructionClass::Display),
+            "input" => Some(InstructionClass::Input),
+            "timer" => Some(InstructionClass::Timer),
+            "sound" => Some(InstructionClass::Sound),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown opcode {:04X}", self.opcode)
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+        let (op0, op1, op2, op3): (u8, u8, u8, u8) = (
+            ((opcode & 0xF000) >> 12) as u8,
+            ((opcode & 0x0F00) >> 8) as u8,
+            ((opcode & 0x00F0) >> 4) as u8,
+            (opcode & 0x000F) as u8,
+        );
+
+        let nnn = opcode & 0x0FFF;
+        let nn: u8 = (op2 << 4) | op3;
+        let n = op3;
+        let x = op1;
+        let y = op2;
+
+        let instruction = match (op0, op1, op2, op3) {
+            (0x0, 0x0, 0xC, _) => Instruction::ScrollDown { n },
+            (0x0, 0x0, 0xD, _) => Instruction::ScrollUp { n },
+            (0x0, 0x0, 0xE, 0x0) => Instruction::Clear,
+            (0x0, 0x0, 0xE, 0xE) => Instruction::Return,
+            (0x0, 0x0, 0xF, 0xB) => Instruction::ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => Instruction::ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Instruction::Exit,
+            (0x0, 0x0, 0xF, 0xE) => Instruction::Lores,
+            (0x0, 0x0, 0xF, 0xF) => Instruction::Hires,
+            (0x0, _, _, _) => Instruction::MachineCall { nnn },
+            (0x1, _, _, _) => Instruction::Jump { nnn },
+            (0x2, _, _, _) => Instruction::Call { nnn },
+            (0x3, _, _, _) => Instruction::SkipEqImm { x, nn },
+            (0x4, _, _, _) => Instruction::SkipNeqImm { x, nn },
+            (0x5, _, _, 0x0) => Instruction::SkipEq { x, y },
+            (0x5, _, _, 0x2) => Instruction::SaveRange { x, y },
+            (0x5, _, _, 0x3) => Instruction::LoadRange { x, y },
+            (0x6, _, _, _) => Instruction::LoadImm { x, nn },
+            (0x7, _, _, _) => Instruction::AddImm { x, nn },
+            (0x8, _, _, 0x0) => Instruction::Move { x, y },
+            (0x8, _, _, 0x1) => Instruction::Or { x, y },
+            (0x8, _, _, 0x2) => Instruction::And { x, y },
+            (0x8, _, _, 0x3) => Instruction::Xor { x, y },
+            (0x8, _, _, 0x4) => Instruction::Add { x, y },
+            (0x8, _, _, 0x5) => Instruction::Sub { x, y },
+            (0x8, _, _, 0x6) => Instruction::ShiftRight { x, y },
+            (0x8, _, _, 0x7) => Instruction::SubReverse { x, y },
+            (0x8, _, _, 0xE) => Instruction::ShiftLeft { x, y },
+            (0x9, _, _, 0x0) => Instruction::SkipNeq { x, y },
+            (0xA, _, _, _) => Instruction::LoadI { nnn },
+            (0xB, _, _, _) => Instruction::JumpOffset { nnn },
+            (0xC, _, _, _) => Instruction::Random { x, nn },
+            (0xD, _, _, _) => Instruction::Draw { x, y, n },
+            (0xE, _, 0x9, 0xE) => Instruction::SkipKey { x },
+            (0xE, _, 0xA, 0x1) => Instruction::SkipNotKey { x },
+            (0xF, 0x0, 0x0, 0x0) => Instruction::LoadILong,
+            (0xF, _, 0x0, 0x1) => Instruction::SelectPlanes { n: x },
+            (0xF, 0x0, 0x0, 0x2) => Instruction::LoadAudio,
+            (0xF, _, 0x0, 0x7) => Instruction::GetDelay { x },
+            (0xF, _, 0x0, 0xA) => Instruction::WaitKey { x },
+            (0xF, _, 0x1, 0x5) => Instruction::SetDelay { x },
+            (0xF, _, 0x1, 0x8) => Instruction::SetSound { x },
+            (0xF, _, 0x1, 0xE) => Instruction::AddI { x },
+            (0xF, _, 0x2, 0x9) => Instruction::LoadFont { x },
+            (0xF, _, 0x3, 0x0) => Instruction::LoadBigFont { x },
+            (0xF, _, 0x3, 0x3) => Instruction::Bcd { x },
+            (0xF, _, 0x3, 0xA) => Instruction::SetPitch { x },
+            (0xF, _, 0x5, 0x5) => Instruction::Store { x },
+            (0xF, _, 0x6, 0x5) => Instruction::Load { x },
+            (0xF, _, 0x7, 0x5) => Instruction::SaveFlags { x },
+            (0xF, _, 0x8, 0x5) => Instruction::LoadFlags { x },
+            _ => return Err(DecodeError { opcode }),
+        };
+        Ok(instruction)
+    }
+
+    pub fn encode(&self) -> u16 {
+        fn xy(base: u16, x: u8, y: u8) -> u16 {
+            base | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4)
+        }
+        fn xnn(base: u16, x: u8, nn: u8) -> u16 {
+            base | ((x as u16 & 0xF) << 8) | nn as u16
+        }
+        fn x_only(base: u16, x: u8) -> u16 {
+            base | ((x as u16 & 0xF) << 8)
+        }
+
+        match *self {
+            Instruction::ScrollDown { n } => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollUp { n } => 0x00D0 | (n as u16 & 0xF),
+            Instruction::Clear => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Lores => 0x00FE,
+            Instruction::Hires => 0x00FF,
+            Instruction::MachineCall { nnn } => nnn & 0x0FFF,
+            Instruction::Jump { nnn } => 0x1000 | (nnn & 0x0FFF),
+            Instruction::Call { nnn } => 0x2000 | (nnn & 0x0FFF),
+            Instruction::SkipEqImm { x, nn } => xnn(0x3000, x, nn),
+            Instruction::SkipNeqImm { x, nn } => xnn(0x4000, x, nn),
+            Instruction::SkipEq { x, y } => xy(0x5000, x, y),
+            Instruction::SaveRange { x, y } => xy(0x5002, x, y),
+            Instruction::LoadRange { x, y } => xy(0x5003, x, y),
+            Instruction::LoadImm { x, nn } => xnn(0x6000, x, nn),
+            Instruction::AddImm { x, nn } => xnn(0x7000, x, nn),
+            Instruction::Move { x, y } => xy(0x8000, x, y),
+            Instruction::Or { x, y } => xy(0x8001, x, y),
+            Instruction::And { x, y } => xy(0x8002, x, y),
+            Instruction::Xor { x, y } => xy(0x8003, x, y),
+            Instruction::Add { x, y } => xy(0x8004, x, y),
+            Instruction::Sub { x, y } => xy(0x8005, x, y),
+            Instruction::ShiftRight { x, y } => xy(0x8006, x, y),
+            Instruction::SubReverse { x, y } => xy(0x8007, x, y),
+            Instruction::ShiftLeft { x, y } => xy(0x800E, x, y),
+            Instruction::SkipNeq { x, y } => xy(0x9000, x, y),
+            Instruction::LoadI { nnn } => 0xA000 | (nnn & 0x0FFF),
+            Instruction::JumpOffset { nnn } => 0xB000 | (nnn & 0x0FFF),
+            Instruction::Random { x, nn } => xnn(0xC000, x, nn),
+            Instruction::Draw { x, y, n } => xy(0xD000, x, y) | (n as u16 & 0xF),
+            Instruction::SkipKey { x } => x_only(0xE09E, x),
+            Instruction::SkipNotKey { x } => x_only(0xE0A1, x),
+            Instruction::LoadILong => 0xF000,
+            Instruction::SelectPlanes { n } => x_only(0xF001, n),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::GetDelay { x } => x_only(0xF007, x),
+            Instruction::WaitKey { x } => x_only(0xF00A, x),
+            Instruction::SetDelay { x } => x_only(0xF015, x),
+            Instruction::SetSound { x } => x_only(0xF018, x),
+            Instruction::AddI { x } => x_only(0xF01E, x),
+            Instruction::LoadFont { x } => x_only(0xF029, x),
+            Instruction::LoadBigFont { x } => x_only(0xF030, x),
+            Instruction::Bcd { x } => x_only(0xF033, x),
+            Instruction::SetPitch { x } => x_only(0xF03A, x),
+            Instruction::Store { x } => x_only(0xF055, x),
+            Instruction::Load { x } => x_only(0xF065, x),
+            Instruction::SaveFlags { x } => x_only(0xF075, x),
+            Instruction::LoadFlags { x } => x_only(0xF085, x),
+        }
+    }
+
+    // The first mode that understands this instruction
+    pub fn required_mode(&self) -> Mode {
+        match self {
+            Instruction::ScrollDown { .. }
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::Lores
+            | Instruction::Hires
+            | Instruction::LoadBigFont { .. }
+            | Instruction::SaveFlags { .. }
+            | Instruction::LoadFlags { .. } => Mode::SuperChip,
+            Instruction::ScrollUp { .. }
+            | Instruction::SaveRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LoadILong
+            | Instruction::SelectPlanes { .. }
+            | Instruction::LoadAudio
+            | Instruction::SetPitch { .. } => Mode::XoChip,
+            _ => Mode::Chip8,
+        }
+    }
+
+    pub fn class(&self) -> InstructionClass {
+        match self {
+            Instruction::Return
+            | Instruction::Exit
+            | Instruction::MachineCall { .. }
+            | Instruction::Jump { .. }
+            | Instruction::Call { .. }
+            | Instruction::SkipEqImm { .. }
+            | Instruction::SkipNeqImm { .. }
+            | Instruction::SkipEq { .. }
+            | Instruction::SkipNeq { .. }
+            | Instruction::JumpOffset { .. } => InstructionClass::Flow,
+            Instruction::LoadImm { .. }
+            | Instruction::AddImm { .. }
+            | Instruction::Move { .. }
+            | Instruction::Or { .. }
+            | Instruction::And { .. }
+            | Instruction::Xor { .. }
+            | Instruction::Add { .. }
+            | Instruction::Sub { .. }
+            | Instruction::ShiftRight { .. }
+            | Instruction::SubReverse { .. }
+            | Instruction::ShiftLeft { .. }
+            | Instruction::Random { .. } => InstructionClass::Alu,
+            Instruction::SaveRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LoadI { .. }
+            | Instruction::LoadILong
+            | Instruction::AddI { .. }
+            | Instruction::LoadFont { .. }
+            | Instruction::LoadBigFont { .. }
+            | Instruction::Bcd { .. }
+            | Instruction::Store { .. }
+            | Instruction::Load { .. }
+            | Instruction::SaveFlags { .. }
+            | Instruction::LoadFlags { .. } => InstructionClass::Memory,
+            Instruction::ScrollDown { .. }
+            | Instruction::ScrollUp { .. }
+            | Instruction::Clear
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Lores
+            | Instruction::Hires
+            | Instruction::Draw { .. }
+            | Instruction::SelectPlanes { .. } => InstructionClass::Display,
+            Instruction::SkipKey { .. }
+            | Instruction::SkipNotKey { .. }
+            | Instruction::WaitKey { .. } => InstructionClass::Input,
+            Instruction::GetDelay { .. } | Instruction::SetDelay { .. } => InstructionClass::Timer,
+            Instruction::SetSound { .. }
+            | Instruction::LoadAudio
+            | Instruction::SetPitch { .. } => InstructionClass::Sound,
+        }
+    }
+
+    // Size in bytes, including the address word of F000 NNNN
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadILong => 4,
+            _ => 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn decodes_opcodes() {
+        assert_eq!(Instruction::decode(0x00E0), Ok(Instruction::Clear));
+        assert_eq!(
+            Instruction::decode(0x1ABC),
+            Ok(Instruction::Jump { nnn: 0xABC })
+        );
+        assert_eq!(
+            Instruction::decode(0x7A05),
+            Ok(Instruction::AddImm { x: 0xA, nn: 0x05 })
+        );
+        assert_eq!(
+            Instruction::decode(0xD12F),
+            Ok(Instruction::Draw { x: 1, y: 2, n: 0xF })
+        );
+        assert_eq!(
+            Instruction::decode(0xF301),
+            Ok(Instruction::SelectPlanes { n: 3 })
+        );
+        assert_eq!(
+            Instruction::decode(0x0123),
+            Ok(Instruction::MachineCall { nnn: 0x123 })
+        );
+    }
+    #[test]
+    fn rejects_unknown_opcodes() {
+        for opcode in [0x5001, 0x8008, 0x9001, 0xE000, 0xF100, 0xF0FF].iter() {
+            assert_eq!(
+                Instruction::decode(*opcode),
+                Err(DecodeError { opcode: *opcode })
+            );
+        }
+    }
+    #[test]
+    fn encodes_every_decodable_opcode_back() {
+        for opcode in 0..=0xFFFFu16 {
+            if let Ok(instruction) = Instruction::decode(opcode) {
+                assert_eq!(instruction.encode(), opcode);
+            }
+        }
+    }
+    #[test]
+    fn knows_required_mode() {
+        assert_eq!(Instruction::Clear.required_mode(), Mode::Chip8);
+        assert_eq!(Instruction::Hires.required_mode(), Mode::SuperChip);
+        assert_eq!(Instruction::LoadILong.required_mode(), Mode::XoChip);
+        assert!(Mode::SuperChip < Mode::XoChip);
+    }
+    #[test]
+    fn knows_class() {
+        assert_eq!(Instruction::Return.class(), InstructionClass::Flow);
+        assert_eq!(
+            Instruction::SkipKey { x: 0 }.class(),
+            InstructionClass::Input
+        );
+        assert_eq!(Instruction::Bcd { x: 0 }.class(), InstructionClass::Memory);
+        assert_eq!(
+            InstructionClass::from_name("display"),
+            Some(InstructionClass::Display)
+        );
+        assert_eq!(InstructionClass::from_name("draw"), None);
+    }
+}
@@ -0,0 +1,99 @@
+// COSMAC VIP hex keypad mapped onto the left side of a QWERTY keyboard
+//   1 2 3 C        1 2 3 4
+//   4 5 6 D   <=   Q W E R
+//   7 8 9 E        A S D F
+//   A 0 B F        Z X C V
+const LAYOUT: [(char, u8); 16] = [
+    ('1', 0x1),
+    ('2', 0x2),
+    ('3', 0x3),
+    ('4', 0xC),
+    ('Q', 0x4),
+    ('W', 0x5),
+    ('E', 0x6),
+    ('R', 0xD),
+    ('A', 0x7),
+    ('S', 0x8),
+    ('D', 0x9),
+    ('F', 0xE),
+    ('Z', 0xA),
+    ('X', 0x0),
+    ('C', 0xB),
+    ('V', 0xF),
+];
+
+// Returns the hex key bound to a keyboard character
+pub fn key_for_char(c: char) -> Option<u8> {
+    let c = c.to_ascii_uppercase();
+    LAYOUT
+        .iter()
+        .find(|(layout_char, _)| *layout_char == c)
+        .map(|(_, key)| *key)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Keypad {
+    pub keys: [bool; 16],
+}
+
+impl Keypad {
+    pub fn press(&mut self, key: u8) {
+        self.keys[(key & 0xF) as usize] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.keys[(key & 0xF) as usize] = false;
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys[(key & 0xF) as usize]
+    }
+
+    // Held keys as a bitmask, bit N for key N
+    pub fn bits(&self) -> u16 {
+        self.keys
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (key, held)| bits | (*held as u16) << key)
+    }
+
+    pub fn set_bits(&mut self, bits: u16) {
+        for (key, held) in self.keys.iter_mut().enumerate() {
+            *held = bits & (1 << key) != 0;
+        }
+    }
+
+    // Lowest key that is currently held down
+    pub fn first_pressed(&self) -> Option<u8> {
+        self.keys.iter().position(|key| *key).map(|key| key as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn maps_qwerty_layout() {
+        assert_eq!(key_for_char('1'), Some(0x1));
+        assert_eq!(key_for_char('4'), Some(0xC));
+        assert_eq!(key_for_char('w'), Some(0x5));
+        assert_eq!(key_for_char('X'), Some(0x0));
+        assert_eq!(key_for_char('V'), Some(0xF));
+        assert_eq!(key_for_char('P'), None);
+    }
+    #[test]
+    fn tracks_pressed_keys() {
+        let mut keypad = Keypad::default();
+        assert_eq!(keypad.first_pressed(), None);
+        keypad.press(0xB);
+        keypad.press(0x3);
+        assert!(keypad.is_pressed(0xB));
+        assert_eq!(keypad.first_pressed(), Some(0x3));
+        keypad.release(0x3);
+        assert_eq!(keypad.first_pressed(), Some(0xB));
+        assert_eq!(keypad.bits(), 0x0800);
+        keypad.set_bits(0x8001);
+        assert!(keypad.is_pressed(0x0) && keypad.is_pressed(0xF));
+        assert!(!keypad.is_pressed(0xB));
+    }
+}
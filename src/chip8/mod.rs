@@ -1,2 +1,22 @@
+pub mod asm;
+pub mod audio;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
 pub mod display;
+pub mod error;
+pub mod frontend;
+pub mod gdb;
+pub mod golden;
+pub mod instruction;
+pub mod keypad;
+pub mod movie;
+pub mod png;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod savestate;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+pub mod trace;
+pub mod tracediff;
@@ -0,0 +1,417 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write as _;
+
+use super::cpu::{Mode, OpcodePolicy, CPU};
+use super::error::EmulatorError;
+use super::quirks::{IndexIncrement, Quirks};
+use super::random::Random;
+use super::savestate;
+
+// How often the recording notes a hash of the machine state to check
+// replays against
+pub const CHECKPOINT_FRAMES: usize = 60;
+
+const HEADER: &str = "chip8 movie 1";
+
+// The keypad during one frame, and the state hash after it on checkpoint
+// frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub keys: u16,
+    pub state_hash: Option<u64>,
+}
+
+// Everything needed to repeat a run: the settings and initial random state
+// of the machine and the keypad of every frame. Saved as text, the settings
+// on a line each and then a line per frame with the held keys in hex and an
+// optional state hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub mode: Mode,
+    pub quirks: Quirks,
+    pub random: Random,
+    pub instructions_per_frame: u32,
+    pub opcode_policy: OpcodePolicy,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    Parse {
+        line: usize,
+        message: String,
+    },
+    WrongRom {
+        expected: u64,
+        found: u64,
+    },
+    // The replay no longer matches the recording from this frame on
+    Desync {
+        frame: usize,
+        expected: u64,
+        found: u64,
+    },
+    // The ROM doesn't load, or the machine failed during the replay
+    Machine(EmulatorError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => write!(f, "{}: {}", line, message),
+            MovieError::WrongRom { expected, found } => write!(
+                f,
+                "movie was recorded with a different ROM, hash {:016x} instead of {:016x}",
+                expected, found
+            ),
+            MovieError::Desync {
+                frame,
+                expected,
+                found,
+            } => write!(
+                f,
+                "desync at frame {}, state hash {:016x} instead of {:016x}",
+                frame, found, expected
+            ),
+            MovieError::Machine(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<EmulatorError> for MovieError {
+    fn from(e: EmulatorError) -> Self {
+        MovieError::Machine(e)
+    }
+}
+
+fn index_increment_name(increment: IndexIncrement) -> &'static str {
+    match increment {
+        IndexIncrement::Unchanged => "0",
+        IndexIncrement::ByX => "x",
+        IndexIncrement::ByXPlusOne => "x+1",
+    }
+}
+
+fn quirks_text(quirks: &Quirks) -> String {
+    format!(
+        "shift_uses_vy={} load_store_index={} jump_uses_vx={} logic_resets_vf={} clip_sprites={}",
+        quirks.shift_uses_vy as u8,
+        index_increment_name(quirks.load_store_index),
+        quirks.jump_uses_vx as u8,
+        quirks.logic_resets_vf as u8,
+        quirks.clip_sprites as u8
+    )
+}
+
+fn parse_quirks(text: &str) -> Option<Quirks> {
+    let mut quirks = Quirks::default();
+    let mut seen = 0;
+    for setting in text.split_whitespace() {
+        let (name, value) = setting.split_once('=')?;
+        let flag = match value {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None,
+        };
+        match name {
+            "shift_uses_vy" => quirks.shift_uses_vy = flag?,
+            "jump_uses_vx" => quirks.jump_uses_vx = flag?,
+            "logic_resets_vf" => quirks.logic_resets_vf = flag?,
+            "clip_sprites" => quirks.clip_sprites = flag?,
+            "load_store_index" => {
+                quirks.load_store_index = match value {
+                    "0" => IndexIncrement::Unchanged,
+                    "x" => IndexIncrement::ByX,
+                    "x+1" => IndexIncrement::ByXPlusOne,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+        seen += 1;
+    }
+    if seen == 5 {
+        Some(quirks)
+    } else {
+        None
+    }
+}
+
+impl Movie {
+    // Starts an empty movie from the machine as it is about to run
+    pub fn new(cpu: &CPU) -> Self {
+        Movie {
+            rom_hash: cpu.rom_hash,
+            mode: cpu.mode,
+            quirks: cpu.quirks,
+            random: cpu.random,
+            instructions_per_frame: cpu.instructions_per_frame,
+            opcode_policy: cpu.opcode_policy,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "{}", HEADER).unwrap();
+        writeln!(text, "rom {:016x}", self.rom_hash).unwrap();
+        writeln!(text, "mode {}", self.mode.name()).unwrap();
+        writeln!(text, "quirks {}", quirks_text(&self.quirks)).unwrap();
+        writeln!(text, "random {}", self.random.state).unwrap();
+        writeln!(text, "ipf {}", self.instructions_per_frame).unwrap();
+        writeln!(text, "unknown {}", self.opcode_policy.name()).unwrap();
+        writeln!(text, "frames").unwrap();
+        for frame in self.frames.iter() {
+            match frame.state_hash {
+                Some(hash) => writeln!(text, "{:04x} {:016x}", frame.keys, hash).unwrap(),
+                None => writeln!(text, "{:04x}", frame.keys).unwrap(),
+            }
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(ind, line)| (ind + 1, line))
+            .peekable();
+        let mut setting = |name: &str| {
+            let (line, text) = lines.next().unwrap_or((0, ""));
+            let error = |message: String| MovieError::Parse { line, message };
+            match text.split_once(' ') {
+                Some((found, value)) if found == name => Ok((value.to_string(), line)),
+                _ => Err(error(format!("expected the {} line", name))),
+            }
+        };
+        let error = |line: usize, message: &str| MovieError::Parse {
+            line,
+            message: message.to_string(),
+        };
+        let (header, line) = setting("chip8")?;
+        if format!("chip8 {}", header) != HEADER {
+            return Err(error(line, "not a movie from this version"));
+        }
+        let (rom, line) = setting("rom")?;
+        let rom_hash = u64::from_str_radix(&rom, 16).map_err(|_| error(line, "bad ROM hash"))?;
+        let (mode, line) = setting("mode")?;
+        let mode = Mode::from_name(&mode).ok_or_else(|| error(line, "unknown mode"))?;
+        let (quirks, line) = setting("quirks")?;
+        let quirks = parse_quirks(&quirks).ok_or_else(|| error(line, "bad quirks"))?;
+        let (random, line) = setting("random")?;
+        let random = random
+            .parse()
+            .map(Random::seeded)
+            .map_err(|_| error(line, "bad random generator"))?;
+        let (ipf, line) = setting("ipf")?;
+        let instructions_per_frame = ipf
+            .parse()
+            .map_err(|_| error(line, "bad instructions per frame"))?;
+        // Movies recorded before there was a choice always halted
+        let opcode_policy = match lines.peek() {
+            Some(&(line, text)) if text.starts_with("unknown ") => {
+                lines.next();
+                OpcodePolicy::from_name(&text["unknown ".len()..])
+                    .ok_or_else(|| error(line, "unknown opcode policy"))?
+            }
+            _ => OpcodePolicy::Halt,
+        };
+        match lines.next() {
+            Some((_, "frames")) => {}
+            Some((line, _)) => return Err(error(line, "expected the frames line")),
+            None => return Err(error(0, "expected the frames line")),
+        }
+        let frames = lines
+            .map(|(line, text)| {
+                let mut fields = text.split(' ');
+                let keys = fields
+                    .next()
+                    .and_then(|keys| u16::from_str_radix(keys, 16).ok());
+                let state_hash = fields.next().map(|hash| u64::from_str_radix(hash, 16).ok());
+                match (keys, state_hash, fields.next()) {
+                    (Some(keys), None, None) => Ok(Frame {
+                        keys,
+                        state_hash: None,
+                    }),
+                    (Some(keys), Some(Some(hash)), None) => Ok(Frame {
+                        keys,
+                        state_hash: Some(hash),
+                    }),
+                    _ => Err(error(line, "bad frame")),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Movie {
+            rom_hash,
+            mode,
+            quirks,
+            random,
+            instructions_per_frame,
+            opcode_policy,
+            frames,
+        })
+    }
+
+    // Sets up a machine for the ROM the way it was when recording started
+    pub fn machine(&self, rom: &[u8]) -> Result<CPU, MovieError> {
+        let mut cpu = CPU::with_mode(rom, self.mode)?;
+        if cpu.rom_hash != self.rom_hash {
+            return Err(MovieError::WrongRom {
+                expected: self.rom_hash,
+                found: cpu.rom_hash,
+            });
+        }
+        cpu.quirks = self.quirks;
+        cpu.random = self.random;
+        cpu.instructions_per_frame = self.instructions_per_frame;
+        cpu.opcode_policy = self.opcode_policy;
+        Ok(cpu)
+    }
+
+    // Plays the frames back without a frontend, stopping at the first
+    // checkpoint whose state hash doesn't match. Returns the frames played.
+    pub fn replay(&self, cpu: &mut CPU) -> Result<usize, MovieError> {
+        for (ind, frame) in self.frames.iter().enumerate() {
+            cpu.keypad.set_bits(frame.keys);
+            cpu.run_frame()?;
+            if let Some(expected) = frame.state_hash {
+                let found = savestate::state_hash(cpu);
+                if found != expected {
+                    return Err(MovieError::Desync {
+                        frame: ind,
+                        expected,
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(self.frames.len())
+    }
+}
+
+// Builds a movie from a live run, frame by frame
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    pub movie: Movie,
+}
+
+impl Recorder {
+    pub fn new(cpu: &CPU) -> Self {
+        Recorder {
+            movie: Movie::new(cpu),
+        }
+    }
+
+    // Notes the keypad of a frame that just ran, along with the state hash
+    // on checkpoint frames
+    pub fn record_frame(&mut self, cpu: &CPU) {
+        let frames = &mut self.movie.frames;
+        let state_hash = if (frames.len() + 1).is_multiple_of(CHECKPOINT_FRAMES) {
+            Some(savestate::state_hash(cpu))
+        } else {
+            None
+        };
+        frames.push(Frame {
+            keys: cpu.keypad.bits(),
+            state_hash,
+        });
+    }
+
+    // Forgets the last frame, for frames undone by rewinding
+    pub fn drop_last_frame(&mut self) {
+        self.movie.frames.pop();
+    }
+
+    // The finished movie, with a checkpoint on the last frame so the end of
+    // the run is always checked
+    pub fn finish(mut self, cpu: &CPU) -> Movie {
+        if let Some(last) = self.movie.frames.last_mut() {
+            last.state_hash = Some(savestate::state_hash(cpu));
+        }
+        self.movie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::asm::assemble;
+    // Draws a random digit while key 5 is held and counts the frames in v4
+    const PROGRAM: &str = "
+        : main
+            v4 += 1
+            v1 := 5
+            if v1 -key then jump main
+            v0 := random 0xF
+            i := hex v0
+            clear
+            sprite v2 v3 5
+            v2 += 3
+            jump main
+    ";
+    fn record(frames: usize) -> (CPU, Movie) {
+        let rom = assemble(PROGRAM).unwrap();
+        let mut cpu = CPU::new(&rom).unwrap();
+        cpu.random = Random::seeded(99);
+        let mut recorder = Recorder::new(&cpu);
+        for frame in 0..frames {
+            cpu.keypad.set_bits(if frame % 7 < 3 { 1 << 5 } else { 0 });
+            cpu.run_frame().unwrap();
+            recorder.record_frame(&cpu);
+        }
+        let movie = recorder.finish(&cpu);
+        (cpu, movie)
+    }
+    #[test]
+    fn replays_the_recorded_run() {
+        let (cpu, movie) = record(150);
+        assert_eq!(movie.frames.len(), 150);
+        assert!(movie.frames[59].state_hash.is_some());
+        assert!(movie.frames[60].state_hash.is_none());
+        let mut replayed = movie.machine(&assemble(PROGRAM).unwrap()).unwrap();
+        assert_eq!(movie.replay(&mut replayed), Ok(150));
+        assert_eq!(replayed.display.pixels, cpu.display.pixels);
+        assert_eq!(replayed.v_reg, cpu.v_reg);
+    }
+    #[test]
+    fn round_trips_through_text() {
+        let (_, movie) = record(70);
+        let text = movie.to_text();
+        assert!(text.starts_with("chip8 movie 1\nrom "));
+        assert!(text.contains("\nmode chip8\nquirks shift_uses_vy=1 load_store_index=x+1"));
+        assert!(text.contains("\nrandom 99\nipf "));
+        assert!(text.contains("\nunknown halt\nframes\n"));
+        assert_eq!(Movie::parse(&text), Ok(movie.clone()));
+        let without_policy = text.replace("\nunknown halt\n", "\n");
+        assert_eq!(Movie::parse(&without_policy), Ok(movie));
+        assert_eq!(
+            Movie::parse("chip8 movie 1\nrom 12\nmode chip9\n"),
+            Err(MovieError::Parse {
+                line: 3,
+                message: "unknown mode".to_string()
+            })
+        );
+    }
+    #[test]
+    fn detects_desyncs_and_other_roms() {
+        let (_, mut movie) = record(130);
+        // Different input up to the first checkpoint sends the program down
+        // another path
+        for frame in movie.frames[..CHECKPOINT_FRAMES].iter_mut() {
+            frame.keys ^= 1 << 5;
+        }
+        let mut cpu = movie.machine(&assemble(PROGRAM).unwrap()).unwrap();
+        match movie.replay(&mut cpu) {
+            Err(MovieError::Desync { frame, .. }) => assert_eq!(frame, 59),
+            result => panic!("expected a desync, got {:?}", result),
+        }
+        assert!(matches!(
+            movie.machine(&[0x12, 0x00]),
+            Err(MovieError::WrongRom { .. })
+        ));
+    }
+}
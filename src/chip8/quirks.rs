@@ -0,0 +1,99 @@
+// How FX55/FX65 leave the index register behind them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    Unchanged,
+    // CHIP-48 is off by one
+    ByX,
+    ByXPlusOne,
+}
+
+// Interpretations of the opcodes that differ between CHIP-8 implementations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    pub load_store_index: IndexIncrement,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // Sprites are cut off at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+}
+
+pub const PRESET_NAMES: [&str; 4] = ["vip", "chip48", "schip", "modern"];
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::cosmac_vip()
+    }
+}
+
+impl Quirks {
+    // The original interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_index: IndexIncrement::ByXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_index: IndexIncrement::ByX,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    // SUPER-CHIP 1.1
+    pub fn superchip() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_index: IndexIncrement::Unchanged,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    // What Octo and XO-CHIP programs expect
+    pub fn modern() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_index: IndexIncrement::ByXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::superchip()),
+            "modern" => Some(Quirks::modern()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn looks_up_presets_by_name() {
+        for name in PRESET_NAMES.iter() {
+            assert!(Quirks::from_name(name).is_some());
+        }
+        assert_eq!(Quirks::from_name("schip"), Some(Quirks::superchip()));
+        assert_eq!(Quirks::from_name("xo"), None);
+    }
+}
@@ -0,0 +1,42 @@
+// Random number generator kept in the machine state, so a run can be
+// reproduced from its seed and save states resume the same sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Random {
+    pub state: u64,
+}
+
+impl Default for Random {
+    // Seeded from the system, for runs that don't need to be repeatable
+    fn default() -> Self {
+        Random::seeded(rand::random())
+    }
+}
+
+impl Random {
+    pub fn seeded(seed: u64) -> Self {
+        Random { state: seed }
+    }
+
+    // SplitMix64, small and fast with a state that fits in a save state
+    pub fn next_byte(&mut self) -> u8 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        ((z ^ (z >> 31)) >> 56) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn repeats_the_sequence_of_a_seed() {
+        let bytes = |seed| {
+            let mut random = Random::seeded(seed);
+            (0..8).map(|_| random.next_byte()).collect::<Vec<u8>>()
+        };
+        assert_eq!(bytes(1), bytes(1));
+        assert_ne!(bytes(1), bytes(2));
+    }
+}
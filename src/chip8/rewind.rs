@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+
+use super::cpu::{CPU, TIMER_HZ};
+use super::savestate;
+
+// Ten seconds of frames
+pub const DEFAULT_REWIND_FRAMES: usize = 10 * TIMER_HZ as usize;
+
+// Turns one snapshot into the other. Consecutive frames mostly differ in a
+// few registers and pixels, so storing the XOR of the two as runs of
+// unchanged bytes and literal changed ones keeps each frame small.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Delta {
+    // Snapshots of different sizes, such as across a resolution change
+    Full(Vec<u8>),
+    // Pairs of an unchanged run length and a literal run, the runs as LEB128
+    // numbers and the literals as the XOR of both snapshots
+    Xor(Vec<u8>),
+}
+
+fn push_number(out: &mut Vec<u8>, mut number: usize) {
+    loop {
+        let byte = (number & 0x7F) as u8;
+        number >>= 7;
+        if number == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_number(data: &[u8], at: &mut usize) -> usize {
+    let mut number = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*at];
+        *at += 1;
+        number |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return number;
+        }
+        shift += 7;
+    }
+}
+
+impl Delta {
+    fn between(from: &[u8], to: &[u8]) -> Self {
+        if from.len() != to.len() {
+            return Delta::Full(to.to_vec());
+        }
+        let mut out = Vec::new();
+        let mut ind = 0;
+        while ind < from.len() {
+            let unchanged = from[ind..]
+                .iter()
+                .zip(&to[ind..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            let start = ind + unchanged;
+            let changed = from[start..]
+                .iter()
+                .zip(&to[start..])
+                .take_while(|(a, b)| a != b)
+                .count();
+            if changed == 0 {
+                break;
+            }
+            push_number(&mut out, unchanged);
+            push_number(&mut out, changed);
+            out.extend(
+                from[start..start + changed]
+                    .iter()
+                    .zip(&to[start..start + changed])
+                    .map(|(a, b)| a ^ b),
+            );
+            ind = start + changed;
+        }
+        Delta::Xor(out)
+    }
+
+    fn apply(&self, from: &[u8]) -> Vec<u8> {
+        let runs = match self {
+            Delta::Full(to) => return to.clone(),
+            Delta::Xor(runs) => runs,
+        };
+        let mut to = from.to_vec();
+        let (mut at, mut ind) = (0, 0);
+        while at < runs.len() {
+            ind += read_number(runs, &mut at);
+            let changed = read_number(runs, &mut at);
+            for byte in to[ind..ind + changed].iter_mut() {
+                *byte ^= runs[at];
+                at += 1;
+            }
+            ind += changed;
+        }
+        to
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Delta::Full(data) | Delta::Xor(data) => data.len(),
+        }
+    }
+}
+
+// Ring buffer of the last frames, stored as the newest full snapshot and a
+// delta back to each frame before it. Stepping back applies the newest delta,
+// dropping the oldest frame just forgets its delta.
+#[derive(Debug, Default)]
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+impl Rewind {
+    // Keeps up to `capacity` frames to step back to, 0 disables rewinding
+    pub fn new(capacity: usize) -> Self {
+        Rewind {
+            capacity,
+            ..Rewind::default()
+        }
+    }
+
+    // Frames that can be stepped back
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // Bytes held by the snapshot and the deltas
+    pub fn memory_used(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len)
+            + self.deltas.iter().map(Delta::size).sum::<usize>()
+    }
+
+    // Records the state at the end of a frame
+    pub fn capture(&mut self, cpu: &CPU) {
+        if self.capacity == 0 {
+            return;
+        }
+        let state = savestate::save(cpu);
+        if let Some(latest) = self.latest.take() {
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(Delta::between(&state, &latest));
+        }
+        self.latest = Some(state);
+    }
+
+    // Goes back to the previous captured frame, returns false once there are
+    // none left
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        let (delta, latest) = match (self.deltas.pop_back(), &self.latest) {
+            (Some(delta), Some(latest)) => (delta, latest),
+            _ => return false,
+        };
+        let previous = delta.apply(latest);
+        // Snapshots come from this machine, so they always match its ROM
+        savestate::restore(cpu, &previous).expect("rewind snapshot failed to restore");
+        self.latest = Some(previous);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::asm::assemble;
+    const PROGRAM: &str = "
+        : main
+            v0 += 1
+            i := hex v0
+            clear
+            sprite v1 v1 5
+            v1 += 1
+            jump main
+    ";
+    #[test]
+    fn steps_back_through_captured_frames() {
+        let mut cpu = CPU::new(&assemble(PROGRAM).unwrap()).unwrap();
+        cpu.instructions_per_frame = 3;
+        let mut rewind = Rewind::new(100);
+        let mut frames = Vec::new();
+        for _ in 0..20 {
+            rewind.capture(&cpu);
+            frames.push(savestate::save(&cpu));
+            cpu.run_frame().unwrap();
+        }
+        rewind.capture(&cpu);
+        assert_eq!(rewind.len(), 20);
+        for frame in frames.iter().rev() {
+            assert!(rewind.step_back(&mut cpu));
+            assert_eq!(&savestate::save(&cpu), frame);
+        }
+        assert!(!rewind.step_back(&mut cpu));
+        assert_eq!(cpu.prog_counter, 0x200);
+    }
+    #[test]
+    fn forgets_the_oldest_frames_and_stays_small() {
+        let mut cpu = CPU::new(&assemble(PROGRAM).unwrap()).unwrap();
+        let mut rewind = Rewind::new(50);
+        for _ in 0..200 {
+            cpu.run_frame().unwrap();
+            rewind.capture(&cpu);
+        }
+        assert_eq!(rewind.len(), 50);
+        let snapshot = savestate::save(&cpu).len();
+        assert!(rewind.memory_used() < snapshot * 2);
+        let mut disabled = Rewind::new(0);
+        disabled.capture(&cpu);
+        assert!(!disabled.step_back(&mut cpu));
+        assert_eq!(disabled.memory_used(), 0);
+    }
+    #[test]
+    fn deltas_turn_one_snapshot_into_the_other() {
+        let from = [1, 2, 3, 4, 5, 6, 7, 8];
+        let to = [1, 9, 9, 4, 5, 6, 7, 0];
+        let delta = Delta::between(&from, &to);
+        assert_eq!(delta, Delta::Xor(vec![1, 2, 2 ^ 9, 3 ^ 9, 4, 1, 8]));
+        assert_eq!(delta.apply(&from), to);
+        assert_eq!(Delta::between(&from, &from), Delta::Xor(vec![]));
+        assert_eq!(
+            Delta::between(&from, &to[..4]),
+            Delta::Full(to[..4].to_vec())
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::cpu::{Mode, CPU};
+use super::display::Display;
+use super::random::Random;
+
+// A state file is the magic, the format version, the hash of the ROM it was
+// saved from and then a list of tagged chunks, each with a big endian u32
+// length. Readers skip chunks they don't know and fall back to defaults for
+// optional chunks that are missing, so adding a chunk doesn't need a new
+// version. Changing the layout of an existing chunk does, along with a step
+// in `migrate`.
+const MAGIC: &[u8; 4] = b"CH8S";
+pub const VERSION: u16 = 1;
+
+const REGISTERS: &[u8; 4] = b"REGS";
+const MEMORY: &[u8; 4] = b"MEM ";
+const DISPLAY: &[u8; 4] = b"DISP";
+const MODE: &[u8; 4] = b"MODE";
+const RPL_FLAGS: &[u8; 4] = b"FLAG";
+const AUDIO: &[u8; 4] = b"AUDI";
+const CONTROL: &[u8; 4] = b"CTRL";
+const RANDOM: &[u8; 4] = b"RAND";
+
+// Save state hotkeys, slots are numbered from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotCommand {
+    Save(u8),
+    Load(u8),
+}
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    NotASaveState,
+    UnsupportedVersion(u16),
+    WrongRom { expected: u64, found: u64 },
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::Io(e) => write!(f, "{}", e),
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} isn't supported, this build reads up to version {}",
+                version, VERSION
+            ),
+            SaveStateError::WrongRom { expected, found } => write!(
+                f,
+                "save state belongs to a different ROM, hash {:016x} instead of {:016x}",
+                found, expected
+            ),
+            SaveStateError::Corrupt(what) => write!(f, "save state is corrupt, {}", what),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> Self {
+        SaveStateError::Io(e)
+    }
+}
+
+// 64 bit FNV-1a, unlike the std hashers it is guaranteed to stay the same
+// across releases
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// Hash of the whole machine state, used to check replays stay in sync
+pub fn state_hash(cpu: &CPU) -> u64 {
+    rom_hash(&save(cpu))
+}
+
+type Chunks = BTreeMap<[u8; 4], Vec<u8>>;
+
+fn push_chunk(state: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    state.extend_from_slice(tag);
+    state.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    state.extend_from_slice(data);
+}
+
+fn mode_number(mode: Mode) -> u8 {
+    match mode {
+        Mode::Chip8 => 0,
+        Mode::SuperChip => 1,
+        Mode::XoChip => 2,
+    }
+}
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut state = Vec::new();
+    state.extend_from_slice(MAGIC);
+    state.extend_from_slice(&VERSION.to_be_bytes());
+    state.extend_from_slice(&cpu.rom_hash.to_be_bytes());
+
+    let mut registers = cpu.v_reg.to_vec();
+    registers.extend_from_slice(&cpu.i_reg.to_be_bytes());
+    registers.extend_from_slice(&cpu.prog_counter.to_be_bytes());
+    registers.push(cpu.stack_ptr);
+    for address in cpu.stack.iter() {
+        registers.extend_from_slice(&address.to_be_bytes());
+    }
+    registers.push(cpu.delay_reg);
+    registers.push(cpu.sound_reg);
+    push_chunk(&mut state, REGISTERS, &registers);
+
+    push_chunk(&mut state, MODE, &[mode_number(cpu.mode)]);
+    push_chunk(&mut state, MEMORY, &cpu.memory);
+
+    let mut display = Vec::new();
+    display.extend_from_slice(&(cpu.display.width as u16).to_be_bytes());
+    display.extend_from_slice(&(cpu.display.height as u16).to_be_bytes());
+    display.push(cpu.display.planes);
+    display.extend_from_slice(&cpu.display.pixels);
+    push_chunk(&mut state, DISPLAY, &display);
+
+    push_chunk(&mut state, RPL_FLAGS, &cpu.rpl_flags);
+    let mut audio = match cpu.audio_buffer {
+        Some(pattern) => [&[1][..], &pattern[..]].concat(),
+        None => vec![0; 17],
+    };
+    audio.push(cpu.audio_pitch);
+    push_chunk(&mut state, AUDIO, &audio);
+    // 0xFF stands for no key being waited on
+    push_chunk(
+        &mut state,
+        CONTROL,
+        &[cpu.halted as u8, cpu.waiting_key.unwrap_or(0xFF)],
+    );
+    push_chunk(&mut state, RANDOM, &cpu.random.state.to_be_bytes());
+    state
+}
+
+fn read_chunks(mut data: &[u8]) -> Result<Chunks, SaveStateError> {
+    let mut chunks = Chunks::new();
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err(SaveStateError::Corrupt("a chunk header is cut off"));
+        }
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&data[..4]);
+        let length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let chunk = data
+            .get(8..8 + length)
+            .ok_or(SaveStateError::Corrupt("a chunk is cut off"))?;
+        chunks.insert(tag, chunk.to_vec());
+        data = &data[8 + length..];
+    }
+    Ok(chunks)
+}
+
+// Upgrades the chunks of an older version to the current layout. Version 1
+// is the first format, so there is nothing to upgrade yet.
+fn migrate(version: u16, chunks: Chunks) -> Result<Chunks, SaveStateError> {
+    match version {
+        VERSION => Ok(chunks),
+        _ => Err(SaveStateError::UnsupportedVersion(version)),
+    }
+}
+
+fn required<'a>(chunks: &'a Chunks, tag: &[u8; 4]) -> Result<&'a [u8], SaveStateError> {
+    chunks
+        .get(tag)
+        .map(Vec::as_slice)
+        .ok_or(SaveStateError::Corrupt("a required chunk is missing"))
+}
+
+fn word(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+// Replaces the machine state with a saved one. Settings such as the quirks
+// and the instruction rate stay as they are, and nothing changes when the
+// state can't be loaded.
+pub fn restore(cpu: &mut CPU, data: &[u8]) -> Result<(), SaveStateError> {
+    if data.len() < 14 || &data[..4] != MAGIC {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let version = word(data, 4);
+    if version == 0 || version > VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&data[6..14]);
+    let found = u64::from_be_bytes(hash);
+    if found != cpu.rom_hash {
+        return Err(SaveStateError::WrongRom {
+            expected: cpu.rom_hash,
+            found,
+        });
+    }
+    let chunks = migrate(version, read_chunks(&data[14..])?)?;
+    let mut restored = cpu.clone();
+
+    let registers = required(&chunks, REGISTERS)?;
+    if registers.len() != 55 {
+        return Err(SaveStateError::Corrupt("the registers have the wrong size"));
+    }
+    restored.v_reg.copy_from_slice(&registers[..16]);
+    restored.i_reg = word(registers, 16);
+    restored.prog_counter = word(registers, 18);
+    restored.stack_ptr = registers[20];
+    for (ind, address) in restored.stack.iter_mut().enumerate() {
+        *address = word(registers, 21 + ind * 2);
+    }
+    restored.delay_reg = registers[53];
+    restored.sound_reg = registers[54];
+    if restored.stack_ptr as usize > restored.stack.len() {
+        return Err(SaveStateError::Corrupt("the stack pointer is out of range"));
+    }
+
+    restored.mode = match chunks.get(MODE).map(Vec::as_slice) {
+        Some([0]) => Mode::Chip8,
+        Some([1]) => Mode::SuperChip,
+        Some([2]) => Mode::XoChip,
+        Some(_) => return Err(SaveStateError::Corrupt("the mode is unknown")),
+        None => cpu.mode,
+    };
+    let memory = required(&chunks, MEMORY)?;
+    if memory.len() != restored.mode.memory_size() {
+        return Err(SaveStateError::Corrupt("the memory doesn't match the mode"));
+    }
+    restored.memory = memory.to_vec();
+
+    let display = required(&chunks, DISPLAY)?;
+    if display.len() < 5 {
+        return Err(SaveStateError::Corrupt("the display is cut off"));
+    }
+    let (width, height) = (word(display, 0) as u32, word(display, 2) as u32);
+    if display.len() - 5 != (width * height) as usize {
+        return Err(SaveStateError::Corrupt("the display has the wrong size"));
+    }
+    restored.display = Display::new(width, height);
+    restored.display.planes = display[4];
+    restored.display.pixels = display[5..].to_vec();
+
+    if let Some(flags) = chunks.get(RPL_FLAGS) {
+        if flags.len() != restored.rpl_flags.len() {
+            return Err(SaveStateError::Corrupt("the flags have the wrong size"));
+        }
+        restored.rpl_flags.copy_from_slice(flags);
+    }
+    if let Some(audio) = chunks.get(AUDIO) {
+        if audio.len() != 18 {
+            return Err(SaveStateError::Corrupt("the audio has the wrong size"));
+        }
+        let mut pattern = [0; 16];
+        pattern.copy_from_slice(&audio[1..17]);
+        restored.audio_buffer = if audio[0] != 0 { Some(pattern) } else { None };
+        restored.audio_pitch = audio[17];
+    }
+    if let Some(control) = chunks.get(CONTROL) {
+        if control.len() != 2 {
+            return Err(SaveStateError::Corrupt(
+                "the control state has the wrong size",
+            ));
+        }
+        restored.halted = control[0] != 0;
+        restored.waiting_key = if control[1] == 0xFF {
+            None
+        } else {
+            Some(control[1])
+        };
+    }
+    // States saved before the generator was part of the machine keep the
+    // current one
+    if let Some(random) = chunks.get(RANDOM) {
+        if random.len() != 8 {
+            return Err(SaveStateError::Corrupt(
+                "the random generator has the wrong size",
+            ));
+        }
+        let mut seed = [0; 8];
+        seed.copy_from_slice(random);
+        restored.random = Random::seeded(u64::from_be_bytes(seed));
+    }
+    *cpu = restored;
+    Ok(())
+}
+
+// Slots are stored next to the ROM, `pong.ch8` saves slot 1 to `pong.state1`
+pub fn slot_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("state{}", slot))
+}
+
+pub fn save_slot(cpu: &CPU, rom_path: &Path, slot: u8) -> Result<PathBuf, SaveStateError> {
+    let path = slot_path(rom_path, slot);
+    fs::write(&path, save(cpu))?;
+    Ok(path)
+}
+
+pub fn load_slot(cpu: &mut CPU, rom_path: &Path, slot: u8) -> Result<PathBuf, SaveStateError> {
+    let path = slot_path(rom_path, slot);
+    restore(cpu, &fs::read(&path)?)?;
+    Ok(path)
+}
+
+// Carries out a hotkey from the run loop, slots need the ROM path to be set
+pub fn run_slot_command(cpu: &mut CPU, command: SlotCommand) {
+    let rom_path = match cpu.rom_path.clone() {
+        Some(rom_path) => rom_path,
+        None => return,
+    };
+    match command {
+        SlotCommand::Save(slot) => match save_slot(cpu, &rom_path, slot) {
+            Ok(path) => println!("Saved slot {} to {}", slot, path.display()),
+            Err(e) => eprintln!("Could not save slot {}: {}", slot, e),
+        },
+        SlotCommand::Load(slot) => match load_slot(cpu, &rom_path, slot) {
+            Ok(path) => println!("Loaded slot {} from {}", slot, path.display()),
+            Err(e) => eprintln!("Could not load slot {}: {}", slot, e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::asm::assemble;
+    fn running_cpu() -> CPU {
+        let rom = assemble(
+            "
+            : main
+                hires
+                v3 := 42
+                i := 0x300
+                save v3
+                sprite v0 v0 0
+                :call spin
+            : spin
+                jump spin
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::with_mode(&rom, Mode::SuperChip).unwrap();
+        for _ in 0..7 {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+    #[test]
+    fn restores_the_saved_machine() {
+        let cpu = running_cpu();
+        let state = save(&cpu);
+        let mut restored = CPU::new(&[]).unwrap();
+        restored.rom_hash = cpu.rom_hash;
+        restore(&mut restored, &state).unwrap();
+        assert_eq!(restored.mode, Mode::SuperChip);
+        assert_eq!(restored.v_reg, cpu.v_reg);
+        assert_eq!(restored.i_reg, cpu.i_reg);
+        assert_eq!(restored.prog_counter, cpu.prog_counter);
+        assert_eq!(restored.stack_ptr, 1);
+        assert_eq!(restored.stack, cpu.stack);
+        assert_eq!(restored.memory, cpu.memory);
+        assert!(restored.display.is_hires());
+        assert_eq!(restored.display.pixels, cpu.display.pixels);
+        assert_eq!(restored.random, cpu.random);
+        assert_eq!(save(&restored), state);
+    }
+    #[test]
+    fn rejects_other_roms_and_broken_states() {
+        let cpu = running_cpu();
+        let state = save(&cpu);
+        let mut other = CPU::new(&[0x12, 0x00]).unwrap();
+        let pc = other.prog_counter;
+        match restore(&mut other, &state) {
+            Err(SaveStateError::WrongRom { expected, found }) => {
+                assert_eq!(expected, rom_hash(&[0x12, 0x00]));
+                assert_eq!(found, cpu.rom_hash);
+            }
+            result => panic!("expected a wrong ROM error, got {:?}", result),
+        }
+        assert_eq!(other.prog_counter, pc);
+
+        let mut same = cpu.clone();
+        let mut newer = state.clone();
+        newer[5] = 9;
+        assert_eq!(
+            restore(&mut same, &newer).unwrap_err().to_string(),
+            "save state version 9 isn't supported, this build reads up to version 1"
+        );
+        assert!(matches!(
+            restore(&mut same, &state[..state.len() - 1]),
+            Err(SaveStateError::Corrupt(_))
+        ));
+        assert!(matches!(
+            restore(&mut same, b"not a state at all"),
+            Err(SaveStateError::NotASaveState)
+        ));
+    }
+    #[test]
+    fn skips_unknown_chunks_and_defaults_optional_ones() {
+        let cpu = running_cpu();
+        let mut state = save(&cpu);
+        push_chunk(&mut state, b"NEW!", &[1, 2, 3]);
+        let mut restored = cpu.clone();
+        restored.v_reg[3] = 0;
+        restore(&mut restored, &state).unwrap();
+        assert_eq!(restored.v_reg[3], 42);
+
+        // A state with only the required chunks
+        let chunks = read_chunks(&save(&cpu)[14..]).unwrap();
+        let mut minimal = save(&cpu)[..14].to_vec();
+        for tag in [REGISTERS, MEMORY, DISPLAY].iter() {
+            push_chunk(&mut minimal, tag, &chunks[*tag]);
+        }
+        restore(&mut restored, &minimal).unwrap();
+        assert_eq!(restored.memory, cpu.memory);
+    }
+}
@@ -0,0 +1,398 @@
+use sdl2::{
+    audio::{AudioCallback, AudioDevice, AudioSpecDesired},
+    render::Canvas,
+    video::Window,
+};
+use sdl2::{
+    event::Event,
+    keyboard::{Keycode, Mod, Scancode},
+    pixels::Color,
+    rect::Rect,
+    render::{BlendMode, TextureCreator},
+    video::WindowContext,
+    Sdl,
+};
+use std::fmt;
+
+use super::audio::{AudioConfig, AudioPattern, Buzzer};
+use super::debugger::Command;
+use super::display::Display;
+use super::error::EmulatorError;
+use super::frontend::Frontend;
+use super::keypad::{self, Keypad};
+use super::savestate::SlotCommand;
+
+// Colour for each combination of the two XO-CHIP planes
+const PALETTE: [Color; 4] = [
+    Color::BLACK,
+    Color::YELLOW,
+    Color::RGB(0xFF, 0x66, 0x00),
+    Color::RGB(0x66, 0x22, 0x00),
+];
+
+// Debugger overlay text is drawn from a 3x5 font at this scale
+const TEXT_SCALE: i32 = 2;
+const CHAR_WIDTH: i32 = 4 * TEXT_SCALE;
+const LINE_HEIGHT: i32 = 6 * TEXT_SCALE;
+
+// Rows of a 3x5 glyph, the low three bits of each byte, left pixel first
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ' ' => [0; 5],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '*' => [0b000, 0b101, 0b010, 0b101, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '|' => [0b010, 0b010, 0b010, 0b010, 0b010],
+        '&' => [0b010, 0b101, 0b010, 0b101, 0b011],
+        '^' => [0b010, 0b101, 0b000, 0b000, 0b000],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+        ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '~' => [0b000, 0b011, 0b110, 0b000, 0b000],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+pub struct SdlFrontend {
+    pub width: u32,
+    pub height: u32,
+    pub sdl_ctx: Sdl,
+    pub canvas: Canvas<Window>,
+    pub texture_creator: TextureCreator<WindowContext>,
+    // None when muted or when no audio device could be opened
+    audio_device: Option<AudioDevice<Buzzer>>,
+    buzzer: bool,
+    // Debugger hotkeys pressed since the debugger last asked
+    debug_commands: Vec<Command>,
+    slot_commands: Vec<SlotCommand>,
+    // Backspace plays the game backwards while held
+    rewind_held: bool,
+}
+
+impl AudioCallback for Buzzer {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.fill(out);
+    }
+}
+
+impl SdlFrontend {
+    pub fn new(width: u32, height: u32, audio_config: AudioConfig) -> Result<Self, EmulatorError> {
+        let (sdl_ctx, canvas, texture_creator) =
+            SdlFrontend::init_sdl(width, height).map_err(EmulatorError::Frontend)?;
+        let audio_device = if audio_config.muted {
+            None
+        } else {
+            SdlFrontend::init_audio(&sdl_ctx, audio_config)
+        };
+        Ok(SdlFrontend {
+            width,
+            height,
+            sdl_ctx,
+            canvas,
+            texture_creator,
+            audio_device,
+            buzzer: false,
+            debug_commands: Vec::new(),
+            slot_commands: Vec::new(),
+            rewind_held: false,
+        })
+    }
+
+    // A missing audio device shouldn't stop the emulator, it just stays silent
+    fn init_audio(sdl_ctx: &Sdl, audio_config: AudioConfig) -> Option<AudioDevice<Buzzer>> {
+        let desired_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None,
+        };
+        let audio_subsystem = sdl_ctx
+            .audio()
+            .map_err(|e| eprintln!("Could not initialize audio: {}", e))
+            .ok()?;
+        audio_subsystem
+            .open_playback(None, &desired_spec, |spec| {
+                Buzzer::new(audio_config, spec.freq as u32)
+            })
+            .map_err(|e| eprintln!("Could not open audio device: {}", e))
+            .ok()
+    }
+
+    fn init_sdl(
+        width: u32,
+        height: u32,
+    ) -> Result<(Sdl, Canvas<Window>, TextureCreator<WindowContext>), String> {
+        let sdl_context = sdl2::init()?;
+        let video_subsystem = sdl_context.video()?;
+        let window = video_subsystem
+            .window("Chip-8 Emulator", width, height)
+            .position_centered()
+            .opengl()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let tex_creator = canvas.texture_creator();
+        canvas.set_draw_color(Color::GREEN);
+        Ok((sdl_context, canvas, tex_creator))
+    }
+
+    fn update(&mut self, keypad: &mut Keypad) -> bool {
+        for event in self.sdl_ctx.event_pump().unwrap().poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => {
+                    return true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewind_held = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewind_held = false,
+                // Holding a hotkey down shouldn't fire it again, only rewind
+                // acts for as long as its key is held
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    scancode,
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(command) = SdlFrontend::debug_command(keycode, keymod) {
+                        self.debug_commands.push(command);
+                    } else if let Some(command) = SdlFrontend::slot_command(keycode, keymod) {
+                        self.slot_commands.push(command);
+                    } else if let Some(key) = scancode.and_then(SdlFrontend::hex_key) {
+                        keypad.press(key);
+                    }
+                }
+                Event::KeyUp {
+                    scancode: Some(scancode),
+                    ..
+                } => {
+                    if let Some(key) = SdlFrontend::hex_key(scancode) {
+                        keypad.release(key);
+                    }
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    // Goes by where the key sits rather than what it types, scancodes are
+    // named after the QWERTY key in the same place
+    fn hex_key(scancode: Scancode) -> Option<u8> {
+        let name = scancode.name();
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => keypad::key_for_char(c),
+            _ => None,
+        }
+    }
+
+    fn debug_command(keycode: Keycode, keymod: Mod) -> Option<Command> {
+        let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+        match keycode {
+            Keycode::F5 => Some(Command::TogglePause),
+            Keycode::F10 => Some(Command::StepOver),
+            Keycode::F11 if shift => Some(Command::StepOut),
+            Keycode::F11 => Some(Command::Step),
+            _ => None,
+        }
+    }
+
+    // F1 to F4 load a save state slot, with Shift they save to it
+    fn slot_command(keycode: Keycode, keymod: Mod) -> Option<SlotCommand> {
+        let slot = match keycode {
+            Keycode::F1 => 1,
+            Keycode::F2 => 2,
+            Keycode::F3 => 3,
+            Keycode::F4 => 4,
+            _ => return None,
+        };
+        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+            Some(SlotCommand::Save(slot))
+        } else {
+            Some(SlotCommand::Load(slot))
+        }
+    }
+
+    fn draw(&mut self, display: &Display) {
+        self.render(display);
+        self.canvas.present();
+    }
+
+    // Draws the framebuffer to the canvas without presenting it
+    fn render(&mut self, display: &Display) {
+        let mut texture = self
+            .texture_creator
+            .create_texture_target(
+                self.texture_creator.default_pixel_format(),
+                self.width,
+                self.height,
+            )
+            .unwrap();
+        let x_scale = self.width / display.width;
+        let y_scale = self.height / display.height;
+        self.canvas
+            .with_texture_canvas(&mut texture, |texture_canvas| {
+                texture_canvas.set_draw_color(PALETTE[0]);
+                texture_canvas.clear();
+                for (ind, pixel) in display.pixels.iter().enumerate() {
+                    if *pixel != 0 {
+                        texture_canvas.set_draw_color(PALETTE[(*pixel & 0b11) as usize]);
+                        texture_canvas
+                            .fill_rect(Rect::new(
+                                (ind % display.width as usize) as i32 * (x_scale as i32),
+                                (ind / display.width as usize) as i32 * (y_scale as i32),
+                                x_scale,
+                                y_scale,
+                            ))
+                            .unwrap();
+                    }
+                }
+            })
+            .unwrap();
+
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None).unwrap();
+    }
+
+    fn draw_text(&mut self, x: i32, y: i32, text: &str) {
+        for (column, c) in text.chars().enumerate() {
+            let left = x + column as i32 * CHAR_WIDTH;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for bit in 0..3 {
+                    if bits & (0b100 >> bit) != 0 {
+                        self.canvas
+                            .fill_rect(Rect::new(
+                                left + bit * TEXT_SCALE,
+                                y + row as i32 * TEXT_SCALE,
+                                TEXT_SCALE as u32,
+                                TEXT_SCALE as u32,
+                            ))
+                            .unwrap();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Frontend for SdlFrontend {
+    fn present(&mut self, display: &Display) {
+        self.draw(display);
+    }
+
+    fn poll_input(&mut self, keypad: &mut Keypad) -> bool {
+        self.update(keypad)
+    }
+
+    fn present_overlay(&mut self, display: &Display, lines: &[String]) {
+        self.render(display);
+        // Darken the game behind the text so it stays readable
+        self.canvas.set_blend_mode(BlendMode::Blend);
+        self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 0xC0));
+        self.canvas
+            .fill_rect(Rect::new(0, 0, self.width, self.height))
+            .unwrap();
+        self.canvas.set_draw_color(Color::WHITE);
+        for (row, line) in lines.iter().enumerate() {
+            self.draw_text(TEXT_SCALE, TEXT_SCALE + row as i32 * LINE_HEIGHT, line);
+        }
+        self.canvas.set_blend_mode(BlendMode::None);
+        self.canvas.present();
+    }
+
+    fn debug_commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.debug_commands)
+    }
+
+    fn slot_commands(&mut self) -> Vec<SlotCommand> {
+        std::mem::take(&mut self.slot_commands)
+    }
+
+    fn rewind_held(&self) -> bool {
+        self.rewind_held
+    }
+
+    fn set_buzzer(&mut self, on: bool) {
+        if on == self.buzzer {
+            return;
+        }
+        if let Some(device) = &self.audio_device {
+            if on {
+                device.resume();
+            } else {
+                device.pause();
+            }
+        }
+        self.buzzer = on;
+    }
+
+    fn set_audio_pattern(&mut self, pattern: Option<AudioPattern>) {
+        if let Some(device) = &mut self.audio_device {
+            device.lock().set_pattern(pattern);
+        }
+    }
+}
+
+impl fmt::Debug for SdlFrontend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SDL Frontend").finish()
+    }
+}
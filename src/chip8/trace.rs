@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::cpu::CPU;
+use super::disasm::{self, Syntax};
+use super::instruction::{Instruction, InstructionClass};
+
+// Starts binary traces, followed by the version as a big endian u16
+pub const BINARY_MAGIC: &[u8; 4] = b"CH8T";
+pub const BINARY_VERSION: u16 = 1;
+// Cycle, PC, opcode, V0-VF, I and SP of each entry
+pub const BINARY_ENTRY_SIZE: usize = 8 + 2 + 2 + 16 + 2 + 1;
+
+// The registers other interpreters usually log, enough to spot where two
+// runs part ways
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+}
+
+impl Registers {
+    pub fn of(cpu: &CPU) -> Self {
+        Registers {
+            v: cpu.v_reg,
+            i: cpu.i_reg,
+            sp: cpu.stack_ptr,
+        }
+    }
+}
+
+// One executed instruction and the registers after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    // Instructions executed before this one
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    // The word after the opcode, the address of F000 NNNN
+    pub long_operand: u16,
+    pub after: Registers,
+}
+
+impl TraceEntry {
+    // Line of a text trace in the format trace-diff reads: cycle, PC, opcode,
+    // V0-VF, I and SP, with the disassembly in a trailing comment
+    pub fn to_text(&self) -> String {
+        let text = match Instruction::decode(self.opcode) {
+            Ok(instruction) => disasm::format_instruction(
+                &instruction,
+                self.long_operand,
+                Syntax::Octo,
+                &BTreeMap::new(),
+            ),
+            Err(_) => "unknown".to_string(),
+        };
+        let v: Vec<String> = self.after.v.iter().map(|v| format!("{:02X}", v)).collect();
+        format!(
+            "{:>8} {:04X} {:04X} {} {:04X} {:X}  # {}",
+            self.cycle,
+            self.pc,
+            self.opcode,
+            v.join(" "),
+            self.after.i,
+            self.after.sp,
+            text
+        )
+    }
+
+    // Fixed size big endian record of a binary trace, with the registers
+    // after the instruction
+    pub fn to_binary(&self) -> [u8; BINARY_ENTRY_SIZE] {
+        let mut out = [0; BINARY_ENTRY_SIZE];
+        out[..8].copy_from_slice(&self.cycle.to_be_bytes());
+        out[8..10].copy_from_slice(&self.pc.to_be_bytes());
+        out[10..12].copy_from_slice(&self.opcode.to_be_bytes());
+        out[12..28].copy_from_slice(&self.after.v);
+        out[28..30].copy_from_slice(&self.after.i.to_be_bytes());
+        out[30] = self.after.sp;
+        out
+    }
+}
+
+// An instruction as read back from a trace, with the registers after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub registers: Registers,
+}
+
+// Reads back a binary trace
+pub fn read_binary(data: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    if data.len() < 6 || &data[..4] != BINARY_MAGIC {
+        return Err("not a binary trace".to_string());
+    }
+    let version = u16::from_be_bytes([data[4], data[5]]);
+    if version != BINARY_VERSION {
+        return Err(format!("binary trace version {} isn't supported", version));
+    }
+    let records = &data[6..];
+    if !records.len().is_multiple_of(BINARY_ENTRY_SIZE) {
+        return Err("the last record is cut off".to_string());
+    }
+    Ok(records
+        .chunks(BINARY_ENTRY_SIZE)
+        .map(|record| {
+            let mut cycle = [0; 8];
+            cycle.copy_from_slice(&record[..8]);
+            let mut v = [0; 16];
+            v.copy_from_slice(&record[12..28]);
+            TraceRecord {
+                cycle: u64::from_be_bytes(cycle),
+                pc: u16::from_be_bytes([record[8], record[9]]),
+                opcode: u16::from_be_bytes([record[10], record[11]]),
+                registers: Registers {
+                    v,
+                    i: u16::from_be_bytes([record[28], record[29]]),
+                    sp: record[30],
+                },
+            }
+        })
+        .collect())
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+pub const TRACE_FORMAT_NAMES: [&str; 2] = ["text", "binary"];
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "binary" => Some(TraceFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+// Which instructions make it into the trace, all of them by default
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TraceFilter {
+    // Inclusive range of addresses
+    pub range: Option<(u16, u16)>,
+    // Classes to keep, empty keeps every class. Unknown opcodes have no
+    // class and only show up when this is empty.
+    pub classes: Vec<InstructionClass>,
+}
+
+impl TraceFilter {
+    pub fn accepts(&self, entry: &TraceEntry) -> bool {
+        let in_range = match self.range {
+            Some((start, end)) => (start..=end).contains(&entry.pc),
+            None => true,
+        };
+        let in_class = self.classes.is_empty()
+            || Instruction::decode(entry.opcode)
+                .is_ok_and(|instruction| self.classes.contains(&instruction.class()));
+        in_range && in_class
+    }
+}
+
+// Writes the entries the run loop collects from the machine
+pub struct Tracer {
+    format: TraceFormat,
+    filter: TraceFilter,
+    out: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(
+        format: TraceFormat,
+        filter: TraceFilter,
+        mut out: Box<dyn Write>,
+    ) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            out.write_all(BINARY_MAGIC)?;
+            out.write_all(&BINARY_VERSION.to_be_bytes())?;
+        }
+        Ok(Tracer {
+            format,
+            filter,
+            out,
+        })
+    }
+
+    pub fn create(path: &str, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Tracer::new(format, filter, Box::new(file))
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        if !self.filter.accepts(entry) {
+            return Ok(());
+        }
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", entry.to_text()),
+            TraceFormat::Binary => self.out.write_all(&entry.to_binary()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::asm::assemble;
+    use crate::chip8::tracediff;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    // Keeps what the tracer wrote reachable after it takes the writer
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    fn trace(format: TraceFormat, filter: TraceFilter) -> Vec<u8> {
+        let program = "
+            : main
+                v0 := 5
+                i := 0x300
+                sub
+                jump main
+            : sub
+                v0 += 1
+                return
+        ";
+        let mut cpu = CPU::new(&assemble(program).unwrap()).unwrap();
+        cpu.trace_log = Some(Vec::new());
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        let out = Shared::default();
+        let mut tracer = Tracer::new(format, filter, Box::new(out.clone())).unwrap();
+        for entry in cpu.trace_log.take().unwrap() {
+            tracer.record(&entry).unwrap();
+        }
+        tracer.flush().unwrap();
+        let bytes = out.0.borrow().clone();
+        bytes
+    }
+    #[test]
+    fn writes_text_lines_trace_diff_reads() {
+        let text = String::from_utf8(trace(TraceFormat::Text, TraceFilter::default())).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            "       0 0200 6005 05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0000 0  # v0 := 0x05"
+        );
+        assert_eq!(
+            lines[2],
+            "       2 0204 2208 05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0300 1  # :call 0x208"
+        );
+        let binary = read_binary(&trace(TraceFormat::Binary, TraceFilter::default())).unwrap();
+        assert_eq!(tracediff::parse_common(&text), Ok(binary));
+    }
+    #[test]
+    fn filters_by_range_and_class() {
+        let filter = TraceFilter {
+            range: Some((0x202, 0x20A)),
+            classes: vec![InstructionClass::Flow, InstructionClass::Memory],
+        };
+        let text = String::from_utf8(trace(TraceFormat::Text, filter)).unwrap();
+        let pcs: Vec<&str> = text.lines().map(|line| &line[9..13]).collect();
+        assert_eq!(pcs, ["0202", "0204", "020A", "0206"]);
+    }
+    #[test]
+    fn writes_fixed_size_binary_records() {
+        let data = trace(TraceFormat::Binary, TraceFilter::default());
+        assert_eq!(&data[..4], BINARY_MAGIC);
+        assert_eq!(data.len(), 6 + 6 * BINARY_ENTRY_SIZE);
+        let record = &data[6 + 3 * BINARY_ENTRY_SIZE..6 + 4 * BINARY_ENTRY_SIZE];
+        assert_eq!(record[..8], 3u64.to_be_bytes());
+        assert_eq!(record[8..12], [0x02, 0x08, 0x70, 0x01]);
+        assert_eq!(record[12], 6);
+        assert_eq!(record[28..31], [0x03, 0x00, 1]);
+        let records = read_binary(&data).unwrap();
+        assert_eq!(records.len(), 6);
+        assert_eq!(
+            records[3],
+            TraceRecord {
+                cycle: 3,
+                pc: 0x208,
+                opcode: 0x7001,
+                registers: Registers {
+                    v: [6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    i: 0x300,
+                    sp: 1
+                }
+            }
+        );
+        assert!(read_binary(&data[..data.len() - 1]).is_err());
+        assert!(read_binary(b"0200 6005").is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use std::fmt::Write as _;
+
+use super::trace::{self, Registers, TraceRecord, BINARY_MAGIC};
+
+// Parses the plain format other interpreters can be made to log, and text
+// traces from --trace: a line per instruction with the PC, opcode, V0-VF, I
+// and SP in hex, the registers as they are after the instruction. An extra
+// leading number is the cycle in decimal, otherwise the line order is.
+// Anything after a # is a comment, blank lines are skipped.
+pub fn parse_common(text: &str) -> Result<Vec<TraceRecord>, String> {
+    let mut records = Vec::new();
+    for (ind, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("{}: {}", ind + 1, message);
+        let mut fields: Vec<&str> = line.split_whitespace().collect();
+        let cycle = match fields.len() {
+            20 => records.len() as u64,
+            21 => fields
+                .remove(0)
+                .parse()
+                .map_err(|_| error("expected a decimal cycle"))?,
+            _ => return Err(error("expected PC, opcode, V0-VF, I and SP")),
+        };
+        // Values wider than their register are a mistake, not something to
+        // cut down to size
+        let hex = |field: &str, name: &str, max: u16| {
+            let digits = field.strip_prefix("0x").unwrap_or(field);
+            match u64::from_str_radix(digits, 16) {
+                Ok(value) if value <= max as u64 => Ok(value as u16),
+                Ok(_) => Err(error(&format!("{} {} is out of range", name, field))),
+                Err(_) => Err(error(&format!("expected a hex number for {}", name))),
+            }
+        };
+        let mut v = [0; 16];
+        for (register, (field, name)) in v
+            .iter_mut()
+            .zip(fields[2..18].iter().zip(REGISTER_NAMES.iter()))
+        {
+            *register = hex(field, name, 0xFF)? as u8;
+        }
+        records.push(TraceRecord {
+            cycle,
+            pc: hex(fields[0], "PC", 0xFFFF)?,
+            opcode: hex(fields[1], "opcode", 0xFFFF)?,
+            registers: Registers {
+                v,
+                i: hex(fields[18], "I", 0xFFFF)?,
+                sp: hex(fields[19], "SP", 0xFF)? as u8,
+            },
+        });
+    }
+    Ok(records)
+}
+
+const REGISTER_NAMES: [&str; 16] = [
+    "V0", "V1", "V2", "V3", "V4", "V5", "V6", "V7", "V8", "V9", "VA", "VB", "VC", "VD", "VE", "VF",
+];
+
+// Reads a binary trace from this emulator or a trace in the common format
+pub fn read_any(data: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    if data.starts_with(BINARY_MAGIC) {
+        return trace::read_binary(data);
+    }
+    let text = std::str::from_utf8(data).map_err(|_| "neither a binary nor a text trace")?;
+    parse_common(text)
+}
+
+// Where two traces first disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub cycle: u64,
+    // Positions of the differing records in each trace
+    pub ours: usize,
+    pub theirs: usize,
+    // Such as `v3 05 != 04`
+    pub differences: Vec<String>,
+}
+
+fn differences(ours: &TraceRecord, theirs: &TraceRecord) -> Vec<String> {
+    let mut differences = Vec::new();
+    if ours.pc != theirs.pc {
+        differences.push(format!("pc {:04X} != {:04X}", ours.pc, theirs.pc));
+    }
+    if ours.opcode != theirs.opcode {
+        differences.push(format!(
+            "opcode {:04X} != {:04X}",
+            ours.opcode, theirs.opcode
+        ));
+    }
+    let (ours, theirs) = (&ours.registers, &theirs.registers);
+    for ind in 0..16 {
+        if ours.v[ind] != theirs.v[ind] {
+            differences.push(format!(
+                "v{:x} {:02X} != {:02X}",
+                ind, ours.v[ind], theirs.v[ind]
+            ));
+        }
+    }
+    if ours.i != theirs.i {
+        differences.push(format!("i {:04X} != {:04X}", ours.i, theirs.i));
+    }
+    if ours.sp != theirs.sp {
+        differences.push(format!("sp {:X} != {:X}", ours.sp, theirs.sp));
+    }
+    differences
+}
+
+// Pairs the records by cycle and finds the first pair that differs. Cycles
+// only one trace has, such as ones filtered out of it, are passed over.
+// Returns the divergence if any and how many pairs matched before it.
+pub fn first_divergence(
+    ours: &[TraceRecord],
+    theirs: &[TraceRecord],
+) -> (Option<Divergence>, usize) {
+    let (mut ind_ours, mut ind_theirs, mut matched) = (0, 0, 0);
+    while ind_ours < ours.len() && ind_theirs < theirs.len() {
+        let (a, b) = (&ours[ind_ours], &theirs[ind_theirs]);
+        if a.cycle < b.cycle {
+            ind_ours += 1;
+        } else if a.cycle > b.cycle {
+            ind_theirs += 1;
+        } else {
+            let differences = differences(a, b);
+            if !differences.is_empty() {
+                let divergence = Divergence {
+                    cycle: a.cycle,
+                    ours: ind_ours,
+                    theirs: ind_theirs,
+                    differences,
+                };
+                return (Some(divergence), matched);
+            }
+            matched += 1;
+            ind_ours += 1;
+            ind_theirs += 1;
+        }
+    }
+    (None, matched)
+}
+
+fn record_line(label: &str, record: &TraceRecord) -> String {
+    let registers = &record.registers;
+    let v: Vec<String> = registers.v.iter().map(|v| format!("{:02X}", v)).collect();
+    format!(
+        "{:<7}{:>8} {:04X} {:04X}  {}  {:04X} {:X}",
+        label,
+        record.cycle,
+        record.pc,
+        record.opcode,
+        v.join(" "),
+        registers.i,
+        registers.sp
+    )
+}
+
+// Describes the first divergence with the records leading up to it, or says
+// how far the traces agree
+pub fn report(ours: &[TraceRecord], theirs: &[TraceRecord], context: usize) -> String {
+    let mut out = String::new();
+    let (divergence, matched) = first_divergence(ours, theirs);
+    let divergence = match divergence {
+        Some(divergence) => divergence,
+        None => {
+            writeln!(out, "No divergence in {} matching instructions", matched).unwrap();
+            if ours.len() != theirs.len() {
+                writeln!(
+                    out,
+                    "Ours has {} records and theirs {}",
+                    ours.len(),
+                    theirs.len()
+                )
+                .unwrap();
+            }
+            return out;
+        }
+    };
+    writeln!(
+        out,
+        "First divergence at cycle {} after {} matching instructions",
+        divergence.cycle, matched
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "{:<7}{:>8} {:<4} {:<4}  {}  {:<4} SP",
+        "",
+        "cycle",
+        "PC",
+        "OP",
+        (0..16)
+            .map(|ind| format!("V{:X}", ind))
+            .collect::<Vec<_>>()
+            .join(" "),
+        "I"
+    )
+    .unwrap();
+    for record in ours[divergence.ours.saturating_sub(context)..divergence.ours].iter() {
+        writeln!(out, "{}", record_line("", record)).unwrap();
+    }
+    writeln!(out, "{}", record_line("ours", &ours[divergence.ours])).unwrap();
+    writeln!(out, "{}", record_line("theirs", &theirs[divergence.theirs])).unwrap();
+    writeln!(out, "Differs in {}", divergence.differences.join(", ")).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn record(cycle: u64, pc: u16, v0: u8) -> TraceRecord {
+        let mut v = [0; 16];
+        v[0] = v0;
+        TraceRecord {
+            cycle,
+            pc,
+            opcode: 0x7001,
+            registers: Registers { v, i: 0x300, sp: 0 },
+        }
+    }
+    #[test]
+    fn parses_the_common_format() {
+        let text = "
+            # pc op v0-vf i sp
+            0200 6005 05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0000 0
+            10 0x0202 A300 05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0300 0
+        ";
+        let records = parse_common(text).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].cycle, 0);
+        assert_eq!(records[0].registers.v[0], 5);
+        assert_eq!(records[1].cycle, 10);
+        assert_eq!(records[1].pc, 0x202);
+        assert_eq!(records[1].registers.i, 0x300);
+        assert_eq!(
+            parse_common("0200 6005 05"),
+            Err("1: expected PC, opcode, V0-VF, I and SP".to_string())
+        );
+        assert_eq!(
+            parse_common("0200 zz"),
+            Err("1: expected PC, opcode, V0-VF, I and SP".to_string())
+        );
+        let bad_hex = "0200 6005 zz 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0000 0";
+        assert_eq!(
+            parse_common(bad_hex),
+            Err("1: expected a hex number for V0".to_string())
+        );
+        assert_eq!(
+            parse_common(&bad_hex.replace("zz", "105")),
+            Err("1: V0 105 is out of range".to_string())
+        );
+        assert_eq!(
+            parse_common(&bad_hex.replace("0200", "10200").replace("zz", "00")),
+            Err("1: PC 10200 is out of range".to_string())
+        );
+        assert_eq!(
+            parse_common(&bad_hex.replace("zz", "00").replace(" 0000 0", " 0000 100")),
+            Err("1: SP 100 is out of range".to_string())
+        );
+        // Comments can follow the registers
+        let commented = bad_hex.replace("zz", "00") + "  # v0 := 0x00";
+        assert_eq!(parse_common(&commented).unwrap().len(), 1);
+        assert_eq!(
+            parse_common(&format!("1f {}", bad_hex.replace("zz", "00"))),
+            Err("1: expected a decimal cycle".to_string())
+        );
+    }
+    #[test]
+    fn finds_the_first_differing_cycle() {
+        let ours = [
+            record(0, 0x200, 1),
+            record(1, 0x202, 2),
+            record(3, 0x206, 9),
+        ];
+        let theirs = [
+            record(0, 0x200, 1),
+            record(1, 0x202, 2),
+            record(2, 0x204, 3),
+            record(3, 0x206, 4),
+        ];
+        let (divergence, matched) = first_divergence(&ours, &theirs);
+        assert_eq!(matched, 2);
+        assert_eq!(
+            divergence,
+            Some(Divergence {
+                cycle: 3,
+                ours: 2,
+                theirs: 3,
+                differences: vec!["v0 09 != 04".to_string()],
+            })
+        );
+        let report = report(&ours, &theirs, 1);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "First divergence at cycle 3 after 2 matching instructions"
+        );
+        assert!(lines[2].starts_with("              1 0202 7001  02 00"));
+        assert!(lines[3].starts_with("ours          3 0206"));
+        assert!(lines[4].starts_with("theirs        3 0206 7001  04 00"));
+        assert_eq!(lines[5], "Differs in v0 09 != 04");
+    }
+    #[test]
+    fn reports_matching_traces() {
+        let ours = [record(0, 0x200, 1), record(1, 0x202, 2)];
+        assert_eq!(
+            report(&ours, &ours[..1], 3),
+            "No divergence in 1 matching instructions\nOurs has 2 records and theirs 1\n"
+        );
+        assert_eq!(first_divergence(&ours, &ours), (None, 2));
+    }
+}
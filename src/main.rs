@@ -1,19 +1,601 @@
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
-mod chip8;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::process;
 
-fn read_rom() -> Vec<u8> {
-    let file_name = env::args().nth(1).unwrap();
-    let mut file = File::open(file_name).unwrap();
-    let mut rom_buf = Vec::new();
-    file.read_to_end(&mut rom_buf).unwrap();
+use chip8_rust_emulator::chip8;
+use chip8_rust_emulator::chip8::asm;
+use chip8_rust_emulator::chip8::audio::AudioConfig;
+use chip8_rust_emulator::chip8::cpu::{
+    Mode, OpcodePolicy, Session, MODE_NAMES, OPCODE_POLICY_NAMES,
+};
+use chip8_rust_emulator::chip8::debugger::Debugger;
+use chip8_rust_emulator::chip8::disasm::{self, Syntax, SYNTAX_NAMES};
+use chip8_rust_emulator::chip8::error::EmulatorError;
+use chip8_rust_emulator::chip8::frontend::Frontend;
+use chip8_rust_emulator::chip8::gdb::GdbStub;
+use chip8_rust_emulator::chip8::golden::Golden;
+use chip8_rust_emulator::chip8::instruction::{InstructionClass, CLASS_NAMES};
+use chip8_rust_emulator::chip8::movie::{Movie, MovieError, Recorder};
+use chip8_rust_emulator::chip8::quirks::{Quirks, PRESET_NAMES};
+use chip8_rust_emulator::chip8::random::Random;
+use chip8_rust_emulator::chip8::trace::{TraceFilter, TraceFormat, Tracer, TRACE_FORMAT_NAMES};
+use chip8_rust_emulator::chip8::tracediff;
 
-    rom_buf
+const USAGE: &str = "Usage:
+    chip8 [run] [options] <rom or .8o source>
+    chip8 disasm [--syntax octo|cowgod] [--mode <name>] <rom>
+    chip8 asm [--mode <name>] [-o <rom>] <.8o source>
+    chip8 replay <movie> <rom or .8o source>
+    chip8 trace-diff [--context <n>] <trace> <reference trace>
+    chip8 test [--update] [--png <dir>] <golden>...
+
+Traces are binary traces from --trace-format binary, or text with a line per
+instruction holding an optional decimal cycle, then PC, opcode, V0-VF, I and SP
+in hex as they are after the instruction. Anything after a # is a comment, text
+traces from --trace put the disassembly there.
+
+Golden files name a ROM, relative to themselves, and how many frames to run it
+headless with which keys held, then hold the registers and display it should
+end up with. test compares the run against them, --update writes the results
+of the run into them instead and --png writes an image of the expected and
+actual displays for each failing test.
+
+Options:
+    --ipf <n>         Instructions executed per 60 Hz frame
+    --mode <name>     Instruction set: chip8 (default), schip or xochip
+    --quirks <name>   Quirks profile: vip, chip48, schip or modern, defaults to
+                      the one matching the mode
+    --seed <n>        Seed for CXNN random numbers, runs with the same seed
+                      and input repeat exactly
+    --unknown <name>  What unknown opcodes do: halt (default) stops and shows
+                      where, skip steps over them and logs each one, vip
+                      treats 0NNN machine code calls as doing nothing
+    --tone <hz>       Buzzer frequency
+    --volume <0-1>    Buzzer volume
+    --mute            Disable the buzzer
+    --rewind <s>      Seconds of history kept for rewinding with Backspace,
+                      0 turns it off, defaults to 10
+    --debug           Start paused in the debugger, which also starts on its
+                      own for sources with :breakpoint markers
+    --gdb <port>      Wait for GDB to connect on a local port before running
+    --record <movie>  Record the keypad of every frame into a movie file that
+                      chip8 replay checks against. Save state slots can't be
+                      loaded while recording, and it doesn't go with the
+                      debugger, which --debug, --gdb and :breakpoint markers
+                      start.
+    --trace <file>    Write every executed instruction with the registers after
+                      it to a file
+    --trace-format <name>
+                      text (default) or binary, fixed size records
+    --trace-range <start-end>
+                      Only trace instructions between two hex addresses
+    --trace-class <names>
+                      Only trace these comma separated kinds of instructions:
+                      flow, alu, memory, display, input, timer or sound
+
+Exit status:
+    0  The program ran until it exited or the window was closed
+    1  A file could not be read, written or compiled, the ROM doesn't fit, a
+       replay desynced, traces diverged or a test failed
+    2  The command line is wrong
+    3  The program crashed the machine, such as with a stack overflow";
+
+// Exit status when a file can't be used
+const EXIT_BAD_INPUT: i32 = 1;
+// Exit status when the program does something the machine can't run
+const EXIT_CRASHED: i32 = 3;
+
+#[derive(Debug)]
+struct Options {
+    rom_path: String,
+    instructions_per_frame: u32,
+    mode: Mode,
+    quirks: Quirks,
+    seed: Option<u64>,
+    opcode_policy: OpcodePolicy,
+    // Only the SDL frontend makes any sound
+    #[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+    audio: AudioConfig,
+    debug: bool,
+    gdb_port: Option<u16>,
+    rewind_frames: usize,
+    record_path: Option<String>,
+    trace_path: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn parse_mode(value: Option<String>) -> Mode {
+    value
+        .and_then(|name| Mode::from_name(&name))
+        .unwrap_or_else(|| usage_error(&format!("--mode expects one of {}", MODE_NAMES.join(", "))))
+}
+
+// Parses `200-2ff` into an inclusive range of addresses
+fn parse_range(value: Option<String>) -> Option<(u16, u16)> {
+    let value = value?;
+    let (start, end) = value.split_once('-')?;
+    let address = |text: &str| {
+        let digits = text.strip_prefix("0x").unwrap_or(text);
+        u16::from_str_radix(digits, 16).ok()
+    };
+    let (start, end) = (address(start)?, address(end)?);
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn parse_classes(value: Option<String>) -> Option<Vec<InstructionClass>> {
+    value?.split(',').map(InstructionClass::from_name).collect()
+}
+
+fn parse_args(args: Vec<String>) -> Options {
+    let mut rom_path = None;
+    let mut instructions_per_frame = chip8::cpu::DEFAULT_INSTRUCTIONS_PER_FRAME;
+    let mut mode = Mode::default();
+    let mut quirks = None;
+    let mut seed = None;
+    let mut opcode_policy = OpcodePolicy::default();
+    let mut audio = AudioConfig::default();
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut rewind_frames = chip8::rewind::DEFAULT_REWIND_FRAMES;
+    let mut record_path = None;
+    let mut trace_path = None;
+    let mut trace_format = TraceFormat::default();
+    let mut trace_filter = TraceFilter::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ipf" => {
+                instructions_per_frame = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage_error("--ipf expects a number"));
+            }
+            "--mode" => mode = parse_mode(args.next()),
+            "--quirks" => {
+                quirks = Some(
+                    args.next()
+                        .and_then(|name| Quirks::from_name(&name))
+                        .unwrap_or_else(|| {
+                            usage_error(&format!(
+                                "--quirks expects one of {}",
+                                PRESET_NAMES.join(", ")
+                            ))
+                        }),
+                );
+            }
+            "--seed" => {
+                seed = Some(
+                    args.next()
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or_else(|| usage_error("--seed expects a number")),
+                );
+            }
+            "--unknown" => {
+                opcode_policy = args
+                    .next()
+                    .and_then(|name| OpcodePolicy::from_name(&name))
+                    .unwrap_or_else(|| {
+                        usage_error(&format!(
+                            "--unknown expects one of {}",
+                            OPCODE_POLICY_NAMES.join(", ")
+                        ))
+                    });
+            }
+            "--tone" => {
+                audio.frequency = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage_error("--tone expects a frequency in Hz"));
+            }
+            "--volume" => {
+                audio.volume = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage_error("--volume expects a number between 0 and 1"));
+            }
+            "--mute" => audio.muted = true,
+            "--debug" => debug = true,
+            "--record" => {
+                record_path = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--record expects a movie path")),
+                );
+            }
+            "--trace" => {
+                trace_path = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--trace expects a file path")),
+                );
+            }
+            "--trace-format" => {
+                trace_format = args
+                    .next()
+                    .and_then(|name| TraceFormat::from_name(&name))
+                    .unwrap_or_else(|| {
+                        usage_error(&format!(
+                            "--trace-format expects one of {}",
+                            TRACE_FORMAT_NAMES.join(", ")
+                        ))
+                    });
+            }
+            "--trace-range" => {
+                trace_filter.range = Some(parse_range(args.next()).unwrap_or_else(|| {
+                    usage_error("--trace-range expects hex addresses such as 200-2ff")
+                }));
+            }
+            "--trace-class" => {
+                trace_filter.classes = parse_classes(args.next()).unwrap_or_else(|| {
+                    usage_error(&format!(
+                        "--trace-class expects a list of {}",
+                        CLASS_NAMES.join(", ")
+                    ))
+                });
+            }
+            "--rewind" => {
+                let seconds: usize = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage_error("--rewind expects a number of seconds"));
+                rewind_frames = seconds * chip8::cpu::TIMER_HZ as usize;
+            }
+            "--gdb" => {
+                gdb_port = Some(
+                    args.next()
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or_else(|| usage_error("--gdb expects a port number")),
+                );
+            }
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => usage_error(&format!("Unexpected argument {}", arg)),
+        }
+    }
+    // Movies only hold whole frames of keypad input, what the debugger
+    // changes or stops halfway through would make them desync
+    if record_path.is_some() && (debug || gdb_port.is_some()) {
+        usage_error("--record can't be used with --debug or --gdb");
+    }
+    Options {
+        rom_path: rom_path.unwrap_or_else(|| usage_error("No ROM given")),
+        instructions_per_frame,
+        mode,
+        quirks: quirks.unwrap_or_else(|| mode.default_quirks()),
+        seed,
+        opcode_policy,
+        audio,
+        debug,
+        gdb_port,
+        rewind_frames,
+        record_path,
+        trace_path,
+        trace_format,
+        trace_filter,
+    }
+}
+
+// Reports what went wrong with a file and exits
+fn fail(path: &str, error: impl Display, status: i32) -> ! {
+    eprintln!("{}: {}", path, error);
+    process::exit(status);
+}
+
+fn machine_error(path: &str, error: EmulatorError) -> ! {
+    let status = match error {
+        EmulatorError::RomTooLarge { .. } | EmulatorError::Frontend(_) => EXIT_BAD_INPUT,
+        _ => EXIT_CRASHED,
+    };
+    fail(path, error, status)
+}
+
+fn read_rom(file_name: &str) -> Vec<u8> {
+    fs::read(file_name).unwrap_or_else(|e| fail(file_name, e, EXIT_BAD_INPUT))
+}
+
+fn read_text(file_name: &str) -> String {
+    fs::read_to_string(file_name).unwrap_or_else(|e| fail(file_name, e, EXIT_BAD_INPUT))
+}
+
+fn write_file(file_name: &str, data: &[u8]) {
+    fs::write(file_name, data).unwrap_or_else(|e| fail(file_name, e, EXIT_BAD_INPUT));
+}
+
+#[cfg(feature = "sdl")]
+fn create_frontend(options: &Options) -> Box<dyn Frontend> {
+    match chip8::sdl::SdlFrontend::new(640, 320, options.audio) {
+        Ok(frontend) => Box::new(frontend),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(EXIT_BAD_INPUT);
+        }
+    }
+}
+
+#[cfg(not(feature = "sdl"))]
+fn create_frontend(_options: &Options) -> Box<dyn Frontend> {
+    eprintln!("This build has no SDL frontend, rebuild with `--features sdl` to open a window");
+    process::exit(EXIT_BAD_INPUT);
+}
+
+fn compile_source(source_path: &str, mode: Mode) -> asm::Program {
+    let source = read_text(source_path);
+    asm::compile_or_assemble(&source, mode).unwrap_or_else(|e| {
+        eprintln!("{}:{}", source_path, e);
+        process::exit(EXIT_BAD_INPUT);
+    })
+}
+
+fn disasm_command(args: Vec<String>) {
+    let mut rom_path = None;
+    let mut syntax = Syntax::Octo;
+    let mut mode = Mode::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                syntax = args
+                    .next()
+                    .and_then(|name| Syntax::from_name(&name))
+                    .unwrap_or_else(|| {
+                        usage_error(&format!(
+                            "--syntax expects one of {}",
+                            SYNTAX_NAMES.join(", ")
+                        ))
+                    });
+            }
+            "--mode" => mode = parse_mode(args.next()),
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => usage_error(&format!("Unexpected argument {}", arg)),
+        }
+    }
+    let rom_buf = read_rom(&rom_path.unwrap_or_else(|| usage_error("No ROM given")));
+    print!("{}", disasm::disassemble(&rom_buf, mode).render(syntax));
+}
+
+fn asm_command(args: Vec<String>) {
+    let mut source_path = None;
+    let mut rom_path = None;
+    let mut mode = Mode::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mode" => mode = parse_mode(args.next()),
+            "-o" => {
+                rom_path = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("-o expects an output path")),
+                );
+            }
+            _ if source_path.is_none() => source_path = Some(arg),
+            _ => usage_error(&format!("Unexpected argument {}", arg)),
+        }
+    }
+    let source_path = source_path.unwrap_or_else(|| usage_error("No source given"));
+    // Defaults to the source file name with a .ch8 extension
+    let rom_path = rom_path.unwrap_or_else(|| {
+        Path::new(&source_path)
+            .with_extension("ch8")
+            .to_string_lossy()
+            .into_owned()
+    });
+    let program = compile_source(&source_path, mode);
+    write_file(&rom_path, &program.rom);
+}
+
+// Octo sources are compiled on the fly, their debugging markers come along
+fn load_program(path: &str, mode: Mode) -> (Vec<u8>, Option<asm::Program>) {
+    if path.ends_with(".8o") {
+        let program = compile_source(path, mode);
+        (program.rom.clone(), Some(program))
+    } else {
+        (read_rom(path), None)
+    }
+}
+
+fn run_command(args: Vec<String>) {
+    let options = parse_args(args);
+    let (rom_buf, program) = load_program(&options.rom_path, options.mode);
+    let has_breakpoints = program.as_ref().is_some_and(|p| !p.breakpoints.is_empty());
+    if options.record_path.is_some() && has_breakpoints {
+        usage_error("--record can't be used with sources that have :breakpoint markers");
+    }
+    let mut cpu = chip8::cpu::CPU::with_mode(&rom_buf, options.mode)
+        .unwrap_or_else(|e| machine_error(&options.rom_path, e));
+    cpu.instructions_per_frame = options.instructions_per_frame;
+    cpu.quirks = options.quirks;
+    cpu.opcode_policy = options.opcode_policy;
+    if let Some(seed) = options.seed {
+        cpu.random = Random::seeded(seed);
+    }
+    cpu.rom_path = Some(options.rom_path.clone().into());
+    cpu.rewind_frames = options.rewind_frames;
+    let mut frontend = create_frontend(&options);
+    let mut debugger = Debugger::new();
+    if let Some(program) = &program {
+        debugger.load_markers(program);
+    }
+    let use_debugger = if let Some(port) = options.gdb_port {
+        println!("Waiting for GDB on 127.0.0.1:{}", port);
+        let gdb = GdbStub::listen(port).unwrap_or_else(|e| {
+            eprintln!("Could not accept a GDB connection: {}", e);
+            process::exit(EXIT_BAD_INPUT);
+        });
+        // GDB expects the program to be stopped when it attaches
+        debugger.paused = true;
+        debugger.attach_gdb(gdb);
+        true
+    } else if options.debug || !debugger.breakpoints.is_empty() {
+        debugger.paused = options.debug;
+        debugger.attach_console();
+        println!("Debugger attached, type h for help");
+        true
+    } else {
+        false
+    };
+    let mut recorder = options.record_path.as_ref().map(|_| Recorder::new(&cpu));
+    let mut tracer = options.trace_path.as_ref().map(|path| {
+        Tracer::create(path, options.trace_format, options.trace_filter.clone())
+            .unwrap_or_else(|e| fail(path, e, EXIT_BAD_INPUT))
+    });
+    let session = Session {
+        debugger: if use_debugger {
+            Some(&mut debugger)
+        } else {
+            None
+        },
+        recorder: recorder.as_mut(),
+        tracer: tracer.as_mut(),
+    };
+    let result = cpu.run_session(frontend.as_mut(), session);
+    // Keep the movie up to the crash, it's the easiest way to repeat it
+    if let (Some(path), Some(recorder)) = (&options.record_path, recorder) {
+        let movie = recorder.finish(&cpu);
+        write_file(path, movie.to_text().as_bytes());
+        println!("Recorded {} frames to {}", movie.frames.len(), path);
+    }
+    if let Err(e) = result {
+        machine_error(&options.rom_path, e);
+    }
+}
+
+fn replay_command(args: Vec<String>) {
+    let (movie_path, rom_path) = match args.as_slice() {
+        [movie_path, rom_path] => (movie_path, rom_path),
+        _ => usage_error("replay expects a movie and a ROM"),
+    };
+    let text = read_text(movie_path);
+    let fail = |e: MovieError| -> ! {
+        match e {
+            MovieError::Machine(e) => machine_error(rom_path, e),
+            _ => {
+                eprintln!("{}:{}", movie_path, e);
+                process::exit(EXIT_BAD_INPUT);
+            }
+        }
+    };
+    let movie = Movie::parse(&text).unwrap_or_else(|e| fail(e));
+    let (rom_buf, _) = load_program(rom_path, movie.mode);
+    let mut cpu = movie.machine(&rom_buf).unwrap_or_else(|e| fail(e));
+    let frames = movie.replay(&mut cpu).unwrap_or_else(|e| fail(e));
+    println!("Replayed {} frames without a desync", frames);
+}
+
+fn trace_diff_command(args: Vec<String>) {
+    let mut paths = Vec::new();
+    let mut context = 5;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" => {
+                context = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage_error("--context expects a number of records"));
+            }
+            _ if paths.len() < 2 => paths.push(arg),
+            _ => usage_error(&format!("Unexpected argument {}", arg)),
+        }
+    }
+    if paths.len() != 2 {
+        usage_error("trace-diff expects two traces");
+    }
+    let read = |path: &str| {
+        let data = fs::read(path).unwrap_or_else(|e| fail(path, e, EXIT_BAD_INPUT));
+        tracediff::read_any(&data).unwrap_or_else(|e| {
+            eprintln!("{}:{}", path, e);
+            process::exit(EXIT_BAD_INPUT);
+        })
+    };
+    let (ours, theirs) = (read(&paths[0]), read(&paths[1]));
+    print!("{}", tracediff::report(&ours, &theirs, context));
+    if tracediff::first_divergence(&ours, &theirs).0.is_some() {
+        process::exit(EXIT_BAD_INPUT);
+    }
+}
+
+fn test_command(args: Vec<String>) {
+    let mut paths = Vec::new();
+    let mut update = false;
+    let mut png_dir = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--update" => update = true,
+            "--png" => {
+                png_dir = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--png expects a directory")),
+                );
+            }
+            _ if arg.starts_with("--") => usage_error(&format!("Unexpected argument {}", arg)),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage_error("test expects golden files");
+    }
+    let mut failed = 0;
+    for path in paths.iter() {
+        let golden = Golden::parse(&read_text(path)).unwrap_or_else(|e| {
+            eprintln!("{}:{}", path, e);
+            process::exit(EXIT_BAD_INPUT);
+        });
+        let rom_path = golden.rom_path(Path::new(path));
+        let (rom_buf, _) = load_program(&rom_path.to_string_lossy(), golden.mode);
+        let cpu = match golden.run(&rom_buf) {
+            Ok(cpu) => cpu,
+            Err(e) => {
+                println!("{} FAILED\n{}", path, e);
+                failed += 1;
+                continue;
+            }
+        };
+        if update {
+            write_file(path, golden.updated(&cpu).to_text().as_bytes());
+            println!("{} updated", path);
+            continue;
+        }
+        let mismatches = golden.mismatches(&cpu);
+        if mismatches.is_empty() {
+            println!("{} ok", path);
+            continue;
+        }
+        failed += 1;
+        println!("{} FAILED\n{}", path, mismatches.join("\n"));
+        if let Some(dir) = &png_dir {
+            let name = Path::new(path).file_stem().unwrap_or_default();
+            let png_path = Path::new(dir).join(name).with_extension("png");
+            let png_path = png_path.to_string_lossy();
+            write_file(&png_path, &golden.diff_png(&cpu));
+            println!("Wrote the displays to {}", png_path);
+        }
+    }
+    if failed > 0 {
+        println!("{} of {} tests failed", failed, paths.len());
+        process::exit(EXIT_BAD_INPUT);
+    }
 }
 
 fn main() {
-    let rom_buf = read_rom();
-    let mut cpu = chip8::cpu::CPU::new(&rom_buf);
-    cpu.run();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let command = args.first().cloned().unwrap_or_default();
+    match command.as_str() {
+        "disasm" => disasm_command(args.split_off(1)),
+        "asm" => asm_command(args.split_off(1)),
+        "replay" => replay_command(args.split_off(1)),
+        "trace-diff" => trace_diff_command(args.split_off(1)),
+        "test" => test_command(args.split_off(1)),
+        "run" => run_command(args.split_off(1)),
+        _ => run_command(args),
+    }
 }